@@ -0,0 +1,111 @@
+use std::{collections::HashSet, sync::Arc};
+
+use crate::{
+    compaction::{CompactionJob, CompactionOutput, CompactionStrategy},
+    config::Config,
+    engine::Version,
+    sstable::SSTable,
+};
+
+/// @definition: Merges level 0 into level 1 once it has more than `compaction_threshold` tables.
+/// Level 1 holds up to `compaction_tier_size` bytes and every level after it
+/// `compaction_size_multiplier` times more; a level over its size has one of its tables merged
+/// into the tables of the next level it overlaps. Merged tables are split at `target_file_size`.
+#[derive(Debug, Default)]
+pub struct LeveledStrategy;
+
+impl LeveledStrategy {
+    fn level_target_size(level: usize, config: &Config) -> usize {
+        let multiplier = config.compaction_size_multiplier as usize;
+        config.compaction_tier_size * multiplier.pow(level as u32 - 1)
+    }
+
+    fn job(
+        version: &Version,
+        inputs: Vec<Arc<SSTable>>,
+        output_level: usize,
+        config: &Config,
+    ) -> CompactionJob {
+        let min = inputs.iter().map(|table| table.min.as_str()).min().unwrap();
+        let max = inputs.iter().map(|table| table.max.as_str()).max().unwrap();
+
+        // Deleted keys can be dropped once no deeper level can have an older version of them
+        let drop_tombstones = (output_level + 1..version.levels().len())
+            .all(|level| version.overlapping(level, min, max).is_empty());
+
+        CompactionJob {
+            output: Some(CompactionOutput {
+                level: output_level,
+                target_file_size: Some(config.target_file_size),
+                drop_tombstones,
+            }),
+            inputs,
+        }
+    }
+}
+
+impl CompactionStrategy for LeveledStrategy {
+    fn pick(
+        &self,
+        version: &Version,
+        compacting: &HashSet<String>,
+        config: &Config,
+    ) -> Vec<CompactionJob> {
+        let is_compacting = |table: &Arc<SSTable>| compacting.contains(&table.storage_path);
+        let mut jobs = vec![];
+        let mut picked: HashSet<String> = HashSet::new();
+
+        // Level 0 tables overlap, so they have to move to level 1 together and in order
+        let level_0 = version.level(0);
+        if level_0.len() > config.compaction_threshold as usize
+            && !level_0.iter().any(is_compacting)
+        {
+            let min = level_0
+                .iter()
+                .map(|table| table.min.as_str())
+                .min()
+                .unwrap();
+            let max = level_0
+                .iter()
+                .map(|table| table.max.as_str())
+                .max()
+                .unwrap();
+            let mut inputs = version.overlapping(1, min, max);
+            if !inputs.iter().any(is_compacting) {
+                inputs.extend(level_0.iter().cloned());
+                picked.extend(inputs.iter().map(|table| table.storage_path.clone()));
+                jobs.push(Self::job(version, inputs, 1, config));
+            }
+        }
+
+        // The last level has nowhere to go
+        for level in 1..config.max_levels.saturating_sub(1) {
+            let tables = version.level(level);
+            let size: usize = tables.iter().map(|table| table.size).sum();
+            if size <= Self::level_target_size(level, config) {
+                continue;
+            }
+
+            let is_taken =
+                |table: &Arc<SSTable>| is_compacting(table) || picked.contains(&table.storage_path);
+            let job = tables
+                .iter()
+                .filter(|table| !is_taken(table))
+                .find_map(|table| {
+                    let mut inputs = version.overlapping(level + 1, &table.min, &table.max);
+                    if inputs.iter().any(is_taken) {
+                        return None;
+                    }
+                    inputs.push(Arc::clone(table));
+                    Some(inputs)
+                });
+
+            if let Some(inputs) = job {
+                picked.extend(inputs.iter().map(|table| table.storage_path.clone()));
+                jobs.push(Self::job(version, inputs, level + 1, config));
+            }
+        }
+
+        jobs
+    }
+}
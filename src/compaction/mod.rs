@@ -1,21 +1,37 @@
-use core::panic;
+mod leveled;
+mod size_tiered;
+mod strategy;
+mod time_window;
+
 use std::{
     cmp::Reverse,
     collections::BinaryHeap,
     fs::{File, OpenOptions},
-    io::{BufReader, Read, Result as IOResult, Seek, SeekFrom, Write},
+    io::{self, BufReader, ErrorKind, Read, Result as IOResult},
+    path::Path,
 };
 
 use crate::{
-    config::Config, memtable::MemTableRecord, serialization::SerializationEngine, sstable::SSTable,
+    config::Config,
+    memtable::MemTableRecord,
+    serialization::SerializationEngine,
+    sstable::{
+        SSTable, SSTableBuilder, blob::StoredValue, block::StorageReader, error::SSTableError,
+        table::index_offset,
+    },
 };
 use tempfile::NamedTempFile;
 
+pub use leveled::LeveledStrategy;
+pub use size_tiered::SizeTieredStrategy;
+pub use strategy::{CompactionJob, CompactionStrategy};
+pub use time_window::TimeWindowStrategy;
+
 #[derive(Debug)]
 struct Entry<T> {
     key: String,
     reader: usize,
-    value: Option<T>,
+    value: StoredValue<T>,
 }
 
 impl<T> Eq for Entry<T> {}
@@ -41,79 +57,107 @@ impl<T> Ord for Entry<T> {
 }
 
 impl<T> Entry<T> {
-    fn new(key: String, reader: usize, value: Option<T>) -> Entry<T> {
+    fn new(key: String, reader: usize, value: StoredValue<T>) -> Entry<T> {
         Entry { key, reader, value }
     }
 }
 
+/// @definition: Where and how the merged records are written
+/// @field level: The level of the merged tables
+/// @field target_file_size: Once a merged table reaches this size, the next records go to a new
+/// table. None writes a single table
+/// @field drop_tombstones: Whether deleted keys can be left out. Only safe when none of the tables
+/// that aren't part of the compaction can have an older version of the key
+#[derive(Debug, Clone)]
+pub struct CompactionOutput {
+    pub level: usize,
+    pub target_file_size: Option<usize>,
+    pub drop_tombstones: bool,
+}
+
 /// The order of SSTables is given such that an older index indicate the newest SSTable. This will
-/// be used for conflicting keys where the newer will be used
+/// be used for conflicting keys where the newer will be used. `new_paths` gives the index and
+/// storage paths of every table that is written. Values in blob files are not read, the merged
+/// tables point to them where they are.
 pub fn compact<T, SS>(
     tables: Vec<&SSTable>,
     serializer: &SS,
     config: &Config,
-    new_index_path: String,
-    new_storage_path: String,
-) -> IOResult<SSTable>
+    output: &CompactionOutput,
+    mut new_paths: impl FnMut() -> IOResult<(String, String)>,
+) -> IOResult<Vec<SSTable>>
 where
     T: MemTableRecord,
     SS: SerializationEngine<Option<T>>,
 {
     if tables.is_empty() {
-        panic!("There must be a number of tables");
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "There must be a number of tables",
+        ));
     }
 
-    let mut readers: Vec<(BufReader<_>, BufReader<_>)> = tables
+    let mut readers: Vec<(BufReader<_>, StorageReader)> = tables
         .iter()
         .map(|table| {
-            let index_file = OpenOptions::new()
-                .read(true)
-                .open(&table.index_path)
-                .unwrap();
-            let storage_file = OpenOptions::new()
-                .read(true)
-                .open(&table.storage_path)
-                .unwrap();
-            (BufReader::new(index_file), BufReader::new(storage_file))
+            let index_file = OpenOptions::new().read(true).open(&table.index_path)?;
+            let storage_reader = StorageReader::open(&table.storage_path)?;
+            Ok((BufReader::new(index_file), storage_reader))
         })
-        .collect();
+        .collect::<IOResult<_>>()?;
+
+    // The merged tables cover the writes of all the inputs
+    let max_sequence = tables
+        .iter()
+        .map(|table| table.max_sequence)
+        .max()
+        .unwrap_or_default();
+    let timestamps = (
+        tables
+            .iter()
+            .map(|table| table.min_timestamp)
+            .min()
+            .unwrap_or_default(),
+        tables
+            .iter()
+            .map(|table| table.max_timestamp)
+            .max()
+            .unwrap_or_default(),
+    );
+
+    // No older data lies below the tables when tombstones can be dropped
+    let compression = match config.bottommost_compression {
+        Some(compression) if output.drop_tombstones => compression,
+        _ => config.compression,
+    };
 
     let mut heap = BinaryHeap::<Reverse<Entry<T>>>::new();
-    let mut index_file = NamedTempFile::new_in(&config.db_path)?;
-    let mut storage_file = NamedTempFile::new_in(&config.db_path)?;
-    let mut indices: Vec<(String, u64)> = vec![];
+    let mut outputs = vec![];
+    let mut builder: Option<SSTableBuilder<T, SS>> = None;
+    let finish = |builder: SSTableBuilder<T, SS>, (index_path, storage_path): (String, String)| {
+        let mut table = builder
+            .finish(&storage_path, &index_path)
+            .map_err(table_error)?;
+        table.level = output.level;
+        Ok::<_, io::Error>(table)
+    };
 
     // Read the first elements in each key
     for (i, (index_reader, storage_reader)) in readers.iter_mut().enumerate() {
-        if let Some((key, value)) =
-            read_next_key(index_reader, storage_reader, config, serializer).unwrap()
+        if let Some((key, value)) = read_next_key(index_reader, storage_reader, config, serializer)?
         {
             heap.push(Reverse(Entry::new(key, i, value)));
         }
     }
 
-    // Get initial values for min, max, and count
-    let mut max = {
-        let Reverse(entry): &Reverse<Entry<_>> = heap.peek().unwrap();
-        entry.key.clone()
-    };
-    let mut min = {
-        let Reverse(entry): &Reverse<Entry<_>> = heap.peek().unwrap();
-        entry.key.clone()
-    };
-    let mut count = 0;
-
     // Main Loop
-    while !heap.is_empty() {
-        let current_key = {
-            let Reverse(peek) = heap.peek().unwrap();
-            peek.key.clone()
-        };
+    while let Some(Reverse(peek)) = heap.peek() {
+        let current_key = peek.key.clone();
 
         // Read from all the files so that at least all occurrences of this key are in the heap
         for (i, (index_reader, storage_reader)) in readers.iter_mut().enumerate() {
             if let Some((key, value)) =
-                read_next_key(index_reader, storage_reader, config, serializer).unwrap()
+                read_next_key(index_reader, storage_reader, config, serializer)?
             {
                 heap.push(Reverse(Entry::new(key, i, value)));
             }
@@ -128,50 +172,66 @@ where
             versions.push(entry);
         }
 
-        let entry = versions
-            .into_iter()
-            .max_by_key(|entry| entry.reader)
-            .unwrap();
+        let Some(entry) = versions.into_iter().max_by_key(|entry| entry.reader) else {
+            continue;
+        };
 
-        min = min.min(entry.key.clone());
-        max = max.max(entry.key.clone());
-        count += 1;
+        if matches!(entry.value, StoredValue::Inline(None)) && output.drop_tombstones {
+            continue;
+        }
 
-        indices.push((entry.key, storage_file.stream_position().unwrap()));
-        let encoded = serializer.serialize(entry.value).unwrap();
-        storage_file.write_all(&encoded).unwrap();
+        // Tables being written are temporary files until they are finished, so an interrupted
+        // compaction never leaves a partial table behind
+        let current = match builder.as_mut() {
+            Some(current) => current,
+            None => builder.insert(
+                SSTableBuilder::new(serializer, config)
+                    .map_err(table_error)?
+                    .with_compression(compression)
+                    .with_sequence(max_sequence)
+                    .with_timestamps(timestamps.0, timestamps.1),
+            ),
+        };
+        current
+            .add_stored(entry.key, entry.value)
+            .map_err(table_error)?;
+
+        let full = |current: &mut SSTableBuilder<T, SS>| {
+            output
+                .target_file_size
+                .is_some_and(|target_file_size| current.size() >= target_file_size)
+        };
+        if let Some(current) = builder.take_if(full) {
+            outputs.push(finish(current, new_paths()?)?);
+        }
     }
 
-    for (key, offset) in indices.iter() {
-        let mut key_bytes = vec![0u8; config.index_key_string_size];
-        let truncated = key.as_bytes();
-        let len = truncated.len().min(config.index_key_string_size);
-        key_bytes[..len].copy_from_slice(&truncated[..len]);
-
-        index_file.write_all(&key_bytes).unwrap();
-        index_file.write_all(&offset.to_le_bytes()).unwrap();
+    if let Some(current) = builder {
+        outputs.push(finish(current, new_paths()?)?);
     }
 
-    let size = storage_file.stream_position().unwrap() as usize;
+    Ok(outputs)
+}
 
-    index_file.persist(&new_index_path).unwrap();
-    storage_file.persist(&new_storage_path).unwrap();
+pub(crate) fn table_error(err: SSTableError) -> io::Error {
+    io::Error::other(format!("{:?}", err))
+}
 
-    Ok(SSTable {
-        storage_path: new_storage_path,
-        index_path: new_index_path,
-        size,
-        min,
-        max,
-        count,
-    })
+/// A temporary file named after the record type, so that the orphan cleanup of other types leaves
+/// it alone
+pub(crate) fn temp_file(dir: impl AsRef<Path>, type_name: &str) -> IOResult<NamedTempFile> {
+    tempfile::Builder::new()
+        .prefix(&format!("{}-", type_name))
+        .suffix(".tmp")
+        .tempfile_in(dir)
 }
-fn read_next_key<T, SS>(
+
+pub(crate) fn read_next_key<T, SS>(
     index_reader: &mut BufReader<File>,
-    storage_reader: &mut BufReader<File>,
+    storage_reader: &mut StorageReader,
     config: &Config,
     serializer: &SS,
-) -> IOResult<Option<(String, Option<T>)>>
+) -> IOResult<Option<(String, StoredValue<T>)>>
 where
     T: MemTableRecord,
     SS: SerializationEngine<Option<T>>,
@@ -185,21 +245,25 @@ where
         .to_string();
 
     let mut offset = vec![0u8; config.index_offset_size];
-    index_reader.read_exact(&mut offset).unwrap();
-
-    let offset = u64::from_le_bytes(offset.try_into().unwrap());
-
-    storage_reader.seek(SeekFrom::Start(offset)).unwrap();
-    let value = serializer.deserialize(storage_reader).unwrap(); // TODO: Fix if possible
+    index_reader.read_exact(&mut offset)?;
+    let offset = index_offset(&offset)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Index offsets are too large"))?;
 
+    let value = storage_reader.read(offset, serializer)?;
     Ok(Some((key, value)))
 }
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
+
     use crate::{
-        config::Config, engine::Engine, memtable::MemTableRecord,
+        compaction::{CompactionOutput, compact},
+        config::Config,
+        engine::Engine,
+        memtable::MemTableRecord,
         serialization::BinarySerializationEngine,
+        sstable::SSTableBuilder,
     };
     use bincode::{Decode, Encode};
     use tempfile::TempDir;
@@ -232,14 +296,13 @@ mod tests {
             compaction_threshold: 3,
             compaction_tier_size: 2097152,
             compaction_size_multiplier: 10,
+            ..Default::default()
         };
 
-        let serializer = BinarySerializationEngine;
-
         let engine = Engine::<Photo, BinarySerializationEngine, BinarySerializationEngine>::new(
-            &serializer,
-            &serializer,
-            &config,
+            BinarySerializationEngine,
+            BinarySerializationEngine,
+            config,
         )
         .expect("Engine creation failed");
 
@@ -271,7 +334,7 @@ mod tests {
 
         // Run compaction multiple times to test stability
         for _ in 0..10 {
-            engine.compact();
+            engine.compact().expect("Compaction failed");
         }
 
         // Verify data integrity after compaction
@@ -285,4 +348,62 @@ mod tests {
             assert!(photo.is_none(), "Expected key {} to be deleted", key);
         }
     }
+
+    #[test]
+    fn unreadable_inputs_fail_the_compaction() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let config = Config {
+            db_path: temp_dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let path = |name: &str| temp_dir.path().join(name).display().to_string();
+        let tables: Vec<_> = (0..2)
+            .map(|i| {
+                let mut builder = SSTableBuilder::new(&BinarySerializationEngine, &config).unwrap();
+                let photo = Photo {
+                    id: format!("id_{}", i),
+                    url: String::new(),
+                    thumbnail_url: String::new(),
+                };
+                builder.add(photo.id.clone(), Some(photo)).unwrap();
+                let [storage, index] =
+                    ["storage", "index"].map(|kind| path(&format!("{i}.{kind}")));
+                builder.finish(&storage, &index).unwrap()
+            })
+            .collect();
+        let output = CompactionOutput {
+            level: 0,
+            target_file_size: None,
+            drop_tombstones: false,
+        };
+        let mut outputs = 0;
+        let mut new_paths = || {
+            outputs += 1;
+            Ok((
+                path(&format!("out{outputs}.index")),
+                path(&format!("out{outputs}.storage")),
+            ))
+        };
+
+        // A storage file that is gone, then one that is garbage
+        fs::remove_file(&tables[1].storage_path).unwrap();
+        let result = compact::<Photo, _>(
+            tables.iter().collect(),
+            &BinarySerializationEngine,
+            &config,
+            &output,
+            &mut new_paths,
+        );
+        assert!(result.is_err());
+
+        fs::write(&tables[1].storage_path, "garbage").unwrap();
+        let result = compact::<Photo, _>(
+            tables.iter().collect(),
+            &BinarySerializationEngine,
+            &config,
+            &output,
+            &mut new_paths,
+        );
+        assert!(result.is_err());
+    }
 }
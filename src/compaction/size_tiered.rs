@@ -0,0 +1,140 @@
+use std::{collections::HashSet, sync::Arc};
+
+use crate::{
+    compaction::{CompactionJob, CompactionOutput, CompactionStrategy},
+    config::Config,
+    engine::Version,
+    sstable::SSTable,
+};
+
+/// @definition: Keeps every table in level 0, and merges runs of more than `compaction_threshold`
+/// adjacent tables of the same tier. The tier of a table is the log of its size in multiples of
+/// `compaction_tier_size`, with `compaction_size_multiplier` as the base. Only adjacent tables are
+/// merged, so that the merged table never jumps over a table newer than some of its inputs.
+#[derive(Debug, Default)]
+pub struct SizeTieredStrategy;
+
+impl SizeTieredStrategy {
+    fn tier(table: &SSTable, config: &Config) -> usize {
+        (table.size as f64 / config.compaction_tier_size as f64)
+            .log(config.compaction_size_multiplier as f64)
+            .floor() as usize
+    }
+}
+
+impl CompactionStrategy for SizeTieredStrategy {
+    fn pick(
+        &self,
+        version: &Version,
+        compacting: &HashSet<String>,
+        config: &Config,
+    ) -> Vec<CompactionJob> {
+        let mut runs: Vec<Vec<Arc<SSTable>>> = vec![];
+        let mut previous_tier = None;
+
+        for table in version.level(0).iter() {
+            if compacting.contains(&table.storage_path) {
+                previous_tier = None;
+                continue;
+            }
+
+            let tier = Some(Self::tier(table, config));
+            if tier != previous_tier {
+                runs.push(vec![]);
+                previous_tier = tier;
+            }
+            runs.last_mut().unwrap().push(Arc::clone(table));
+        }
+
+        runs.into_iter()
+            .filter(|run| run.len() > config.compaction_threshold as usize)
+            .map(|inputs| CompactionJob {
+                inputs,
+                output: Some(CompactionOutput {
+                    level: 0,
+                    target_file_size: None,
+                    drop_tombstones: false,
+                }),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        sync::{Arc, atomic::AtomicBool},
+    };
+
+    use crate::{
+        compaction::{CompactionStrategy, SizeTieredStrategy},
+        config::Config,
+        engine::Version,
+        sstable::SSTable,
+    };
+
+    fn table(name: &str, size: usize) -> Arc<SSTable> {
+        Arc::new(SSTable {
+            storage_path: name.to_string(),
+            index_path: name.to_string(),
+            min: "a".to_string(),
+            max: "z".to_string(),
+            size,
+            count: 1,
+            level: 0,
+            min_timestamp: 0,
+            max_timestamp: 0,
+            max_sequence: 0,
+            blobs: vec![],
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    #[test]
+    fn picks_runs_of_adjacent_tables_in_order() {
+        let config = Config {
+            compaction_threshold: 2,
+            compaction_tier_size: 100,
+            compaction_size_multiplier: 10,
+            ..Default::default()
+        };
+        let version = Version::new([
+            table("small_1", 10),
+            table("big", 50000),
+            table("small_2", 10),
+            table("small_3", 10),
+            table("small_4", 10),
+            table("medium_1", 1000),
+            table("medium_2", 1000),
+            table("medium_3", 1000),
+        ]);
+
+        let names = |compacting: &HashSet<String>| -> Vec<Vec<String>> {
+            SizeTieredStrategy
+                .pick(&version, compacting, &config)
+                .into_iter()
+                .map(|job| {
+                    job.inputs
+                        .iter()
+                        .map(|table| table.storage_path.clone())
+                        .collect()
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            names(&HashSet::new()),
+            vec![
+                vec!["small_2", "small_3", "small_4"],
+                vec!["medium_1", "medium_2", "medium_3"],
+            ]
+        );
+
+        let compacting = HashSet::from(["small_3".to_string()]);
+        assert_eq!(
+            names(&compacting),
+            vec![vec!["medium_1", "medium_2", "medium_3"]]
+        );
+    }
+}
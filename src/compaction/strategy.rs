@@ -0,0 +1,27 @@
+use std::{collections::HashSet, fmt::Debug, sync::Arc};
+
+use crate::{compaction::CompactionOutput, config::Config, engine::Version, sstable::SSTable};
+
+/// @definition: A compaction to run
+/// @field inputs: The tables to merge, from oldest to newest. Newer tables win on conflicting keys
+/// @field output: Where the merged tables go. None drops the inputs without merging them, for
+/// tables whose data expired
+#[derive(Debug, Clone)]
+pub struct CompactionJob {
+    pub inputs: Vec<Arc<SSTable>>,
+    pub output: Option<CompactionOutput>,
+}
+
+/// @definition: Decides which tables are merged together. The engine asks the strategy for jobs
+/// after every flush and compaction, and runs them on the background threads.
+pub trait CompactionStrategy: Debug + Send + Sync {
+    /// Returns the compactions worth running on the current tables, most important first.
+    /// `compacting` has the storage paths of the tables that are inputs of running compactions,
+    /// which can't be part of any of the returned jobs.
+    fn pick(
+        &self,
+        version: &Version,
+        compacting: &HashSet<String>,
+        config: &Config,
+    ) -> Vec<CompactionJob>;
+}
@@ -0,0 +1,228 @@
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    compaction::{CompactionJob, CompactionOutput, CompactionStrategy},
+    config::Config,
+    engine::Version,
+    sstable::SSTable,
+};
+
+/// @definition: For append-mostly data that expires. Tables are bucketed into windows of
+/// `time_window_size_ms` by the write time of their newest record, and only tables of the same
+/// window are merged: the current window once it has more than `compaction_threshold` tables, and
+/// the older windows into a single table each. Once a window ended more than
+/// `time_window_retention_ms` ago, its tables are dropped as a whole.
+#[derive(Debug, Default)]
+pub struct TimeWindowStrategy;
+
+impl TimeWindowStrategy {
+    fn window(table: &SSTable, config: &Config) -> u64 {
+        table.max_timestamp / config.time_window_size_ms.max(1)
+    }
+
+    /// Tables without write times, from before they were recorded, are older than any other, so
+    /// they belong to the first window
+    fn is_expired(table: &SSTable, now: u64, config: &Config) -> bool {
+        let Some(retention) = config.time_window_retention_ms else {
+            return false;
+        };
+        let window_end = (Self::window(table, config) + 1) * config.time_window_size_ms.max(1);
+        window_end + retention <= now
+    }
+
+    fn pick_at(
+        &self,
+        version: &Version,
+        compacting: &HashSet<String>,
+        config: &Config,
+        now: u64,
+    ) -> Vec<CompactionJob> {
+        let mut jobs = vec![];
+        let tables = version.level(0);
+
+        // A table is only dropped with the older tables it overlaps, so that none of its tombstones
+        // brings back a record they still have
+        let mut expired: Vec<Arc<SSTable>> = vec![];
+        let mut kept: Vec<&Arc<SSTable>> = vec![];
+        for table in tables.iter() {
+            let covers_kept = kept
+                .iter()
+                .any(|older| older.min <= table.max && older.max >= table.min);
+            if !covers_kept
+                && !compacting.contains(&table.storage_path)
+                && Self::is_expired(table, now, config)
+            {
+                expired.push(Arc::clone(table));
+            } else {
+                kept.push(table);
+            }
+        }
+        let dropped: HashSet<&str> = expired
+            .iter()
+            .map(|table| table.storage_path.as_str())
+            .collect();
+
+        // Only adjacent tables are merged, so that the merged table never jumps over a newer one
+        let mut runs: Vec<(u64, Vec<Arc<SSTable>>)> = vec![];
+        for table in tables.iter() {
+            if compacting.contains(&table.storage_path)
+                || dropped.contains(table.storage_path.as_str())
+            {
+                runs.push((u64::MAX, vec![]));
+                continue;
+            }
+
+            let window = Self::window(table, config);
+            match runs.last_mut() {
+                Some((last, run)) if *last == window => run.push(Arc::clone(table)),
+                _ => runs.push((window, vec![Arc::clone(table)])),
+            }
+        }
+
+        if !expired.is_empty() {
+            jobs.push(CompactionJob {
+                inputs: expired,
+                output: None,
+            });
+        }
+
+        let current = now / config.time_window_size_ms.max(1);
+        for (window, inputs) in runs {
+            let threshold = if window >= current {
+                config.compaction_threshold as usize
+            } else {
+                1
+            };
+            if inputs.len() > threshold {
+                jobs.push(CompactionJob {
+                    inputs,
+                    output: Some(CompactionOutput {
+                        level: 0,
+                        target_file_size: None,
+                        drop_tombstones: false,
+                    }),
+                });
+            }
+        }
+
+        jobs
+    }
+}
+
+impl CompactionStrategy for TimeWindowStrategy {
+    fn pick(
+        &self,
+        version: &Version,
+        compacting: &HashSet<String>,
+        config: &Config,
+    ) -> Vec<CompactionJob> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.pick_at(version, compacting, config, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        sync::{Arc, atomic::AtomicBool},
+    };
+
+    use crate::{
+        compaction::TimeWindowStrategy, config::Config, engine::Version, sstable::SSTable,
+    };
+
+    fn table(name: &str, min_timestamp: u64, max_timestamp: u64) -> Arc<SSTable> {
+        Arc::new(SSTable {
+            storage_path: name.to_string(),
+            index_path: name.to_string(),
+            min: "a".to_string(),
+            max: "z".to_string(),
+            size: 100,
+            count: 1,
+            level: 0,
+            min_timestamp,
+            max_timestamp,
+            max_sequence: 0,
+            blobs: vec![],
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    #[test]
+    fn merges_within_windows_and_drops_expired_ones() {
+        let config = Config {
+            compaction_threshold: 2,
+            time_window_size_ms: 100,
+            time_window_retention_ms: Some(200),
+            ..Default::default()
+        };
+        let version = Version::new([
+            table("legacy", 0, 0),
+            table("expired_1", 10, 20),
+            table("expired_2", 30, 90),
+            table("old_1", 300, 320),
+            table("old_2", 330, 350),
+            table("previous", 450, 480),
+            table("current_1", 500, 510),
+            table("current_2", 520, 530),
+        ]);
+
+        let names_while = |compacting: &[&str], now: u64| -> Vec<(Vec<String>, bool)> {
+            let compacting: HashSet<String> =
+                compacting.iter().map(|name| name.to_string()).collect();
+            TimeWindowStrategy
+                .pick_at(&version, &compacting, &config, now)
+                .into_iter()
+                .map(|job| {
+                    let names = job
+                        .inputs
+                        .iter()
+                        .map(|table| table.storage_path.clone())
+                        .collect();
+                    (names, job.output.is_some())
+                })
+                .collect()
+        };
+        let names = |now: u64| names_while(&[], now);
+
+        let names_to_strings =
+            |names: &[&str]| -> Vec<String> { names.iter().map(|name| name.to_string()).collect() };
+
+        // The first window ended at 100, and is kept until 300. The table without write times is
+        // older than it
+        assert_eq!(
+            names(550),
+            vec![
+                (
+                    names_to_strings(&["legacy", "expired_1", "expired_2"]),
+                    false
+                ),
+                (names_to_strings(&["old_1", "old_2"]), true),
+            ]
+        );
+        // Before that it is merged like any past window, while the later windows haven't started
+        assert_eq!(
+            names(299),
+            vec![(
+                names_to_strings(&["legacy", "expired_1", "expired_2"]),
+                true
+            )]
+        );
+        // Their tombstones would bring back the records of the older table still being compacted
+        assert_eq!(
+            names_while(&["legacy"], 550),
+            vec![
+                (names_to_strings(&["expired_1", "expired_2"]), true),
+                (names_to_strings(&["old_1", "old_2"]), true),
+            ]
+        );
+    }
+}
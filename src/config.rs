@@ -1,22 +1,265 @@
 use serde::Deserialize;
 use serde_yaml;
-use std::fs;
+use std::{fs, sync::Arc};
 
-#[derive(Debug, Deserialize)]
+use crate::{
+    compaction::{CompactionStrategy, LeveledStrategy, SizeTieredStrategy, TimeWindowStrategy},
+    memtable::{HashRep, MemTableRep, SkipList, TreeRep, VectorRep},
+    write_buffer::WriteBufferManager,
+};
+
+/// @definition: How the background threads pick the tables to compact, unless a custom strategy is
+/// given in `Config::custom_compaction_strategy`
+/// @variant SizeTiered: Tables stay in level 0, and tables of similar sizes are merged together
+/// @variant Leveled: Flushed tables are merged into levels 1 and up, where the tables of a level
+/// don't overlap and every level is `compaction_size_multiplier` times larger than the one above
+/// @variant TimeWindow: Tables are only merged with tables written in the same time window, and
+/// dropped once their window is past the retention
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompactionStyle {
+    SizeTiered,
+    Leveled,
+    TimeWindow,
+}
+
+/// @definition: What happens to the files left behind by a crash, that no table or manifest uses
+/// @variant Delete: They are deleted when the engine is opened
+/// @variant Quarantine: They are moved to the `lost` directory of the database, for inspection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrphanFileAction {
+    Delete,
+    Quarantine,
+}
+
+/// @definition: When the writes in the WAL are synced to disk
+/// @variant None: Left to the operating system. Writes survive a crash of the process, but not of
+/// the machine
+/// @variant Always: Before every write is acknowledged. Concurrent writes share a sync
+/// @variant Interval: Every `wal_sync_interval_ms` by a background thread, so at most that much
+/// of the acknowledged writes is lost when the machine crashes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WalSyncMode {
+    None,
+    Always,
+    Interval,
+}
+
+/// @definition: What happens to the damaged records of a WAL when it is replayed
+/// @variant Fail: Opening the engine fails, even when only the last record was cut short
+/// @variant TruncateTail: The log is cut at the first damaged record. Survives a crash in the
+/// middle of an append
+/// @variant SkipCorrupted: Records with a wrong checksum are skipped, and the log is only cut
+/// when its end is damaged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WalRecoveryMode {
+    Fail,
+    TruncateTail,
+    SkipCorrupted,
+}
+
+/// @definition: How the blocks of the storage files are compressed. The codec is recorded in every
+/// block, so changing it only affects the tables written afterwards
+/// @variant None: Blocks are stored as they are
+/// @variant Lz4: Fast to compress and decompress, with a moderate ratio
+/// @variant Deflate: Slower, with a higher ratio. Best for data that is rarely rewritten
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    None,
+    Lz4,
+    Deflate,
+}
+
+/// @definition: How the entries of the memtables are kept in memory
+/// @variant SkipList: A lock-free skiplist, where writers and readers don't block each other. For
+/// mixed workloads
+/// @variant Tree: A red-black tree behind a lock
+/// @variant Vector: A vector sorted by key, cheapest when the keys are written in order. For bulk
+/// loads
+/// @variant Hash: A hash map, sorted only when the memtable is iterated. For point lookups
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MemTableRepKind {
+    SkipList,
+    Tree,
+    Vector,
+    Hash,
+}
+
+/// @definition: How a single write is applied
+/// @field disable_wal: The write isn't appended to the WAL, so it is lost if the process stops
+/// before its memtable is flushed. For bulk loads that can be rerun, which call
+/// `Engine::flush_and_sync` once done
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WriteOptions {
+    pub disable_wal: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub db_path: String,
     pub index_key_string_size: usize,
     pub index_offset_size: usize,
+    /// A memtable is flushed once the index of its table would reach
+    /// `initial_index_file_threshold` bytes, once the keys and operations written to it take
+    /// `memtable_max_bytes`, once its WAL segment reaches `wal_max_bytes`, or once its oldest write
+    /// is `memtable_max_age_ms` old, whichever comes first. None disables a trigger. The age is
+    /// checked on writes and by the flush thread, once the memtable gets that old
     pub initial_index_file_threshold: usize,
+    pub memtable_max_bytes: Option<u64>,
+    pub wal_max_bytes: Option<u64>,
+    pub memtable_max_age_ms: Option<u64>,
     pub compaction_threshold: u32,
     pub compaction_tier_size: usize,
     pub compaction_size_multiplier: u32,
+    /// The number of background threads running compactions. 0 leaves compaction to
+    /// `Engine::compact`
+    pub compaction_threads: usize,
+    pub compaction_style: CompactionStyle,
+    /// Leveled compaction only: level 1 holds up to `compaction_tier_size` bytes, and the merged
+    /// tables are split into tables of about `target_file_size` bytes
+    pub target_file_size: usize,
+    pub max_levels: usize,
+    /// Time window compaction only: the length of a window, and how long a window is kept after it
+    /// ends. None keeps everything
+    pub time_window_size_ms: u64,
+    pub time_window_retention_ms: Option<u64>,
+    /// The number of edits appended to the manifest before it is replaced by a snapshot
+    pub manifest_snapshot_interval: usize,
+    pub orphan_files: OrphanFileAction,
+    pub wal_sync: WalSyncMode,
+    pub wal_sync_interval_ms: u64,
+    pub wal_recovery: WalRecoveryMode,
+    /// Whether no write is appended to the WAL, like with `WriteOptions::disable_wal`
+    pub disable_wal: bool,
+    /// Whether the WAL segments of flushed memtables are moved to `logs/archive` instead of being
+    /// deleted. The oldest archived segments are deleted once the archive is larger than
+    /// `wal_archive_max_bytes`, or once they are older than `wal_archive_max_age_ms`. None keeps
+    /// everything
+    pub wal_archive: bool,
+    pub wal_archive_max_bytes: Option<u64>,
+    pub wal_archive_max_age_ms: Option<u64>,
+    /// Values whose encoded size is at least `blob_threshold` bytes are written to blob files when
+    /// flushed, and the tables only keep pointers to them. None keeps every value in the tables.
+    /// A blob file is rewritten once the part of it no table references reaches
+    /// `blob_gc_threshold`
+    pub blob_threshold: Option<usize>,
+    pub blob_gc_threshold: f64,
+    /// The records of the storage files are grouped in blocks of about `block_size` bytes before
+    /// they are compressed with `compression`. The tables with no older data below them, written by
+    /// compactions that drop tombstones, use `bottommost_compression` instead when it is set
+    pub block_size: usize,
+    pub compression: Compression,
+    pub bottommost_compression: Option<Compression>,
+    pub memtable_rep: MemTableRepKind,
+    /// Takes the place of `compaction_style`. Can only be set from code
+    #[serde(skip)]
+    pub custom_compaction_strategy: Option<Arc<dyn CompactionStrategy>>,
+    /// A memory budget for the memtables, shared with the other engines given the same manager.
+    /// Can only be set from code
+    #[serde(skip)]
+    pub write_buffer_manager: Option<Arc<WriteBufferManager>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            db_path: "temp/db".to_string(),
+            index_key_string_size: 24,
+            index_offset_size: 8,
+            initial_index_file_threshold: 1024,
+            memtable_max_bytes: Some(67108864),
+            wal_max_bytes: None,
+            memtable_max_age_ms: None,
+            compaction_threshold: 3,
+            compaction_tier_size: 2097152,
+            compaction_size_multiplier: 10,
+            compaction_threads: 1,
+            compaction_style: CompactionStyle::SizeTiered,
+            target_file_size: 524288,
+            max_levels: 7,
+            time_window_size_ms: 3600000,
+            time_window_retention_ms: None,
+            manifest_snapshot_interval: 100,
+            orphan_files: OrphanFileAction::Delete,
+            wal_sync: WalSyncMode::None,
+            wal_sync_interval_ms: 100,
+            wal_recovery: WalRecoveryMode::TruncateTail,
+            disable_wal: false,
+            wal_archive: false,
+            wal_archive_max_bytes: None,
+            wal_archive_max_age_ms: None,
+            blob_threshold: None,
+            blob_gc_threshold: 0.5,
+            block_size: 4096,
+            compression: Compression::Lz4,
+            bottommost_compression: None,
+            memtable_rep: MemTableRepKind::SkipList,
+            custom_compaction_strategy: None,
+            write_buffer_manager: None,
+        }
+    }
 }
 
 impl Config {
+    /// Reads the config from a YAML file. Every field but `db_path` falls back to its default when
+    /// missing, so that a file without it doesn't open some other database
     pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
         let yaml_str = fs::read_to_string(path)?;
-        let config: Config = serde_yaml::from_str(&yaml_str)?;
+        let yaml: serde_yaml::Value = serde_yaml::from_str(&yaml_str)?;
+        if yaml.get("db_path").is_none() {
+            return Err(format!("{} has no db_path", path).into());
+        }
+        let config: Config = serde_yaml::from_value(yaml)?;
         Ok(config)
     }
+
+    pub fn compaction_strategy(&self) -> Arc<dyn CompactionStrategy> {
+        match (&self.custom_compaction_strategy, self.compaction_style) {
+            (Some(strategy), _) => Arc::clone(strategy),
+            (None, CompactionStyle::SizeTiered) => Arc::new(SizeTieredStrategy),
+            (None, CompactionStyle::Leveled) => Arc::new(LeveledStrategy),
+            (None, CompactionStyle::TimeWindow) => Arc::new(TimeWindowStrategy),
+        }
+    }
+
+    /// An empty memtable representation of the `memtable_rep` kind
+    pub fn memtable_rep<T: Clone + Send + Sync + 'static>(&self) -> Box<dyn MemTableRep<T>> {
+        match self.memtable_rep {
+            MemTableRepKind::SkipList => Box::new(SkipList::new()),
+            MemTableRepKind::Tree => Box::new(TreeRep::default()),
+            MemTableRepKind::Vector => Box::new(VectorRep::default()),
+            MemTableRepKind::Hash => Box::new(HashRep::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::{Compression, Config};
+
+    #[test]
+    fn db_path_is_required_in_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("config.yaml");
+        let path = path.to_str().unwrap();
+
+        fs::write(path, "compression: deflate\n").unwrap();
+        assert!(Config::from_file(path).is_err());
+
+        fs::write(path, "db_path: data/db\ncompression: deflate\n").unwrap();
+        let config = Config::from_file(path).unwrap();
+        assert_eq!(config.db_path, "data/db");
+        assert_eq!(config.compression, Compression::Deflate);
+        assert_eq!(config.block_size, Config::default().block_size);
+    }
 }
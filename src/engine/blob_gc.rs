@@ -0,0 +1,212 @@
+use std::{
+    fmt::Debug,
+    fs::File,
+    io::{BufReader, Result as IOResult},
+    slice,
+    sync::Arc,
+};
+
+use crate::{
+    compaction::{read_next_key, table_error},
+    memtable::{LogOperation, MemTableRecord},
+    serialization::SerializationEngine,
+    sstable::{
+        SSTable, SSTableBuilder,
+        blob::{BlobFile, BlobWriter, StoredValue, read_blob},
+        block::StorageReader,
+    },
+};
+
+use super::{EngineInner, manifest::VersionEdit};
+
+impl<T, S, SS> EngineInner<T, S, SS>
+where
+    T: MemTableRecord + Debug,
+    S: SerializationEngine<LogOperation<T>>,
+    SS: SerializationEngine<Option<T>>,
+{
+    /// Rewrites the blob file with the largest share of garbage, if it reaches
+    /// `blob_gc_threshold`, and returns whether it did. Its live values are copied to a new blob
+    /// file, and every table pointing to them is rewritten with the new pointers.
+    pub(super) fn collect_blob_garbage(&self) -> IOResult<bool> {
+        let Some((blob_file, tables)) = self.pick_blob_file() else {
+            return Ok(false);
+        };
+
+        let collected = self.rewrite_blob_file(&blob_file, &tables);
+
+        let mut compacting = self.compacting.lock().unwrap();
+        for table in tables.iter() {
+            compacting.remove(&table.storage_path);
+        }
+        collected.map(|_| true)
+    }
+
+    /// The blob file to rewrite and the tables referencing it, which are marked as being compacted.
+    /// Blob files referenced by a table of a running compaction are skipped.
+    fn pick_blob_file(&self) -> Option<(Arc<BlobFile>, Vec<Arc<SSTable>>)> {
+        let version = self.version();
+        let mut candidates: Vec<(f64, &Arc<BlobFile>)> = version
+            .blob_files()
+            .filter(|blob_file| blob_file.size > 0)
+            .map(|blob_file| {
+                let live = version.live_blob_bytes(blob_file.number);
+                (1.0 - live as f64 / blob_file.size as f64, blob_file)
+            })
+            .filter(|(garbage, _)| *garbage >= self.config.blob_gc_threshold)
+            .collect();
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let mut compacting = self.compacting.lock().unwrap();
+        candidates.into_iter().find_map(|(_, blob_file)| {
+            let tables: Vec<Arc<SSTable>> = version
+                .tables()
+                .filter(|table| {
+                    table
+                        .blobs
+                        .iter()
+                        .any(|(file, _)| *file == blob_file.number)
+                })
+                .cloned()
+                .collect();
+            if tables
+                .iter()
+                .any(|table| compacting.contains(&table.storage_path))
+            {
+                return None;
+            }
+            for table in tables.iter() {
+                compacting.insert(table.storage_path.clone());
+            }
+            Some((Arc::clone(blob_file), tables))
+        })
+    }
+
+    fn rewrite_blob_file(
+        &self,
+        blob_file: &Arc<BlobFile>,
+        tables: &[Arc<SSTable>],
+    ) -> IOResult<()> {
+        let db_path = &self.config.db_path;
+        let number = self.manifest.lock().unwrap().new_file_number()?;
+        let mut writer = BlobWriter::new(db_path, T::TYPE_NAME, number)?;
+
+        let mut outputs = vec![];
+        for table in tables {
+            let mut index_reader = BufReader::new(File::open(&table.index_path)?);
+            let mut storage_reader = StorageReader::open(&table.storage_path)?;
+            let mut builder = SSTableBuilder::new(self.serializer.as_ref(), &self.config)
+                .map_err(table_error)?
+                .with_sequence(table.max_sequence)
+                .with_timestamps(table.min_timestamp, table.max_timestamp);
+            while let Some((key, value)) = read_next_key(
+                &mut index_reader,
+                &mut storage_reader,
+                &self.config,
+                self.serializer.as_ref(),
+            )? {
+                let value = match value {
+                    StoredValue::Blob(pointer) if pointer.file == blob_file.number => {
+                        let encoded = read_blob(db_path, T::TYPE_NAME, &pointer)?;
+                        StoredValue::Blob(writer.append(&encoded)?)
+                    }
+                    value => value,
+                };
+                builder.add_stored(key, value).map_err(table_error)?;
+            }
+
+            let (index_path, storage_path) = self.get_next_index_storage_logs_name()?;
+            let mut output = builder
+                .finish(&storage_path, &index_path)
+                .map_err(table_error)?;
+            output.level = table.level;
+            outputs.push(Arc::new(output));
+        }
+        let new_file = Arc::new(writer.finish(db_path, T::TYPE_NAME)?);
+
+        let synced = new_file
+            .sync()
+            .and_then(|_| outputs.iter().try_for_each(|table| table.sync()));
+        if let Err(err) = synced {
+            new_file.mark_obsolete();
+            outputs.iter().for_each(|table| table.mark_obsolete());
+            return Err(err);
+        }
+        self.install_rewritten(blob_file, &new_file, tables, &outputs)
+    }
+
+    /// Replaces every table by its rewritten copy with its own edit, so that the tables of level 0
+    /// keep their order. The new blob file is added by the first edit and the old one removed by
+    /// the last, so the blob files referenced by the tables are in the manifest after every edit.
+    fn install_rewritten(
+        &self,
+        blob_file: &Arc<BlobFile>,
+        new_file: &Arc<BlobFile>,
+        inputs: &[Arc<SSTable>],
+        outputs: &[Arc<SSTable>],
+    ) -> IOResult<()> {
+        let mut version = self.version.write().unwrap();
+        let mut manifest = self.manifest.lock().unwrap();
+        let mut updated = (**version).clone();
+        let mut installed = 0;
+        let mut result = Ok(());
+
+        // Nothing references the blob file anymore
+        if inputs.is_empty() {
+            let next = updated.with_blob_files([], &[blob_file.number]);
+            let edit = VersionEdit::default().with_blob_files(&[], slice::from_ref(blob_file));
+            manifest.append(edit, &next)?;
+            new_file.mark_obsolete();
+            blob_file.mark_obsolete();
+            *version = Arc::new(next);
+            return Ok(());
+        }
+
+        for (i, (input, output)) in inputs.iter().zip(outputs).enumerate() {
+            let added = if i == 0 {
+                vec![Arc::clone(new_file)]
+            } else {
+                vec![]
+            };
+            let removed = if i + 1 == inputs.len() {
+                vec![Arc::clone(blob_file)]
+            } else {
+                vec![]
+            };
+            let next = updated
+                .with_compaction(
+                    slice::from_ref(input),
+                    vec![Arc::clone(output)],
+                    input.level,
+                )
+                .with_blob_files(
+                    added.iter().cloned(),
+                    &removed.iter().map(|file| file.number).collect::<Vec<_>>(),
+                );
+            let edit = VersionEdit::compacted(slice::from_ref(input), slice::from_ref(output))
+                .with_blob_files(&added, &removed);
+            if let Err(err) = manifest.append(edit, &next) {
+                result = Err(err);
+                break;
+            }
+            updated = next;
+            installed += 1;
+        }
+
+        // The edits that made it to the manifest are kept, the files of the others are deleted
+        inputs[..installed]
+            .iter()
+            .for_each(|table| table.mark_obsolete());
+        outputs[installed..]
+            .iter()
+            .for_each(|table| table.mark_obsolete());
+        if installed == 0 {
+            new_file.mark_obsolete();
+        }
+        if installed == inputs.len() {
+            blob_file.mark_obsolete();
+        }
+        *version = Arc::new(updated);
+        result
+    }
+}
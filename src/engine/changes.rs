@@ -0,0 +1,193 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::ErrorKind,
+    path::Path,
+    sync::{Arc, atomic::Ordering},
+};
+
+use crate::{
+    config::WalRecoveryMode,
+    memtable::{LogOperation, MemTableLogReader, MemTableRecord},
+    serialization::SerializationEngine,
+};
+
+use super::{Engine, EngineError, wal};
+
+/// @definition: An insertion or deletion, as it was appended to the WAL
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change<T: MemTableRecord> {
+    pub sequence: u64,
+    pub operation: LogOperation<T>,
+}
+
+/// @definition: The writes after a sequence number, read in order from the WAL segments that were
+/// there when the stream was created. Segments are only opened when the stream reaches them, and
+/// the newest one is read up to where it ended at that point.
+/// @field segments: The numbers of the segments left to read
+/// @field reader: The segment being read, and the path of its file
+/// @field started: Whether a change was read. Until then a segment that disappeared was retired
+/// before the stream reached it, and is skipped
+/// @field pending: The first change, read ahead to check that no history is missing
+pub struct ChangeStream<T, S>
+where
+    T: MemTableRecord,
+    S: SerializationEngine<LogOperation<T>>,
+{
+    db_path: String,
+    serializer: Arc<S>,
+    since: u64,
+    segments: VecDeque<u64>,
+    reader: Option<(MemTableLogReader<File>, String)>,
+    last_sequence: u64,
+    started: bool,
+    pending: Option<Change<T>>,
+}
+
+impl<T, S> ChangeStream<T, S>
+where
+    T: MemTableRecord,
+    S: SerializationEngine<LogOperation<T>>,
+{
+    fn next_change(&mut self) -> Result<Option<Change<T>>, EngineError> {
+        loop {
+            let Some((reader, path)) = &mut self.reader else {
+                let Some(number) = self.segments.pop_front() else {
+                    return Ok(None);
+                };
+                self.open(number)?;
+                continue;
+            };
+
+            let next = reader
+                .next_op(self.serializer.as_ref())
+                .map_err(|err| EngineError::ChangeFeed { err })?;
+            if let Some((sequence, operation)) = next {
+                self.last_sequence = sequence;
+                self.started = true;
+                return Ok(Some(Change {
+                    sequence,
+                    operation,
+                }));
+            }
+
+            // The end of the newest segment may be a write in progress
+            if reader.report().dropped_anything() && !self.segments.is_empty() {
+                return Err(EngineError::DBCorrupted { file: path.clone() });
+            }
+            self.reader = None;
+        }
+    }
+
+    /// Opens the segment where it is, or in the archive if it was retired in the meantime
+    fn open(&mut self, number: u64) -> Result<(), EngineError> {
+        let path = wal::segment_path(&self.db_path, T::TYPE_NAME, number);
+        let archived = path
+            .parent()
+            .unwrap()
+            .join("archive")
+            .join(path.file_name().unwrap());
+        for path in [path, archived] {
+            match File::open(&path) {
+                Ok(file) => {
+                    let reader = MemTableLogReader::open(
+                        file,
+                        WalRecoveryMode::TruncateTail,
+                        self.last_sequence,
+                    )
+                    .map_err(|err| EngineError::ChangeFeed { err })?;
+                    self.reader = Some((reader, path.display().to_string()));
+                    return Ok(());
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(EngineError::ChangeFeed { err }),
+            }
+        }
+
+        if self.started {
+            return Err(EngineError::ChangesDiscarded {
+                requested: self.since,
+                oldest: self.last_sequence + 1,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<T, S> Iterator for ChangeStream<T, S>
+where
+    T: MemTableRecord,
+    S: SerializationEngine<LogOperation<T>>,
+{
+    type Item = Result<Change<T>, EngineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(change) = self.pending.take() {
+            return Some(Ok(change));
+        }
+        loop {
+            match self.next_change() {
+                Ok(Some(change)) if change.sequence <= self.since => continue,
+                Ok(change) => return change.map(Ok),
+                Err(err) => {
+                    // Nothing is read past a gap
+                    self.segments.clear();
+                    self.reader = None;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+impl<T, S, SS> Engine<T, S, SS>
+where
+    T: MemTableRecord,
+    S: SerializationEngine<LogOperation<T>>,
+    SS: SerializationEngine<Option<T>>,
+{
+    /// Streams the insertions and deletions numbered after `sequence`, in order, for consumers
+    /// resuming from the last sequence number they processed. The changes are read from the live
+    /// and archived WAL segments, so they survive restarts, but only as far back as the oldest
+    /// segment kept: without `wal_archive` that is the oldest memtable that isn't flushed. Fails
+    /// with `EngineError::ChangesDiscarded` when some of the requested changes are gone. Writes
+    /// made without the WAL are never in the feed, and share the sequence number of the write
+    /// before them, so the numbers in the feed have no gaps.
+    pub fn changes_since(&self, sequence: u64) -> Result<ChangeStream<T, S>, EngineError> {
+        // Every write up to this one is in a segment listed below, or in a retired one
+        let last_sequence = self.inner.last_sequence.load(Ordering::SeqCst);
+
+        let logs_dir = Path::new(&self.inner.config.db_path).join("logs");
+        let list = |dir: &Path| {
+            wal::segment_numbers(dir, T::TYPE_NAME).map_err(|err| EngineError::ChangeFeed { err })
+        };
+        let mut segments = list(&logs_dir.join("archive"))?;
+        segments.extend(list(&logs_dir)?);
+        segments.sort();
+        segments.dedup();
+
+        let mut stream = ChangeStream {
+            db_path: self.inner.config.db_path.clone(),
+            serializer: Arc::clone(&self.inner.memtable_serializer),
+            since: sequence,
+            segments: segments.into(),
+            reader: None,
+            last_sequence: 0,
+            started: false,
+            pending: None,
+        };
+
+        let first = stream.next_change()?;
+        let oldest = first
+            .as_ref()
+            .map_or(last_sequence + 1, |change| change.sequence);
+        if oldest > sequence + 1 {
+            return Err(EngineError::ChangesDiscarded {
+                requested: sequence,
+                oldest,
+            });
+        }
+        stream.pending = first.filter(|change| change.sequence > sequence);
+        Ok(stream)
+    }
+}
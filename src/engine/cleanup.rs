@@ -0,0 +1,99 @@
+use std::{
+    collections::HashSet,
+    ffi::{OsStr, OsString},
+    fs::{self, create_dir_all},
+    io::{ErrorKind, Result as IOResult},
+    path::{Path, PathBuf},
+};
+
+use crate::config::OrphanFileAction;
+
+/// @definition: The files that were left behind by a crash and cleaned up when the engine was
+/// opened
+/// @field deleted: The files that were deleted
+/// @field quarantined: The files that were moved to the `lost` directory, by their old path. A file
+/// named like one quarantined before gets a counter after its name there
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct OrphanReport {
+    pub deleted: Vec<PathBuf>,
+    pub quarantined: Vec<PathBuf>,
+}
+
+impl OrphanReport {
+    pub fn is_empty(&self) -> bool {
+        self.deleted.is_empty() && self.quarantined.is_empty()
+    }
+}
+
+/// Cleans up the files of the record type that aren't in `referenced`: partial table and blob
+/// files, the temporary files of compactions, manifests and WAL rewrites, and replaced manifests.
+/// Only the file names are compared, since they are unique across the directories.
+pub(super) fn remove_orphans(
+    db_path: &str,
+    type_name: &str,
+    referenced: &HashSet<OsString>,
+    action: OrphanFileAction,
+) -> IOResult<OrphanReport> {
+    let prefix = format!("{}-", type_name);
+    let mut report = OrphanReport::default();
+
+    for dir in ["", "storage", "indices", "blobs", "metadata", "logs"] {
+        let dir_path = Path::new(db_path).join(dir);
+        if !dir_path.exists() {
+            continue;
+        }
+
+        for entry in fs::read_dir(&dir_path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(name_str) = name.to_str() else {
+                continue;
+            };
+            // The database directory also has the directories and the files of other components,
+            // and the WAL segments are found by `wal::recover_segments`
+            let temporary = name_str.ends_with(".tmp");
+            if !name_str.starts_with(&prefix)
+                || ((dir.is_empty() || dir == "logs") && !temporary)
+                || !entry.file_type()?.is_file()
+                || referenced.contains(&name)
+            {
+                continue;
+            }
+
+            let path = entry.path();
+            match action {
+                OrphanFileAction::Delete => {
+                    fs::remove_file(&path)?;
+                    report.deleted.push(path);
+                }
+                OrphanFileAction::Quarantine => {
+                    let lost = Path::new(db_path).join("lost").join(dir);
+                    create_dir_all(&lost)?;
+                    quarantine(&path, &lost, &name)?;
+                    report.quarantined.push(path);
+                }
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Moves the file to the `lost` directory without replacing a file quarantined before: a name
+/// that is taken gets the first free counter after it, as in `Photo-9.log.1`
+fn quarantine(path: &Path, lost: &Path, name: &OsStr) -> IOResult<()> {
+    let mut target = lost.join(name);
+    for counter in 1.. {
+        // Unlike a rename, the link fails when the target exists
+        match fs::hard_link(path, &target) {
+            Ok(()) => break,
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                let mut numbered = name.to_os_string();
+                numbered.push(format!(".{}", counter));
+                target = lost.join(numbered);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    fs::remove_file(path)
+}
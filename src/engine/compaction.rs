@@ -0,0 +1,195 @@
+use std::{
+    fmt::Debug,
+    io::Result as IOResult,
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    compaction::{CompactionJob, compact},
+    memtable::{LogOperation, MemTableRecord},
+    serialization::SerializationEngine,
+    sstable::SSTable,
+};
+
+use super::{EngineInner, manifest::VersionEdit};
+
+/// @definition: Wakes the compaction threads whenever the set of sstables changes
+/// @field pending: Set when the tables changed since a thread last looked for compactions
+/// @field shutdown: Set once the last engine handle is dropped
+#[derive(Default)]
+struct SignalState {
+    pending: bool,
+    shutdown: bool,
+}
+
+#[derive(Default)]
+pub(super) struct CompactionSignal {
+    state: Mutex<SignalState>,
+    condvar: Condvar,
+}
+
+impl CompactionSignal {
+    pub fn notify(&self) {
+        self.state.lock().unwrap().pending = true;
+        self.condvar.notify_all();
+    }
+
+    fn shutdown(&self) {
+        self.state.lock().unwrap().shutdown = true;
+        self.condvar.notify_all();
+    }
+
+    /// Blocks until there is something to compact. Returns false once the engine shuts down.
+    fn wait(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while !state.pending && !state.shutdown {
+            state = self.condvar.wait(state).unwrap();
+        }
+        state.pending = false;
+        !state.shutdown
+    }
+}
+
+/// @definition: The pool of threads running compactions in the background. Each thread picks tables
+/// that no other thread is compacting, so several tiers can be compacted at the same time.
+pub(super) struct CompactionWorkers {
+    signal: Arc<CompactionSignal>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl CompactionWorkers {
+    pub fn spawn<T, S, SS>(inner: Arc<EngineInner<T, S, SS>>, threads: usize) -> CompactionWorkers
+    where
+        T: MemTableRecord + Debug + Send + Sync + 'static,
+        S: SerializationEngine<LogOperation<T>> + Send + Sync + 'static,
+        SS: SerializationEngine<Option<T>> + Send + Sync + 'static,
+    {
+        let handles = (0..threads)
+            .map(|_| {
+                let inner = Arc::clone(&inner);
+                thread::spawn(move || {
+                    // A failed compaction is recorded, and tried again after the next change
+                    while inner.compaction_signal.wait() {
+                        while let Ok(true) = inner.compact_once() {}
+                    }
+                })
+            })
+            .collect();
+
+        CompactionWorkers {
+            signal: Arc::clone(&inner.compaction_signal),
+            handles,
+        }
+    }
+}
+
+impl Drop for CompactionWorkers {
+    fn drop(&mut self) {
+        // Running compactions are finished, but no new ones are started
+        self.signal.shutdown();
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl<T, S, SS> EngineInner<T, S, SS>
+where
+    T: MemTableRecord + Debug,
+    S: SerializationEngine<LogOperation<T>>,
+    SS: SerializationEngine<Option<T>>,
+{
+    /// Asks the strategy for the next compaction, and marks its tables as being compacted. Jobs
+    /// using tables of a running compaction are skipped, in case the strategy returns them anyway.
+    fn pick_compaction(&self) -> Option<CompactionJob> {
+        let version = self.version();
+        let mut compacting = self.compacting.lock().unwrap();
+        let job = self
+            .strategy
+            .pick(&version, &compacting, &self.config)
+            .into_iter()
+            .find(|job| {
+                !job.inputs.is_empty()
+                    && job
+                        .inputs
+                        .iter()
+                        .all(|table| !compacting.contains(&table.storage_path))
+            })?;
+
+        for table in job.inputs.iter() {
+            compacting.insert(table.storage_path.clone());
+        }
+        Some(job)
+    }
+
+    /// Runs a single compaction if there is one to run, or else rewrites a blob file with too much
+    /// garbage, and returns whether one ran. The merge happens without holding any lock, so reads
+    /// keep using the old tables until the merged tables are installed. The outcome of the one
+    /// that ran replaces the one in `compaction_error`.
+    pub(super) fn compact_once(&self) -> IOResult<bool> {
+        let Some(job) = self.pick_compaction() else {
+            let collected = self.collect_blob_garbage();
+            if !matches!(collected, Ok(false)) {
+                *self.compaction_error.lock().unwrap() =
+                    collected.as_ref().err().map(|err| format!("{err:?}"));
+            }
+            return collected;
+        };
+
+        let result = match &job.output {
+            Some(output) => compact(
+                job.inputs.iter().map(|table| table.as_ref()).collect(),
+                self.serializer.as_ref(),
+                &self.config,
+                output,
+                || self.get_next_index_storage_logs_name(),
+            ),
+            None => Ok(vec![]),
+        };
+
+        let result = result.and_then(|outputs| self.install_compaction(&job, outputs));
+        *self.compaction_error.lock().unwrap() =
+            result.as_ref().err().map(|err| format!("{err:?}"));
+
+        let mut compacting = self.compacting.lock().unwrap();
+        for table in job.inputs.iter() {
+            compacting.remove(&table.storage_path);
+        }
+        result.map(|_| true)
+    }
+
+    /// Replaces the inputs by the outputs, once the outputs are durable. The inputs are deleted
+    /// once the versions still using them are dropped, and the outputs right away if they can't be
+    /// installed. So are the blob files that the outputs don't reference anymore.
+    fn install_compaction(&self, job: &CompactionJob, outputs: Vec<SSTable>) -> IOResult<()> {
+        let outputs: Vec<Arc<SSTable>> = outputs.into_iter().map(Arc::new).collect();
+        let installed = outputs
+            .iter()
+            .try_for_each(|table| table.sync())
+            .and_then(|_| {
+                let mut version = self.version.write().unwrap();
+                let updated = version.with_compaction(
+                    &job.inputs,
+                    outputs.clone(),
+                    job.output.as_ref().map_or(0, |output| output.level),
+                );
+                let unreferenced = updated.unreferenced_blob_files();
+                let removed: Vec<u64> = unreferenced.iter().map(|file| file.number).collect();
+                let updated = updated.with_blob_files([], &removed);
+
+                let edit = VersionEdit::compacted(&job.inputs, &outputs)
+                    .with_blob_files(&[], &unreferenced);
+                self.manifest.lock().unwrap().append(edit, &updated)?;
+                job.inputs.iter().for_each(|table| table.mark_obsolete());
+                unreferenced.iter().for_each(|file| file.mark_obsolete());
+                *version = Arc::new(updated);
+                Ok(())
+            });
+
+        if installed.is_err() {
+            outputs.iter().for_each(|table| table.mark_obsolete());
+        }
+        installed
+    }
+}
@@ -4,8 +4,19 @@ use std::io;
 pub enum EngineError {
     DBDoesntExist,
     MemtableInitialization { err: io::Error },
+    MemtableRotation { err: io::Error },
+    ManifestRecovery { err: io::Error },
+    OrphanCleanup { err: io::Error },
     Insertion { err: io::Error },
     Deletion { err: io::Error },
+    WalSync { err: io::Error },
+    BackgroundWalSync { message: String },
+    BackgroundFlush { message: String },
+    Compaction { err: io::Error },
+    BackgroundCompaction { message: String },
     DBFileDeleted { file: String },
     DBCorrupted { file: String },
+    ChangeFeed { err: io::Error },
+    ChangesDiscarded { requested: u64, oldest: u64 },
+    Ingestion { file: String, message: String },
 }
@@ -0,0 +1,430 @@
+use std::{
+    fmt::Debug,
+    mem,
+    sync::{
+        Arc, MutexGuard, Weak,
+        atomic::Ordering,
+        mpsc::{self, RecvTimeoutError, Sender},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    config::{Config, WalSyncMode},
+    memtable::{LogOperation, MemTable, MemTableRecord},
+    serialization::SerializationEngine,
+    sstable::{SSTableBuilder, error::SSTableError, sync_dir},
+    write_buffer::BufferedEngine,
+};
+
+use super::{
+    EngineInner,
+    error::EngineError,
+    manifest::VersionEdit,
+    wal::{retire_segment, segment_path},
+};
+
+/// @definition: The memtable that was swapped out and is waiting for the background thread
+/// @field immutable: Still served by reads until its table is added to the sstables
+/// @field immutable_segment: The number of the WAL segment of the immutable memtable
+/// @field segment: The number of the WAL segment of the memtable accepting writes
+/// @field sequence: The sequence number of the last write in the immutable memtable
+/// @field error: The reason the last flush failed. Once set, no more memtables are swapped out
+/// @field unretired: The WAL segments of flushed memtables that couldn't be deleted or archived.
+/// They are tried again after the next flush, and when the engine is opened
+pub(super) struct FlushState<T, S>
+where
+    T: MemTableRecord,
+    S: SerializationEngine<LogOperation<T>>,
+{
+    pub immutable: Option<Arc<MemTable<T, S>>>,
+    pub immutable_segment: u64,
+    pub segment: u64,
+    pub sequence: u64,
+    pub error: Option<String>,
+    pub unretired: Vec<u64>,
+}
+
+impl<T, S> FlushState<T, S>
+where
+    T: MemTableRecord,
+    S: SerializationEngine<LogOperation<T>>,
+{
+    pub fn new(
+        immutable: Option<Arc<MemTable<T, S>>>,
+        immutable_segment: u64,
+        segment: u64,
+        sequence: u64,
+    ) -> Self {
+        FlushState {
+            immutable,
+            immutable_segment,
+            segment,
+            sequence,
+            error: None,
+            unretired: vec![],
+        }
+    }
+}
+
+/// @definition: The thread flushing immutable memtables. With `memtable_max_age_ms`, it also wakes
+/// up when the memtable gets that old, to flush the writes even when no more come. It stops once
+/// the last engine handle is dropped, after flushing whatever was already scheduled
+pub(super) struct FlushWorker {
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl FlushWorker {
+    pub fn spawn<T, S, SS>(inner: Arc<EngineInner<T, S, SS>>) -> FlushWorker
+    where
+        T: MemTableRecord + Debug + Send + Sync + 'static,
+        S: SerializationEngine<LogOperation<T>> + Send + Sync + 'static,
+        SS: SerializationEngine<Option<T>> + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            loop {
+                let received = match inner.memtable_expiry() {
+                    Some(timeout) => receiver.recv_timeout(timeout),
+                    None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok(()) => inner.flush_immutable(),
+                    Err(RecvTimeoutError::Timeout) => {
+                        if inner.rotate_expired_memtable() {
+                            inner.flush_immutable();
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+
+        FlushWorker {
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+
+    pub fn schedule(&self) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(());
+        }
+    }
+}
+
+impl Drop for FlushWorker {
+    fn drop(&mut self) {
+        // Closing the channel lets the thread drain the pending flushes and exit
+        drop(self.sender.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// @definition: An engine as seen by its `WriteBufferManager`. It doesn't keep the engine open
+pub(super) struct FlushHandle<T, S, SS>
+where
+    T: MemTableRecord,
+    S: SerializationEngine<LogOperation<T>>,
+    SS: SerializationEngine<Option<T>>,
+{
+    inner: Weak<EngineInner<T, S, SS>>,
+    flusher: Weak<FlushWorker>,
+}
+
+impl<T, S, SS> FlushHandle<T, S, SS>
+where
+    T: MemTableRecord,
+    S: SerializationEngine<LogOperation<T>>,
+    SS: SerializationEngine<Option<T>>,
+{
+    pub fn new(inner: &Arc<EngineInner<T, S, SS>>, flusher: &Arc<FlushWorker>) -> Self {
+        FlushHandle {
+            inner: Arc::downgrade(inner),
+            flusher: Arc::downgrade(flusher),
+        }
+    }
+}
+
+impl<T, S, SS> BufferedEngine for FlushHandle<T, S, SS>
+where
+    T: MemTableRecord + Debug,
+    S: SerializationEngine<LogOperation<T>> + Send + Sync,
+    SS: SerializationEngine<Option<T>> + Send + Sync,
+{
+    fn memtable_size(&self) -> Option<u64> {
+        let inner = self.inner.upgrade()?;
+        let size = inner.memtable.read().unwrap().size();
+        Some(size)
+    }
+
+    fn flush_memtable(&self) -> bool {
+        let (Some(inner), Some(flusher)) = (self.inner.upgrade(), self.flusher.upgrade()) else {
+            return false;
+        };
+        // A memtable that fails to be swapped out keeps taking writes, and is tried again while
+        // the budget is exceeded. The write that fills it gets the error
+        let rotated = inner.try_rotate_memtable().unwrap_or(false);
+        if rotated {
+            flusher.schedule();
+        }
+        rotated
+    }
+
+    fn is_flushing(&self) -> bool {
+        self.inner.upgrade().is_some_and(|inner| {
+            let state = inner.flush_state.lock().unwrap();
+            state.immutable.is_some() && state.error.is_none()
+        })
+    }
+}
+
+impl<T, S, SS> EngineInner<T, S, SS>
+where
+    T: MemTableRecord + Debug,
+    S: SerializationEngine<LogOperation<T>>,
+    SS: SerializationEngine<Option<T>>,
+{
+    /// Whether a flush trigger of the config fires for the memtable
+    fn memtable_is_full(&self, memtable: &MemTable<T, S>) -> bool {
+        let Config {
+            index_offset_size,
+            index_key_string_size,
+            initial_index_file_threshold,
+            memtable_max_bytes,
+            wal_max_bytes,
+            memtable_max_age_ms,
+            ..
+        } = self.config.as_ref();
+
+        let pair_size = index_key_string_size + index_offset_size;
+        pair_size * memtable.len() >= *initial_index_file_threshold
+            || memtable_max_bytes.is_some_and(|max| memtable.size() >= max)
+            || wal_max_bytes.is_some_and(|max| memtable.log_size() >= max)
+            || memtable_max_age_ms.is_some_and(|max| memtable.age().is_some_and(|age| age >= max))
+    }
+
+    /// How long until the memtable is `memtable_max_age_ms` old. None without the age trigger, or
+    /// once a flush failed
+    fn memtable_expiry(&self) -> Option<Duration> {
+        let max_age = self.config.memtable_max_age_ms?;
+        if self.flush_state.lock().unwrap().error.is_some() {
+            return None;
+        }
+        let age = self.memtable.read().unwrap().age().unwrap_or(0);
+        Some(Duration::from_millis(max_age.saturating_sub(age)))
+    }
+
+    /// Swaps the memtable out once it is `memtable_max_age_ms` old. Runs on the flush thread, so it
+    /// never waits for the previous memtable to be flushed
+    fn rotate_expired_memtable(&self) -> bool {
+        let memtable = Arc::clone(&self.memtable.read().unwrap());
+        if memtable.is_empty() || !self.memtable_is_full(&memtable) {
+            return false;
+        }
+        drop(memtable);
+        // On failure the memtable stays expired, so the next write tries again and gets the error
+        self.try_rotate_memtable().unwrap_or(false)
+    }
+
+    /// Moves the memtable into the immutable slot and starts a fresh memtable and log in its place.
+    /// Returns whether a memtable was swapped out, in which case the flush thread has to be woken.
+    pub(super) fn rotate_memtable(&self, force: bool) -> Result<bool, EngineError> {
+        let mut state = self.flush_state.lock().unwrap();
+        loop {
+            let memtable = Arc::clone(&self.memtable.read().unwrap());
+            if memtable.is_empty() || (!force && !self.memtable_is_full(&memtable)) {
+                return Ok(false);
+            }
+            if let Some(message) = &state.error {
+                return Err(EngineError::BackgroundFlush {
+                    message: message.clone(),
+                });
+            }
+            if state.immutable.is_none() {
+                break;
+            }
+            state = self.flushed.wait(state).unwrap();
+        }
+        self.swap_memtable(&mut state)?;
+        Ok(true)
+    }
+
+    /// Like a forced `rotate_memtable`, without waiting for the previous memtable to be flushed.
+    /// Returns false when it still is, or when a flush failed
+    pub(super) fn try_rotate_memtable(&self) -> Result<bool, EngineError> {
+        let mut state = self.flush_state.lock().unwrap();
+        if state.immutable.is_some()
+            || state.error.is_some()
+            || self.memtable.read().unwrap().is_empty()
+        {
+            return Ok(false);
+        }
+        self.swap_memtable(&mut state)?;
+        Ok(true)
+    }
+
+    /// Moves the memtable into the empty immutable slot
+    fn swap_memtable(&self, state: &mut MutexGuard<FlushState<T, S>>) -> Result<(), EngineError> {
+        let mut memtable = self.memtable.write().unwrap();
+        // The writes not synced yet would otherwise only be synced once they are flushed
+        if self.config.wal_sync != WalSyncMode::None {
+            memtable
+                .log
+                .sync()
+                .map_err(|err| EngineError::MemtableRotation { err })?;
+        }
+        let number = self
+            .manifest
+            .lock()
+            .unwrap()
+            .new_file_number()
+            .map_err(|err| EngineError::MemtableRotation { err })?;
+        let log_path = segment_path(&self.config.db_path, T::TYPE_NAME, number);
+        let fresh = MemTable::open_or_build_with(
+            &log_path.display().to_string(),
+            Arc::clone(&self.memtable_serializer),
+            &self.config,
+            Arc::clone(&self.last_sequence),
+        )
+        .map_err(|err| EngineError::MemtableRotation { err })?;
+        sync_dir(log_path.parent().unwrap())
+            .map_err(|err| EngineError::MemtableRotation { err })?;
+
+        // No write is in flight while the write lock is held
+        state.sequence = self.last_sequence.load(Ordering::SeqCst);
+        state.immutable = Some(mem::replace(&mut *memtable, Arc::new(fresh)));
+        state.immutable_segment = mem::replace(&mut state.segment, number);
+        Ok(())
+    }
+
+    pub(super) fn wait_for_flush(&self) -> Result<(), EngineError> {
+        let mut state = self.flush_state.lock().unwrap();
+        while state.immutable.is_some() {
+            if let Some(message) = &state.error {
+                return Err(EngineError::BackgroundFlush {
+                    message: message.clone(),
+                });
+            }
+            state = self.flushed.wait(state).unwrap();
+        }
+        Ok(())
+    }
+
+    /// Writes the immutable memtable to a new SSTable. The memtable stays readable until the table
+    /// is added to the sstables. A crash at any point loses no acknowledged write, since the steps
+    /// are made durable in order:
+    /// 1. The table files and its blob file are written and synced, along with their directories
+    /// 2. The table is appended to the manifest along with the number of the oldest segment still
+    ///    needed, and the manifest is synced
+    /// 3. Only then the segment of the memtable is deleted or archived, and the removal synced
+    ///
+    /// A crash before step 2 leaves table files that are cleaned up as orphans, while the segment
+    /// is replayed. A crash before step 3 leaves a segment older than the one in the manifest,
+    /// which is retired when the engine is opened. A segment that fails to be retired doesn't fail
+    /// the flush, and is tried again after the next one.
+    pub(super) fn flush_immutable(&self) {
+        let (memtable, sequence, immutable_segment, segment) = {
+            let state = self.flush_state.lock().unwrap();
+            let Some(memtable) = state.immutable.clone() else {
+                return;
+            };
+            (
+                memtable,
+                state.sequence,
+                state.immutable_segment,
+                state.segment,
+            )
+        };
+
+        let result = self.write_sstable(&memtable, sequence, segment);
+        // The memtable is freed, and its memory released, as soon as it leaves the immutable slot
+        drop(memtable);
+
+        let mut state = self.flush_state.lock().unwrap();
+        match result {
+            Ok(()) => {
+                state.immutable = None;
+                state.unretired.push(immutable_segment);
+            }
+            Err(err) => state.error = Some(format!("{err:?}")),
+        }
+        let unretired = mem::take(&mut state.unretired);
+        drop(state);
+
+        // The segments are no longer needed, whether they are retired now or later
+        let unretired: Vec<u64> = unretired
+            .into_iter()
+            .filter(|number| {
+                let log_path = segment_path(&self.config.db_path, T::TYPE_NAME, *number);
+                log_path.exists() && retire_segment(&log_path, T::TYPE_NAME, &self.config).is_err()
+            })
+            .collect();
+        self.flush_state.lock().unwrap().unretired.extend(unretired);
+        self.flushed.notify_all();
+    }
+
+    fn write_sstable(
+        &self,
+        memtable: &MemTable<T, S>,
+        sequence: u64,
+        log_number: u64,
+    ) -> Result<(), SSTableError> {
+        let (index_path, storage_path) = self
+            .get_next_index_storage_logs_name()
+            .map_err(|err| SSTableError::LogWriteError { err })?;
+
+        let mut builder =
+            SSTableBuilder::new(self.serializer.as_ref(), &self.config)?.with_sequence(sequence);
+        if let Some((min_timestamp, max_timestamp)) = memtable.timestamps() {
+            builder = builder.with_timestamps(min_timestamp, max_timestamp);
+        }
+        if self.config.blob_threshold.is_some() {
+            let number = self
+                .manifest
+                .lock()
+                .unwrap()
+                .new_file_number()
+                .map_err(|err| SSTableError::LogWriteError { err })?;
+            builder = builder.with_blob_file(number);
+        }
+        for (key, value) in memtable.iter() {
+            builder.add(key, value)?;
+        }
+        let (table, blob_file) = builder.finish_with_blobs(&storage_path, &index_path)?;
+        let table = Arc::new(table);
+        let blob_files: Vec<_> = blob_file.map(Arc::new).into_iter().collect();
+        let discard = || {
+            table.mark_obsolete();
+            blob_files.iter().for_each(|file| file.mark_obsolete());
+        };
+        let synced = table
+            .sync()
+            .and_then(|_| blob_files.iter().try_for_each(|file| file.sync()));
+        if let Err(err) = synced {
+            discard();
+            return Err(SSTableError::LogWriteError { err });
+        }
+
+        let mut version = self.version.write().unwrap();
+        let updated = version
+            .with_flushed(Arc::clone(&table))
+            .with_blob_files(blob_files.iter().cloned(), &[]);
+        let edit =
+            VersionEdit::flushed(&table, sequence, log_number).with_blob_files(&blob_files, &[]);
+        let appended = self.manifest.lock().unwrap().append(edit, &updated);
+        if let Err(err) = appended {
+            discard();
+            return Err(SSTableError::LogWriteError { err });
+        }
+        *version = Arc::new(updated);
+        drop(version);
+
+        self.compaction_signal.notify();
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    fmt::Debug,
+    fs::{self, OpenOptions},
+    io::Result as IOResult,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    memtable::{LogOperation, MemTableRecord},
+    serialization::SerializationEngine,
+    sstable::{SSTable, footer::TableFooter},
+};
+
+use super::{Engine, EngineError, manifest::VersionEdit};
+
+impl<T, S, SS> Engine<T, S, SS>
+where
+    T: MemTableRecord + Debug + Send + Sync + 'static,
+    S: SerializationEngine<LogOperation<T>> + Send + Sync + 'static,
+    SS: SerializationEngine<Option<T>> + Send + Sync + 'static,
+{
+    /// Adds tables built outside of the engine, like with `SSTableBuilder`, given as pairs of
+    /// storage and index paths. Their values must be in the tables, not in blob files. The tables
+    /// are validated where they are, without being written to, and a table with unreadable entries
+    /// is rejected rather than cut. They must not overlap each other. They are newer than every
+    /// write made before the call: when the memtables have keys in their range, the memtables are
+    /// flushed first. The index files are then linked, or copied, into the database under new file
+    /// numbers, and the storage files copied so that their footer can record that order without
+    /// changing the originals. All the tables are added to level 0 with a single manifest edit, so
+    /// either all of them or none are part of the database.
+    pub fn ingest_files(&self, files: &[(String, String)]) -> Result<(), EngineError> {
+        let mut tables = vec![];
+        for (storage_path, index_path) in files {
+            tables.push(self.check_table(storage_path, index_path)?);
+        }
+
+        let mut ranges: Vec<_> = tables.iter().zip(files).collect();
+        ranges.sort_by(|(a, _), (b, _)| a.min.cmp(&b.min));
+        if let Some(pair) = ranges
+            .windows(2)
+            .find(|pair| pair[0].0.max >= pair[1].0.min)
+        {
+            return Err(EngineError::Ingestion {
+                file: pair[1].1.0.clone(),
+                message: format!("Overlaps {}", pair[0].1.0),
+            });
+        }
+
+        let overlapping = |min: &str, max: &str| {
+            let immutable = self.inner.flush_state.lock().unwrap().immutable.clone();
+            self.inner.memtable.read().unwrap().overlaps(min, max)
+                || immutable.is_some_and(|immutable| immutable.overlaps(min, max))
+        };
+        if tables
+            .iter()
+            .any(|table| overlapping(&table.min, &table.max))
+        {
+            self.flush()?;
+        }
+
+        // The tables come after the last write, and after the flushed tables when it is in one of
+        // them, as their file numbers are greater. The records count as written now
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let footer = TableFooter {
+            max_sequence: self.inner.last_sequence.load(Ordering::SeqCst),
+            min_timestamp: now,
+            max_timestamp: now,
+        };
+
+        // The new files are deleted on every failure, until the tables are recorded
+        let mut added: Vec<Arc<SSTable>> = vec![];
+        for (table, (storage_path, _)) in tables.into_iter().zip(files) {
+            let failed = |err: std::io::Error| EngineError::Ingestion {
+                file: storage_path.clone(),
+                message: format!("{:?}", err),
+            };
+            let table = Arc::new(self.link_table(table, footer).map_err(failed)?);
+            table.sync().map_err(failed)?;
+            added.push(table);
+        }
+
+        let mut version = self.inner.version.write().unwrap();
+        let updated = added.iter().fold((**version).clone(), |updated, table| {
+            updated.with_flushed(Arc::clone(table))
+        });
+        self.inner
+            .manifest
+            .lock()
+            .unwrap()
+            .append(VersionEdit::ingested(&added), &updated)
+            .map_err(|err| EngineError::Ingestion {
+                file: files[0].0.clone(),
+                message: format!("{:?}", err),
+            })?;
+        for table in &added {
+            table.obsolete.store(false, Ordering::SeqCst);
+        }
+        *version = Arc::new(updated);
+        drop(version);
+
+        self.inner.compaction_signal.notify();
+        Ok(())
+    }
+
+    /// Reads the table where it is, and fails unless every entry can be read and its values are
+    /// all in the table
+    fn check_table(&self, storage_path: &str, index_path: &str) -> Result<SSTable, EngineError> {
+        let inspected = SSTable::inspect::<T, SS>(
+            storage_path,
+            index_path,
+            self.inner.serializer.as_ref(),
+            &self.inner.config,
+        );
+        let message = match inspected {
+            Ok((table, None)) if table.blobs.is_empty() => return Ok(table),
+            Ok((_, None)) => "Values in blob files can't be ingested".to_string(),
+            Ok(_) => "Some entries can't be read".to_string(),
+            Err(err) => format!("{:?}", err),
+        };
+        Err(EngineError::Ingestion {
+            file: storage_path.to_string(),
+            message,
+        })
+    }
+
+    /// Brings the files of a checked table into the database under a new file number, and returns
+    /// the table there, obsolete until it is recorded. The index is linked, as it is only read, and
+    /// the storage file is copied and given the footer
+    fn link_table(&self, table: SSTable, footer: TableFooter) -> IOResult<SSTable> {
+        let (index_path, storage_path) = self.inner.get_next_index_storage_logs_name()?;
+        let linked = SSTable {
+            storage_path,
+            index_path,
+            min: table.min.clone(),
+            max: table.max.clone(),
+            size: table.size,
+            count: table.count,
+            level: 0,
+            min_timestamp: footer.min_timestamp,
+            max_timestamp: footer.max_timestamp,
+            max_sequence: footer.max_sequence,
+            blobs: vec![],
+            obsolete: AtomicBool::new(true),
+        };
+
+        fs::copy(&table.storage_path, &linked.storage_path)?;
+        let mut storage = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&linked.storage_path)?;
+        footer.write(&mut storage)?;
+        link_or_copy(&table.index_path, &linked.index_path)?;
+        Ok(linked)
+    }
+}
+
+fn link_or_copy(from: &str, to: &str) -> IOResult<()> {
+    if fs::hard_link(from, to).is_err() {
+        fs::copy(from, Path::new(to))?;
+    }
+    Ok(())
+}
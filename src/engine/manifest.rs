@@ -0,0 +1,717 @@
+use std::{
+    ffi::OsStr,
+    fs::{self, File, OpenOptions, create_dir_all},
+    io::{self, BufRead, BufReader, ErrorKind, Result as IOResult, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, atomic::AtomicBool},
+};
+
+use crate::{
+    compaction::temp_file,
+    sstable::{SSTable, blob::BlobFile, sync_dir},
+};
+use bincode::{Decode, Encode, config::standard};
+
+use super::Version;
+
+/// How many file numbers are reserved in the manifest at a time
+const FILE_NUMBER_BATCH: u64 = 64;
+
+/// @definition: A table as it is recorded in the manifest
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub(super) struct TableRecord {
+    storage_path: String,
+    index_path: String,
+    min: String,
+    max: String,
+    size: u64,
+    count: u64,
+    level: u64,
+    min_timestamp: u64,
+    max_timestamp: u64,
+    max_sequence: u64,
+    blobs: Vec<(u64, u64)>,
+}
+
+/// @definition: A blob file as it is recorded in the manifest
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub(super) struct BlobFileRecord {
+    path: String,
+    number: u64,
+    size: u64,
+}
+
+impl From<&BlobFile> for BlobFileRecord {
+    fn from(blob_file: &BlobFile) -> Self {
+        BlobFileRecord {
+            path: blob_file.path.display().to_string(),
+            number: blob_file.number,
+            size: blob_file.size,
+        }
+    }
+}
+
+impl From<&SSTable> for TableRecord {
+    fn from(table: &SSTable) -> Self {
+        TableRecord {
+            storage_path: table.storage_path.clone(),
+            index_path: table.index_path.clone(),
+            min: table.min.clone(),
+            max: table.max.clone(),
+            size: table.size as u64,
+            count: table.count as u64,
+            level: table.level as u64,
+            min_timestamp: table.min_timestamp,
+            max_timestamp: table.max_timestamp,
+            max_sequence: table.max_sequence,
+            blobs: table.blobs.clone(),
+        }
+    }
+}
+
+impl From<TableRecord> for SSTable {
+    fn from(record: TableRecord) -> Self {
+        SSTable {
+            storage_path: record.storage_path,
+            index_path: record.index_path,
+            min: record.min,
+            max: record.max,
+            size: record.size as usize,
+            count: record.count as usize,
+            level: record.level as usize,
+            min_timestamp: record.min_timestamp,
+            max_timestamp: record.max_timestamp,
+            max_sequence: record.max_sequence,
+            blobs: record.blobs,
+            obsolete: AtomicBool::new(false),
+        }
+    }
+}
+
+/// @definition: A change to the set of sstables. Replaying the edits of the manifest in order
+/// gives the current version
+/// @field added: New tables. Without removed tables they are added after the tables of their
+/// level, like flushed tables
+/// @field removed: The storage paths of the tables replaced by the added ones
+/// @field next_file_number: The numbers below it may be used by files, if it changed
+/// @field last_sequence: The sequence number of the last write that is in the sstables, if it
+/// changed
+/// @field log_number: The WAL segments numbered below it are flushed, if it changed
+/// @field blob_files: New blob files
+/// @field removed_blob_files: The numbers of the blob files no table references anymore
+#[derive(Encode, Decode, Debug, Clone, Default, PartialEq)]
+pub(super) struct VersionEdit {
+    pub added: Vec<TableRecord>,
+    pub removed: Vec<String>,
+    pub next_file_number: Option<u64>,
+    pub last_sequence: Option<u64>,
+    pub log_number: Option<u64>,
+    pub blob_files: Vec<BlobFileRecord>,
+    pub removed_blob_files: Vec<u64>,
+}
+
+impl VersionEdit {
+    pub fn flushed(table: &SSTable, last_sequence: u64, log_number: u64) -> VersionEdit {
+        VersionEdit {
+            added: vec![table.into()],
+            last_sequence: Some(last_sequence),
+            log_number: Some(log_number),
+            ..Default::default()
+        }
+    }
+
+    pub fn ingested(tables: &[Arc<SSTable>]) -> VersionEdit {
+        VersionEdit {
+            added: tables.iter().map(|table| table.as_ref().into()).collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn compacted(inputs: &[Arc<SSTable>], outputs: &[Arc<SSTable>]) -> VersionEdit {
+        VersionEdit {
+            added: outputs.iter().map(|table| table.as_ref().into()).collect(),
+            removed: inputs
+                .iter()
+                .map(|table| table.storage_path.clone())
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// The same edit, that also adds and removes blob files
+    pub fn with_blob_files(mut self, added: &[Arc<BlobFile>], removed: &[Arc<BlobFile>]) -> Self {
+        self.blob_files = added.iter().map(|file| file.as_ref().into()).collect();
+        self.removed_blob_files = removed.iter().map(|file| file.number).collect();
+        self
+    }
+
+    fn snapshot(
+        version: &Version,
+        next_file_number: u64,
+        last_sequence: u64,
+        log_number: u64,
+    ) -> VersionEdit {
+        VersionEdit {
+            added: version
+                .tables()
+                .map(|table| table.as_ref().into())
+                .collect(),
+            removed: vec![],
+            next_file_number: Some(next_file_number),
+            last_sequence: Some(last_sequence),
+            log_number: Some(log_number),
+            blob_files: version
+                .blob_files()
+                .map(|file| file.as_ref().into())
+                .collect(),
+            removed_blob_files: vec![],
+        }
+    }
+
+    fn apply(&self, version: &Version) -> Version {
+        let added = self
+            .added
+            .iter()
+            .cloned()
+            .map(|record| Arc::new(SSTable::from(record)));
+        let updated = if self.removed.is_empty() {
+            Version::new(version.tables().cloned().chain(added))
+                .with_blob_files(version.blob_files().cloned(), &[])
+        } else {
+            let inputs: Vec<Arc<SSTable>> = version
+                .tables()
+                .filter(|table| self.removed.contains(&table.storage_path))
+                .cloned()
+                .collect();
+            let level = self.added.first().map_or(0, |table| table.level as usize);
+            version.with_compaction(&inputs, added.collect(), level)
+        };
+
+        let blob_files = self.blob_files.iter().map(|record| {
+            Arc::new(BlobFile::new(
+                record.number,
+                PathBuf::from(&record.path),
+                record.size,
+            ))
+        });
+        updated.with_blob_files(blob_files, &self.removed_blob_files)
+    }
+}
+
+/// @definition: What the manifest held when the engine was opened
+#[derive(Debug, Default)]
+pub(super) struct RecoveredManifest {
+    pub version: Version,
+    pub next_file_number: u64,
+    pub last_sequence: u64,
+    pub log_number: u64,
+}
+
+impl RecoveredManifest {
+    fn apply(&mut self, edit: &VersionEdit) {
+        self.version = edit.apply(&self.version);
+        self.next_file_number = edit.next_file_number.unwrap_or(self.next_file_number);
+        self.last_sequence = edit.last_sequence.unwrap_or(self.last_sequence);
+        self.log_number = edit.log_number.unwrap_or(self.log_number);
+    }
+}
+
+/// @definition: An append-only log of version edits, replacing the metadata file. Every record is
+/// `[length: u32][crc32: u32][edit]`, so a torn write at the end of the log is ignored while any
+/// other damage is reported. The `CURRENT` file names the manifest in use, and after every
+/// `snapshot_interval` edits the whole version is written to a new manifest that replaces it.
+/// @field number: The number in the name of the manifest in use
+/// @field edits: The edits appended since the last snapshot
+/// @field next_file_number: The next number handed out for a file name. Numbers are reserved in the
+/// manifest before they are handed out, so they are never used twice, even if a crash removes or
+/// leaves behind the files using them
+/// @field reserved_file_number: The numbers below it are reserved in the manifest
+pub(super) struct Manifest {
+    dir: PathBuf,
+    type_name: &'static str,
+    file: File,
+    number: u64,
+    edits: usize,
+    snapshot_interval: usize,
+    next_file_number: u64,
+    reserved_file_number: u64,
+    last_sequence: u64,
+    log_number: u64,
+}
+
+impl Manifest {
+    /// Replays the manifest named by `CURRENT`, or migrates the old metadata file when there is
+    /// none, and starts a new manifest with a snapshot of the recovered version.
+    pub fn open(
+        db_path: &str,
+        type_name: &'static str,
+        snapshot_interval: usize,
+    ) -> IOResult<(Manifest, RecoveredManifest)> {
+        let dir = Path::new(db_path).join("metadata");
+        let current_path = Self::current_path(&dir, type_name);
+        let legacy_path = dir.join(format!("{}.meta", type_name));
+
+        let mut recovered = RecoveredManifest::default();
+        let mut number = 0;
+        if current_path.exists() {
+            let name = fs::read_to_string(&current_path)?;
+            let name = name.trim();
+            number = name
+                .strip_prefix(&format!("{}-MANIFEST-", type_name))
+                .and_then(|number| number.parse().ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Invalid manifest name {:?} in CURRENT", name),
+                    )
+                })?;
+            for edit in Self::read_edits(&dir.join(name))? {
+                recovered.apply(&edit);
+            }
+        } else if legacy_path.exists() {
+            recovered.version = Version::new(
+                Self::read_legacy_metadata(&legacy_path)?
+                    .into_iter()
+                    .map(Arc::new),
+            );
+        }
+
+        // Tables written by a compaction that never made it to the manifest
+        recovered.next_file_number = recovered
+            .next_file_number
+            .max(Self::unused_file_number(db_path, type_name)?);
+
+        let snapshot = VersionEdit::snapshot(
+            &recovered.version,
+            recovered.next_file_number,
+            recovered.last_sequence,
+            recovered.log_number,
+        );
+        let manifest = Manifest {
+            file: Self::start(&dir, type_name, number + 1, &snapshot)?,
+            dir,
+            type_name,
+            number: number + 1,
+            edits: 0,
+            snapshot_interval: snapshot_interval.max(1),
+            next_file_number: recovered.next_file_number,
+            reserved_file_number: recovered.next_file_number,
+            last_sequence: recovered.last_sequence,
+            log_number: recovered.log_number,
+        };
+        let _ = fs::remove_file(legacy_path);
+        Ok((manifest, recovered))
+    }
+
+    /// Replaces the manifest by one holding only `version` and the sequence number of the last
+    /// write, for when the manifest is lost. The manifests from before are cleaned up as orphans
+    /// when the engine is opened.
+    pub fn create(
+        db_path: &str,
+        type_name: &str,
+        version: &Version,
+        last_sequence: u64,
+    ) -> IOResult<()> {
+        let dir = Path::new(db_path).join("metadata");
+        create_dir_all(&dir)?;
+
+        let prefix = format!("{}-MANIFEST-", type_name);
+        let mut number = 0;
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            if let Some(used) = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(&prefix)?.parse::<u64>().ok())
+            {
+                number = number.max(used);
+            }
+        }
+
+        let snapshot = VersionEdit::snapshot(
+            version,
+            Self::unused_file_number(db_path, type_name)?,
+            last_sequence,
+            0,
+        );
+        Self::start(&dir, type_name, number + 1, &snapshot)?;
+        let _ = fs::remove_file(dir.join(format!("{}.meta", type_name)));
+        Ok(())
+    }
+
+    /// Appends the edit that turned the previous version into `version`. The edit is durable once
+    /// this returns.
+    pub fn append(&mut self, mut edit: VersionEdit, version: &Version) -> IOResult<()> {
+        edit.next_file_number = Some(self.reserved_file_number);
+        Self::write_edit(&mut self.file, &edit)?;
+        self.file.sync_data()?;
+        self.last_sequence = edit.last_sequence.unwrap_or(self.last_sequence);
+        self.log_number = edit.log_number.unwrap_or(self.log_number);
+
+        self.edits += 1;
+        if self.edits >= self.snapshot_interval {
+            self.snapshot(version)?;
+        }
+        Ok(())
+    }
+
+    /// A number for the name of a new file
+    pub fn new_file_number(&mut self) -> IOResult<u64> {
+        if self.next_file_number == self.reserved_file_number {
+            let reserved_file_number = self.next_file_number + FILE_NUMBER_BATCH;
+            let edit = VersionEdit {
+                next_file_number: Some(reserved_file_number),
+                ..Default::default()
+            };
+            Self::write_edit(&mut self.file, &edit)?;
+            self.file.sync_data()?;
+            self.reserved_file_number = reserved_file_number;
+        }
+
+        self.next_file_number += 1;
+        Ok(self.next_file_number - 1)
+    }
+
+    fn snapshot(&mut self, version: &Version) -> IOResult<()> {
+        let snapshot = VersionEdit::snapshot(
+            version,
+            self.reserved_file_number,
+            self.last_sequence,
+            self.log_number,
+        );
+        self.file = Self::start(&self.dir, self.type_name, self.number + 1, &snapshot)?;
+        self.number += 1;
+        self.edits = 0;
+        Ok(())
+    }
+
+    /// Writes a new manifest starting with the snapshot, points `CURRENT` to it and removes the
+    /// manifest it replaces
+    fn start(dir: &Path, type_name: &str, number: u64, snapshot: &VersionEdit) -> IOResult<File> {
+        let name = Self::manifest_name(type_name, number);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(dir.join(&name))?;
+        Self::write_edit(&mut file, snapshot)?;
+        file.sync_all()?;
+
+        let mut current = temp_file(dir, type_name)?;
+        writeln!(current, "{}", name)?;
+        current.as_file().sync_all()?;
+        current.persist(Self::current_path(dir, type_name))?;
+        sync_dir(dir)?;
+
+        let _ = fs::remove_file(dir.join(Self::manifest_name(type_name, number - 1)));
+        Ok(file)
+    }
+
+    fn write_edit(file: &mut File, edit: &VersionEdit) -> IOResult<()> {
+        let payload = bincode::encode_to_vec(edit, standard()).map_err(io::Error::other)?;
+        let mut record = Vec::with_capacity(payload.len() + 8);
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+        file.write_all(&record)?;
+        file.flush()
+    }
+
+    fn read_edits(path: &Path) -> IOResult<Vec<VersionEdit>> {
+        let bytes = fs::read(path)?;
+        let mut rest = bytes.as_slice();
+        let mut edits = vec![];
+
+        while rest.len() >= 8 {
+            let len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+            let checksum = u32::from_le_bytes(rest[4..8].try_into().unwrap());
+            // A record cut short by a crash while it was being appended
+            let Some(payload) = rest.get(8..8 + len) else {
+                break;
+            };
+
+            let corrupted = || {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Corrupted manifest record at offset {} of {}",
+                        bytes.len() - rest.len(),
+                        path.display()
+                    ),
+                )
+            };
+            if crc32fast::hash(payload) != checksum {
+                return Err(corrupted());
+            }
+            let (edit, _) =
+                bincode::decode_from_slice(payload, standard()).map_err(|_| corrupted())?;
+
+            edits.push(edit);
+            rest = &rest[8 + len..];
+        }
+        Ok(edits)
+    }
+
+    /// The space separated metadata file used before the manifest, with a
+    /// `storage_path index_path min max count size` line for every table. Its tables are in level
+    /// 0, and their write times are unknown
+    fn read_legacy_metadata(path: &Path) -> IOResult<Vec<SSTable>> {
+        let invalid = |line: &str| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid metadata line {:?} in {}", line, path.display()),
+            )
+        };
+
+        BufReader::new(File::open(path)?)
+            .lines()
+            .map(|line| {
+                let line = line?;
+                let values: Vec<&str> = line.split(" ").collect();
+                if values.len() != 6 {
+                    return Err(invalid(&line));
+                }
+
+                let number = |idx: usize| -> IOResult<usize> {
+                    values[idx].parse().map_err(|_| invalid(&line))
+                };
+                Ok(SSTable {
+                    storage_path: values[0].to_string(),
+                    index_path: values[1].to_string(),
+                    min: values[2].to_string(),
+                    max: values[3].to_string(),
+                    count: number(4)?,
+                    size: number(5)?,
+                    level: 0,
+                    min_timestamp: 0,
+                    max_timestamp: 0,
+                    max_sequence: 0,
+                    blobs: vec![],
+                    obsolete: AtomicBool::new(false),
+                })
+            })
+            .collect()
+    }
+
+    /// The number after the highest one used by the table files, blob files and WAL segments of
+    /// this type
+    fn unused_file_number(db_path: &str, type_name: &str) -> IOResult<u64> {
+        let mut unused = 0;
+        for dir in ["storage", "indices", "blobs", "logs", "logs/archive"] {
+            let path = Path::new(db_path).join(dir);
+            if !path.exists() {
+                continue;
+            }
+            for entry in fs::read_dir(path)? {
+                let name = entry?.file_name();
+                if let Some(number) = table_file_number(&name, type_name) {
+                    unused = unused.max(number + 1);
+                }
+            }
+        }
+        Ok(unused)
+    }
+
+    /// The name of the manifest in use
+    pub fn name(&self) -> String {
+        Self::manifest_name(self.type_name, self.number)
+    }
+
+    fn manifest_name(type_name: &str, number: u64) -> String {
+        format!("{}-MANIFEST-{}", type_name, number)
+    }
+
+    fn current_path(dir: &Path, type_name: &str) -> PathBuf {
+        dir.join(format!("{}.CURRENT", type_name))
+    }
+}
+
+/// The number in the name of a table file or WAL segment of the record type, like `Photo-12.log`
+pub(super) fn table_file_number(name: &OsStr, type_name: &str) -> Option<u64> {
+    name.to_str()?
+        .strip_prefix(type_name)?
+        .strip_prefix('-')?
+        .strip_suffix(".log")?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, OpenOptions},
+        io::Write,
+        sync::{Arc, atomic::AtomicBool},
+    };
+
+    use tempfile::TempDir;
+
+    use crate::{
+        engine::{
+            Version,
+            manifest::{Manifest, VersionEdit},
+        },
+        sstable::SSTable,
+    };
+
+    fn table(name: &str, min: &str, max: &str, level: usize) -> Arc<SSTable> {
+        Arc::new(SSTable {
+            storage_path: name.to_string(),
+            index_path: format!("{}.index", name),
+            min: min.to_string(),
+            max: max.to_string(),
+            size: 100,
+            count: 10,
+            level,
+            min_timestamp: 1,
+            max_timestamp: 2,
+            max_sequence: 3,
+            blobs: vec![],
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    fn open(temp_dir: &TempDir, snapshot_interval: usize) -> (Manifest, Version, u64, u64) {
+        fs::create_dir_all(temp_dir.path().join("metadata")).unwrap();
+        let (manifest, recovered) =
+            Manifest::open(temp_dir.path().to_str().unwrap(), "Test", snapshot_interval)
+                .expect("Failed to open manifest");
+        (
+            manifest,
+            recovered.version,
+            recovered.next_file_number,
+            recovered.last_sequence,
+        )
+    }
+
+    fn names(version: &Version) -> Vec<Vec<String>> {
+        version
+            .levels()
+            .iter()
+            .map(|level| {
+                level
+                    .iter()
+                    .map(|table| table.storage_path.clone())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn edits_are_replayed_in_order() {
+        for snapshot_interval in [1, 100] {
+            let temp_dir = TempDir::new().expect("Failed to create temp dir");
+            let (mut manifest, mut version, _, _) = open(&temp_dir, snapshot_interval);
+
+            for (i, name) in ["a", "b", "c"].into_iter().enumerate() {
+                let table = table(name, "key with spaces", "z", 0);
+                version = version.with_flushed(Arc::clone(&table));
+                manifest
+                    .append(
+                        VersionEdit::flushed(&table, i as u64 * 10, i as u64),
+                        &version,
+                    )
+                    .unwrap();
+            }
+
+            // The merged table takes the place of "b", before the newer "c"
+            let inputs = version.level(0)[..2].to_vec();
+            let outputs = vec![table("ab", "key with spaces", "z", 0)];
+            version = version.with_compaction(&inputs, outputs.clone(), 0);
+            manifest
+                .append(VersionEdit::compacted(&inputs, &outputs), &version)
+                .unwrap();
+            drop(manifest);
+
+            let (_, recovered, _, last_sequence) = open(&temp_dir, snapshot_interval);
+            assert_eq!(names(&recovered), vec![vec!["ab", "c"]]);
+            assert_eq!(recovered.level(0)[0].min, "key with spaces");
+            assert_eq!(last_sequence, 20);
+        }
+    }
+
+    #[test]
+    fn file_numbers_are_never_reused() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let (mut manifest, _, _, _) = open(&temp_dir, 100);
+        let used: Vec<u64> = (0..100)
+            .map(|_| manifest.new_file_number().unwrap())
+            .collect();
+        assert!(used.windows(2).all(|pair| pair[0] < pair[1]));
+        drop(manifest);
+
+        // The numbers were handed out without any table being added
+        let (mut manifest, _, next_file_number, _) = open(&temp_dir, 100);
+        assert!(next_file_number > used[99]);
+        assert!(manifest.new_file_number().unwrap() > used[99]);
+        drop(manifest);
+
+        // A table file that never made it to the manifest
+        fs::create_dir_all(temp_dir.path().join("storage")).unwrap();
+        fs::write(temp_dir.path().join("storage/Test-1000.log"), "").unwrap();
+        fs::write(temp_dir.path().join("storage/Other-5000.log"), "").unwrap();
+        let (mut manifest, _, _, _) = open(&temp_dir, 100);
+        assert_eq!(manifest.new_file_number().unwrap(), 1001);
+    }
+
+    #[test]
+    fn torn_records_are_ignored_and_corrupted_ones_reported() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let (mut manifest, version, _, _) = open(&temp_dir, 100);
+        let version = version.with_flushed(table("a", "a", "b", 0));
+        manifest
+            .append(VersionEdit::flushed(&version.level(0)[0], 1, 1), &version)
+            .unwrap();
+        drop(manifest);
+
+        let metadata = temp_dir.path().join("metadata");
+        let current = fs::read_to_string(metadata.join("Test.CURRENT")).unwrap();
+        let manifest_path = metadata.join(current.trim());
+
+        // Half of a record's header
+        OpenOptions::new()
+            .append(true)
+            .open(&manifest_path)
+            .unwrap()
+            .write_all(&[42, 0])
+            .unwrap();
+        let (_, recovered, _, _) = open(&temp_dir, 100);
+        assert_eq!(names(&recovered), vec![vec!["a"]]);
+
+        let current = fs::read_to_string(metadata.join("Test.CURRENT")).unwrap();
+        let manifest_path = metadata.join(current.trim());
+        let mut bytes = fs::read(&manifest_path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&manifest_path, bytes).unwrap();
+
+        let result = Manifest::open(temp_dir.path().to_str().unwrap(), "Test", 100);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn legacy_metadata_is_migrated() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let metadata = temp_dir.path().join("metadata");
+        fs::create_dir_all(&metadata).unwrap();
+        fs::write(
+            metadata.join("Test.meta"),
+            "s1 i1 a m 10 100\ns2 i2 n z 10 100\n",
+        )
+        .unwrap();
+
+        let (_, recovered, _, _) = open(&temp_dir, 100);
+        assert_eq!(names(&recovered), vec![vec!["s1", "s2"]]);
+        assert_eq!(recovered.level(0)[1].size, 100);
+        assert!(!metadata.join("Test.meta").exists());
+
+        let (_, recovered, _, _) = open(&temp_dir, 100);
+        assert_eq!(names(&recovered), vec![vec!["s1", "s2"]]);
+
+        // Only the lines of the metadata file as it was written are accepted
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let metadata = temp_dir.path().join("metadata");
+        fs::create_dir_all(&metadata).unwrap();
+        fs::write(metadata.join("Test.meta"), "s1 i1 a m 10 100 1\n").unwrap();
+        assert!(Manifest::open(temp_dir.path().to_str().unwrap(), "Test", 100).is_err());
+    }
+}
@@ -1,49 +1,126 @@
+mod blob_gc;
+mod changes;
+mod cleanup;
+mod compaction;
 mod error;
+mod flush;
+mod ingest;
+mod manifest;
+mod repair;
+mod version;
+mod wal;
 
 use std::{
-    collections::HashMap,
+    collections::HashSet,
+    ffi::OsString,
     fmt::Debug,
-    fs::{self, File, OpenOptions, create_dir_all},
-    io::{BufRead, BufReader, Result as IOResult, Seek, SeekFrom, Write},
+    fs::create_dir_all,
+    io::Result as IOResult,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex, RwLock},
+    sync::{
+        Arc, Condvar, Mutex, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
 };
 
 use crate::{
-    compaction::compact,
-    config::Config,
-    memtable::{LogOperation, MemTable, MemTableRecord},
+    compaction::CompactionStrategy,
+    config::{Config, WriteOptions},
+    memtable::{LogOperation, MemTable, MemTableRecord, WalRecoveryReport},
     serialization::SerializationEngine,
-    sstable::SSTable,
+    sstable::error::SSTableError,
 };
+pub use changes::{Change, ChangeStream};
+pub use cleanup::OrphanReport;
+use compaction::{CompactionSignal, CompactionWorkers};
 use error::EngineError;
-use tempfile::NamedTempFile;
+use flush::{FlushHandle, FlushState, FlushWorker};
+use manifest::Manifest;
+pub use repair::RepairReport;
+pub use version::Version;
+use wal::WalSyncer;
 
-pub struct Engine<'a, T, S, SS>
+/// @definition: A handle to a database of records of type `T`. The handle owns its config and
+/// serializers, so it is `Send + Sync + 'static` whenever they are, and cloning it is cheap: every
+/// clone refers to the same memtable and sstables.
+pub struct Engine<T, S, SS>
 where
     T: MemTableRecord,
     S: SerializationEngine<LogOperation<T>>,
     SS: SerializationEngine<Option<T>>,
 {
-    metadata: Arc<Mutex<File>>,
-    memtable: MemTable<'a, T, S>,
-    sstables: Arc<RwLock<Vec<SSTable>>>,
-    config: &'a Config,
-    serializer: &'a SS,
-    flush_mutex: Mutex<()>,
+    inner: Arc<EngineInner<T, S, SS>>,
+    flusher: Arc<FlushWorker>,
+    compactor: Arc<CompactionWorkers>,
+    syncer: Arc<WalSyncer>,
 }
 
-impl<'a, T, S, SS> Engine<'a, T, S, SS>
+/// @field memtable: The memtable accepting writes. Writers hold the read lock while inserting, so
+/// taking the write lock waits for in-flight writes before the memtable is swapped out
+/// @field flush_state: The full memtable waiting to be flushed by the background thread
+/// @field flushed: Notified whenever the background thread is done with the immutable memtable
+/// @field version: The current set of sstables
+/// @field manifest: The log of the changes to the sstables. Only appended to while holding the
+/// version write lock, so the edits are in the same order as the versions
+/// @field compacting: The storage paths of the tables that are inputs of a running compaction
+/// @field compaction_error: The reason the last compaction or blob garbage collection failed, until
+/// one succeeds
+/// @field wal_sync_error: The reason the last background sync of the WAL failed, until one succeeds
+/// @field orphans: The files cleaned up when the engine was opened
+/// @field wal_recovery: What was replayed and dropped from each log when the engine was opened
+/// @field last_sequence: The sequence number of the last write. Every insertion and deletion takes
+/// the next one when it is appended to the WAL
+struct EngineInner<T, S, SS>
 where
-    T: MemTableRecord + Debug,
+    T: MemTableRecord,
     S: SerializationEngine<LogOperation<T>>,
     SS: SerializationEngine<Option<T>>,
+{
+    manifest: Mutex<Manifest>,
+    memtable: RwLock<Arc<MemTable<T, S>>>,
+    flush_state: Mutex<FlushState<T, S>>,
+    flushed: Condvar,
+    version: RwLock<Arc<Version>>,
+    compacting: Mutex<HashSet<String>>,
+    compaction_error: Mutex<Option<String>>,
+    wal_sync_error: Mutex<Option<String>>,
+    compaction_signal: Arc<CompactionSignal>,
+    strategy: Arc<dyn CompactionStrategy>,
+    orphans: OrphanReport,
+    wal_recovery: Vec<(PathBuf, WalRecoveryReport)>,
+    last_sequence: Arc<AtomicU64>,
+    config: Arc<Config>,
+    memtable_serializer: Arc<S>,
+    serializer: Arc<SS>,
+}
+
+impl<T, S, SS> Clone for Engine<T, S, SS>
+where
+    T: MemTableRecord,
+    S: SerializationEngine<LogOperation<T>>,
+    SS: SerializationEngine<Option<T>>,
+{
+    fn clone(&self) -> Self {
+        Engine {
+            inner: Arc::clone(&self.inner),
+            flusher: Arc::clone(&self.flusher),
+            compactor: Arc::clone(&self.compactor),
+            syncer: Arc::clone(&self.syncer),
+        }
+    }
+}
+
+impl<T, S, SS> Engine<T, S, SS>
+where
+    T: MemTableRecord + Debug + Send + Sync + 'static,
+    S: SerializationEngine<LogOperation<T>> + Send + Sync + 'static,
+    SS: SerializationEngine<Option<T>> + Send + Sync + 'static,
 {
     pub fn new(
-        memtable_serializer: &'a S,
-        storage_serializer: &'a SS,
-        config: &'a Config,
-    ) -> Result<Engine<'a, T, S, SS>, EngineError> {
+        memtable_serializer: S,
+        storage_serializer: SS,
+        config: Config,
+    ) -> Result<Engine<T, S, SS>, EngineError> {
         let db_path = Path::new(&config.db_path);
         if !db_path.exists() {
             return Err(EngineError::DBDoesntExist);
@@ -53,65 +130,156 @@ where
         let _ = create_dir_all(db_path.join(Path::new("indices")));
         let _ = create_dir_all(db_path.join(Path::new("storage")));
         let _ = create_dir_all(db_path.join(Path::new("logs")));
+        let _ = create_dir_all(db_path.join(Path::new("blobs")));
 
-        let memtable = MemTable::<T, S>::open_or_build(
-            &db_path
-                .join(format!("logs/{}.log", T::TYPE_NAME))
-                .display()
-                .to_string(),
-            memtable_serializer,
+        let memtable_serializer = Arc::new(memtable_serializer);
+
+        // Load all sstables
+        let (mut manifest, recovered) = Manifest::open(
+            &config.db_path,
+            T::TYPE_NAME,
+            config.manifest_snapshot_interval,
+        )
+        .map_err(|err| EngineError::ManifestRecovery { err })?;
+
+        // The writes in the logs come after the last one in the sstables
+        let last_sequence = Arc::new(AtomicU64::new(recovered.last_sequence));
+        let segments = wal::recover_segments::<T, S>(
+            &mut manifest,
+            recovered.log_number,
+            &config,
+            &memtable_serializer,
+            &last_sequence,
         )
         .map_err(|err| EngineError::MemtableInitialization { err })?;
 
-        // Load all sstables
-        let metadata_path = Self::get_metadata_path(&config.db_path);
-        let metadata = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .truncate(false)
-            .open(&metadata_path)
-            .unwrap(); // TODO: Fix this unwrap later
-        let sstables = Self::read_sstables(&metadata);
-        let metadata = Arc::new(Mutex::new(metadata));
-        let sstables = Arc::new(RwLock::new(sstables));
+        // Files left behind by a crash
+        let referenced: HashSet<OsString> = recovered
+            .version
+            .tables()
+            .flat_map(|table| [&table.storage_path, &table.index_path])
+            .filter_map(|path| Path::new(path).file_name().map(OsString::from))
+            .chain(
+                recovered
+                    .version
+                    .blob_files()
+                    .filter_map(|blob_file| blob_file.path.file_name().map(OsString::from)),
+            )
+            .chain([OsString::from(manifest.name())])
+            .collect();
+        let orphans = cleanup::remove_orphans(
+            &config.db_path,
+            T::TYPE_NAME,
+            &referenced,
+            config.orphan_files,
+        )
+        .map_err(|err| EngineError::OrphanCleanup { err })?;
+        let (immutable, immutable_segment) = segments.immutable.unzip();
+        let (memtable, segment) = segments.active;
+        let immutable_sequence = immutable
+            .as_ref()
+            .map_or(recovered.last_sequence, |immutable| {
+                immutable.last_sequence()
+            });
+
+        let inner = Arc::new(EngineInner {
+            manifest: Mutex::new(manifest),
+            memtable: RwLock::new(Arc::new(memtable)),
+            flush_state: Mutex::new(FlushState::new(
+                immutable.map(Arc::new),
+                immutable_segment.unwrap_or(segment),
+                segment,
+                immutable_sequence,
+            )),
+            flushed: Condvar::new(),
+            version: RwLock::new(Arc::new(recovered.version)),
+            compacting: Mutex::new(HashSet::new()),
+            compaction_error: Mutex::new(None),
+            wal_sync_error: Mutex::new(None),
+            compaction_signal: Arc::new(CompactionSignal::default()),
+            strategy: config.compaction_strategy(),
+            orphans,
+            wal_recovery: segments.reports,
+            last_sequence,
+            config: Arc::new(config),
+            memtable_serializer,
+            serializer: Arc::new(storage_serializer),
+        });
+        let flusher = Arc::new(FlushWorker::spawn(Arc::clone(&inner)));
+        flusher.schedule();
+        if let Some(manager) = &inner.config.write_buffer_manager {
+            manager.register(Arc::new(FlushHandle::new(&inner, &flusher)));
+        }
+        let compactor =
+            CompactionWorkers::spawn(Arc::clone(&inner), inner.config.compaction_threads);
+        inner.compaction_signal.notify();
+
+        let syncer = WalSyncer::spawn(Arc::clone(&inner));
 
         Ok(Engine {
-            metadata,
-            memtable,
-            sstables,
-            config,
-            serializer: storage_serializer,
-            flush_mutex: Mutex::new(()),
+            inner,
+            flusher,
+            compactor: Arc::new(compactor),
+            syncer: Arc::new(syncer),
         })
     }
 
     pub fn insert(&self, record: T) -> Result<(), EngineError> {
-        self.memtable
-            .insert(record)
+        self.insert_with(record, &WriteOptions::default())
+    }
+
+    pub fn insert_with(&self, record: T, options: &WriteOptions) -> Result<(), EngineError> {
+        self.stall_writes();
+        let memtable = self.inner.memtable.read().unwrap();
+        memtable
+            .insert_with(record, self.inner.logged(options))
             .map_err(|err| EngineError::Insertion { err })?;
-        self.flush_if_ready();
-        Ok(())
+        drop(memtable);
+        self.flush_if_ready()
     }
 
     pub fn delete(&self, key: String) -> Result<(), EngineError> {
-        self.memtable
-            .delete(key)
+        self.delete_with(key, &WriteOptions::default())
+    }
+
+    pub fn delete_with(&self, key: String, options: &WriteOptions) -> Result<(), EngineError> {
+        self.stall_writes();
+        let memtable = self.inner.memtable.read().unwrap();
+        memtable
+            .delete_with(key, self.inner.logged(options))
             .map_err(|err| EngineError::Deletion { err })?;
-        self.flush_if_ready();
-        Ok(())
+        drop(memtable);
+        self.flush_if_ready()
     }
 
     pub fn get(&self, key: String) -> Result<Option<T>, EngineError> {
-        let memlookup = self.memtable.get(&key);
-        if let Some(value) = memlookup {
-            return Ok(value.clone());
+        // The memtables are checked from newest to oldest, and the immutable one is only released
+        // after its table is added, so a key is never missed while moving between them
+        let memtable = Arc::clone(&self.inner.memtable.read().unwrap());
+        if let Some(value) = memtable.get(&key) {
+            return Ok(value);
+        }
+
+        let immutable = self.inner.flush_state.lock().unwrap().immutable.clone();
+        if let Some(immutable) = immutable
+            && let Some(value) = immutable.get(&key)
+        {
+            return Ok(value);
         }
 
         // Lookup in SSTables
-        let tables = self.sstables.read().unwrap();
-        for table in tables.iter().rev() {
-            let lookup = table.get(&key, self.config, self.serializer).unwrap(); // TODO: Handle These errors
+        let version = self.inner.version();
+        for table in version.candidates(&key) {
+            let lookup = table
+                .get(&key, &self.inner.config, self.inner.serializer.as_ref())
+                .map_err(|err| match err {
+                    SSTableError::DBFileDeleted { file } => EngineError::DBFileDeleted { file },
+                    SSTableError::DBFileCorrupted { file } => EngineError::DBCorrupted { file },
+                    // A value that can't be decoded
+                    _ => EngineError::DBCorrupted {
+                        file: table.storage_path.clone(),
+                    },
+                })?;
             if let Some(value) = lookup {
                 return Ok(value);
             }
@@ -121,171 +289,1419 @@ where
         Ok(None)
     }
 
-    // TODO: Rewrite this so that it would use size-tiered compaction instead
-    pub fn compact(&self) {
-        let mut tables = self.sstables.write().unwrap();
-        let mut tiers: HashMap<usize, Vec<usize>> = HashMap::new();
+    /// The current set of sstables. Tables merged by compactions after this call don't change it.
+    pub fn version(&self) -> Arc<Version> {
+        self.inner.version()
+    }
 
-        for (i, table) in tables.iter().enumerate() {
-            let size = table.size;
-            let tier = (size as f64 / self.config.compaction_tier_size as f64)
-                .log(self.config.compaction_size_multiplier as f64)
-                .floor() as usize;
-            // println!("Size: {}, Tier: {}", size, tier);
+    /// The files left behind by a crash, that were cleaned up when the engine was opened
+    pub fn orphan_report(&self) -> &OrphanReport {
+        &self.inner.orphans
+    }
+
+    /// What was replayed and dropped from each log when the engine was opened
+    pub fn wal_recovery_reports(&self) -> &[(PathBuf, WalRecoveryReport)] {
+        &self.inner.wal_recovery
+    }
 
-            let entry = tiers.entry(tier).or_default();
-            entry.push(i);
+    /// The sequence number of the last insertion or deletion
+    pub fn last_sequence(&self) -> u64 {
+        self.inner.last_sequence.load(Ordering::SeqCst)
+    }
+
+    /// Runs a single compaction on the calling thread, if there is one to run. Compactions
+    /// also run on their own on the background threads after every flush.
+    pub fn compact(&self) -> Result<(), EngineError> {
+        self.inner
+            .compact_once()
+            .map(|_| ())
+            .map_err(|err| EngineError::Compaction { err })
+    }
+
+    /// Why the last compaction failed, when the one after it didn't succeed yet. A failed
+    /// compaction leaves the tables as they were, and is tried again after the next flush.
+    pub fn compaction_error(&self) -> Option<EngineError> {
+        let message = self.inner.compaction_error.lock().unwrap().clone()?;
+        Some(EngineError::BackgroundCompaction { message })
+    }
+
+    /// Swaps the memtable out once it's full and hands it to the background flush thread. Stalls
+    /// when the previous memtable is still being flushed. With a write buffer manager, the largest
+    /// memtable of its engines is flushed too when they are over its budget.
+    pub fn flush_if_ready(&self) -> Result<(), EngineError> {
+        if self.inner.rotate_memtable(false)? {
+            self.flusher.schedule();
+        }
+        if let Some(manager) = &self.inner.config.write_buffer_manager {
+            manager.enforce();
+        }
+        Ok(())
+    }
+
+    /// Waits while the memtables of the engines sharing the write buffer manager are far over its
+    /// budget
+    fn stall_writes(&self) {
+        if let Some(manager) = &self.inner.config.write_buffer_manager {
+            manager.stall();
         }
+    }
+
+    /// Flushes whatever is in the memtable, and waits until it is written to an SSTable.
+    pub fn flush(&self) -> Result<(), EngineError> {
+        if self.inner.rotate_memtable(true)? {
+            self.flusher.schedule();
+        }
+        self.inner.wait_for_flush()
+    }
+
+    /// Whether some writes were made without the WAL and aren't flushed yet, so they would be lost
+    /// if the process stopped
+    pub fn has_unlogged_data(&self) -> bool {
+        let immutable = self.inner.flush_state.lock().unwrap().immutable.clone();
+        self.inner.memtable.read().unwrap().has_unlogged_data()
+            || immutable.is_some_and(|immutable| immutable.has_unlogged_data())
+    }
+
+    /// Why the last background sync of the WAL failed, with `WalSyncMode::Interval`, when the one
+    /// after it didn't succeed yet. Until then, the writes since the last successful sync may be
+    /// lost if the machine stops.
+    pub fn wal_sync_error(&self) -> Option<EngineError> {
+        let message = self.inner.wal_sync_error.lock().unwrap().clone()?;
+        Some(EngineError::BackgroundWalSync { message })
+    }
+
+    /// Makes every write made before the call durable, including the ones made without the WAL:
+    /// the memtables are flushed, and the WAL of the memtable replacing them is synced.
+    pub fn flush_and_sync(&self) -> Result<(), EngineError> {
+        self.flush()?;
+        let memtable = Arc::clone(&self.inner.memtable.read().unwrap());
+        memtable
+            .log
+            .sync()
+            .map_err(|err| EngineError::WalSync { err })
+    }
+}
+
+impl<T, S, SS> EngineInner<T, S, SS>
+where
+    T: MemTableRecord + Debug,
+    S: SerializationEngine<LogOperation<T>>,
+    SS: SerializationEngine<Option<T>>,
+{
+    fn version(&self) -> Arc<Version> {
+        Arc::clone(&self.version.read().unwrap())
+    }
+
+    fn logged(&self, options: &WriteOptions) -> bool {
+        !(options.disable_wal || self.config.disable_wal)
+    }
+
+    fn get_next_index_storage_logs_name(&self) -> IOResult<(String, String)> {
+        let number = self.manifest.lock().unwrap().new_file_number()?;
+        let [storage_path, index_path] = ["storage", "indices"].map(|dir| {
+            Path::new(&self.config.db_path)
+                .join(format!("{}/{}-{}.log", dir, T::TYPE_NAME, number))
+                .display()
+                .to_string()
+        });
+        Ok((index_path, storage_path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        fs,
+        path::Path,
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        compaction::{CompactionJob, CompactionOutput, CompactionStrategy},
+        config::{
+            CompactionStyle, Compression, Config, OrphanFileAction, WalSyncMode, WriteOptions,
+        },
+        engine::{Change, Engine, EngineError, Version, wal},
+        memtable::{LogOperation, MemTableRecord},
+        serialization::BinarySerializationEngine,
+        sstable::{SSTable, SSTableBuilder, footer::FOOTER_SIZE},
+        write_buffer::WriteBufferManager,
+    };
+    use bincode::{Decode, Encode};
+    use tempfile::TempDir;
 
-        let Some((_, indices)) = tiers
-            .into_iter()
-            .find(|(_, indices)| indices.len() > self.config.compaction_threshold as usize)
-        else {
-            return;
+    #[derive(Encode, Decode, Clone, Debug, PartialEq)]
+    struct Photo {
+        id: String,
+        url: String,
+    }
+
+    impl MemTableRecord for Photo {
+        const TYPE_NAME: &'static str = "Photo";
+        fn get_key(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    #[derive(Debug)]
+    struct MergeEverything;
+
+    impl CompactionStrategy for MergeEverything {
+        fn pick(
+            &self,
+            version: &Version,
+            compacting: &HashSet<String>,
+            _: &Config,
+        ) -> Vec<CompactionJob> {
+            let tables = version.level(0);
+            if tables.len() < 2
+                || tables
+                    .iter()
+                    .any(|table| compacting.contains(&table.storage_path))
+            {
+                return vec![];
+            }
+
+            vec![CompactionJob {
+                inputs: tables.to_vec(),
+                output: Some(CompactionOutput {
+                    level: 0,
+                    target_file_size: None,
+                    drop_tombstones: true,
+                }),
+            }]
+        }
+    }
+
+    type PhotoEngine = Engine<Photo, BinarySerializationEngine, BinarySerializationEngine>;
+
+    fn assert_shareable<E: Send + Sync + Clone + 'static>() {}
+
+    fn test_config(temp_dir: &TempDir) -> Config {
+        Config {
+            db_path: temp_dir.path().to_str().unwrap().to_string(),
+            index_key_string_size: 24,
+            index_offset_size: 8,
+            initial_index_file_threshold: 1024,
+            compaction_threshold: 3,
+            compaction_tier_size: 2097152,
+            compaction_size_multiplier: 10,
+            ..Default::default()
+        }
+    }
+
+    fn open(temp_dir: &TempDir) -> PhotoEngine {
+        open_with(test_config(temp_dir))
+    }
+
+    fn open_with(config: Config) -> PhotoEngine {
+        PhotoEngine::new(BinarySerializationEngine, BinarySerializationEngine, config)
+            .expect("Engine creation failed")
+    }
+
+    fn photo(i: usize) -> Photo {
+        Photo {
+            id: format!("id_{}", i),
+            url: format!("url_{}", i),
+        }
+    }
+
+    #[test]
+    fn engine_is_shareable_across_threads() {
+        assert_shareable::<PhotoEngine>();
+
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let engine = open(&temp_dir);
+
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let engine = engine.clone();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        engine
+                            .insert(Photo {
+                                id: format!("id_{}_{}", t, i),
+                                url: format!("url_{}_{}", t, i),
+                            })
+                            .expect("Insert failed");
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        for t in 0..4 {
+            for i in 0..100 {
+                let photo = engine.get(format!("id_{}_{}", t, i)).expect("Get failed");
+                assert_eq!(photo.unwrap().url, format!("url_{}_{}", t, i));
+            }
+        }
+    }
+
+    #[test]
+    fn flush_writes_memtable_to_sstable() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        {
+            let engine = open(&temp_dir);
+            for i in 0..10 {
+                engine.insert(photo(i)).expect("Insert failed");
+            }
+            engine.flush().expect("Flush failed");
+
+            assert!(engine.inner.memtable.read().unwrap().is_empty());
+            assert_eq!(engine.inner.version().len(), 1);
+        }
+
+        let engine = open(&temp_dir);
+        let version = engine.version();
+        let table = &version.level(0)[0];
+        assert!(0 < table.min_timestamp && table.min_timestamp <= table.max_timestamp);
+        for i in 0..10 {
+            assert_eq!(engine.get(format!("id_{}", i)).unwrap(), Some(photo(i)));
+        }
+    }
+
+    #[test]
+    fn sequence_numbers_survive_reopen() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        {
+            let engine = open(&temp_dir);
+            for i in 0..10 {
+                engine.insert(photo(i)).expect("Insert failed");
+            }
+            engine.flush().expect("Flush failed");
+            engine.delete("id_3".to_string()).expect("Deletion failed");
+            engine.insert(photo(3)).expect("Insert failed");
+            assert_eq!(engine.last_sequence(), 12);
+        }
+
+        // Ten writes in the sstables, and two more in the log
+        let engine = open(&temp_dir);
+        assert_eq!(engine.last_sequence(), 12);
+        engine.insert(photo(10)).expect("Insert failed");
+        assert_eq!(engine.last_sequence(), 13);
+    }
+
+    #[test]
+    fn orphan_files_are_cleaned_up_on_open() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        {
+            let engine = open(&temp_dir);
+            for i in 0..10 {
+                engine.insert(photo(i)).expect("Insert failed");
+            }
+            engine.flush().expect("Flush failed");
+        }
+
+        let db_path = temp_dir.path();
+        let orphans = [
+            "storage/Photo-900.log",
+            "indices/Photo-900.log",
+            "Photo-compaction.tmp",
+            "metadata/Photo-MANIFEST-0",
+            "logs/Photo-900.log.tmp",
+        ];
+        let others = ["storage/Other-900.log", "Other-compaction.tmp", "notes.txt"];
+        for file in orphans.iter().chain(others.iter()) {
+            fs::write(db_path.join(file), "partial").unwrap();
+        }
+
+        let engine = open_with(Config {
+            orphan_files: OrphanFileAction::Quarantine,
+            ..test_config(&temp_dir)
+        });
+        let mut quarantined = engine.orphan_report().quarantined.clone();
+        quarantined.sort();
+        let mut expected: Vec<_> = orphans.iter().map(|file| db_path.join(file)).collect();
+        expected.sort();
+        assert_eq!(quarantined, expected);
+        assert!(engine.orphan_report().deleted.is_empty());
+        for file in orphans {
+            assert!(!db_path.join(file).exists());
+            assert!(db_path.join("lost").join(file).exists());
+        }
+        for file in others {
+            assert!(db_path.join(file).exists());
+        }
+        for i in 0..10 {
+            assert_eq!(engine.get(format!("id_{}", i)).unwrap(), Some(photo(i)));
+        }
+        drop(engine);
+
+        // An orphan with the name of a quarantined one doesn't replace it
+        fs::write(db_path.join(orphans[0]), "later").unwrap();
+        let engine = open_with(Config {
+            orphan_files: OrphanFileAction::Quarantine,
+            ..test_config(&temp_dir)
+        });
+        assert_eq!(
+            engine.orphan_report().quarantined,
+            vec![db_path.join(orphans[0])]
+        );
+        let lost = db_path.join("lost").join(orphans[0]);
+        assert_eq!(fs::read_to_string(&lost).unwrap(), "partial");
+        let numbered = format!("{}.1", lost.display());
+        assert_eq!(fs::read_to_string(numbered).unwrap(), "later");
+        drop(engine);
+
+        fs::write(db_path.join(orphans[0]), "partial").unwrap();
+        let engine = open(&temp_dir);
+        assert_eq!(
+            engine.orphan_report().deleted,
+            vec![db_path.join(orphans[0])]
+        );
+        assert!(!db_path.join(orphans[0]).exists());
+        assert_eq!(engine.get("id_3".to_string()).unwrap(), Some(photo(3)));
+    }
+
+    #[test]
+    fn repair_rebuilds_the_manifest_from_table_files() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        // A block for every record, so that cutting a table loses only the records after the cut
+        let config = Config {
+            compaction_threads: 0,
+            block_size: 1,
+            wal_archive: true,
+            ..test_config(&temp_dir)
+        };
+        let (first, second, last_sequence) = {
+            let engine = open_with(config.clone());
+            for batch in 0..2 {
+                for i in 0..10 {
+                    engine.insert(photo(batch * 10 + i)).expect("Insert failed");
+                }
+                engine.delete("id_3".to_string()).expect("Deletion failed");
+                engine.flush().expect("Flush failed");
+            }
+            let version = engine.version();
+            let tables = version.level(0);
+            (
+                tables[0].storage_path.clone(),
+                tables[1].storage_path.clone(),
+                engine.last_sequence(),
+            )
         };
 
-        let (new_index_path, new_storage_path) = self.get_next_index_storage_logs_name();
-        let target_tables: Vec<&SSTable> = indices.iter().map(|idx| &tables[*idx]).collect();
-        let compacted_table = compact(
-            target_tables,
-            self.serializer,
-            self.config,
-            new_index_path,
-            new_storage_path,
-        )
-        .unwrap(); // TODO: Handle these errors
-
-        // Write the table
-        let last_idx = indices.last().unwrap();
-        tables[*last_idx] = compacted_table;
-        for other_idx in indices.iter().rev() {
-            if *other_idx == *last_idx {
-                continue;
+        // The manifest is gone, the second table lost its second half and a table is garbage
+        let db_path = temp_dir.path();
+        fs::remove_dir_all(db_path.join("metadata")).unwrap();
+        let size = fs::metadata(&second).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&second)
+            .unwrap()
+            .set_len(size / 2)
+            .unwrap();
+        fs::write(db_path.join("storage/Photo-100.log"), "garbage").unwrap();
+        fs::write(db_path.join("indices/Photo-100.log"), "garbage").unwrap();
+
+        let report =
+            PhotoEngine::repair(&BinarySerializationEngine, &config).expect("Repair failed");
+        assert_eq!(report.recovered, vec![first, second.clone()]);
+        assert_eq!(report.truncated, vec![second]);
+        assert_eq!(report.discarded.len(), 1);
+
+        let engine = open_with(config);
+        assert_eq!(engine.version().len(), 2);
+        for i in 0..10 {
+            let expected = if i == 3 { None } else { Some(photo(i)) };
+            assert_eq!(engine.get(format!("id_{}", i)).unwrap(), expected);
+        }
+        // Only the first entries of the second table survive
+        assert_eq!(engine.get("id_10".to_string()).unwrap(), Some(photo(10)));
+        assert_eq!(engine.get("id_19".to_string()).unwrap(), None);
+
+        // The sequence numbers go on from the ones in the archived segments
+        assert_eq!(engine.last_sequence(), last_sequence);
+        engine.insert(photo(19)).expect("Insert failed");
+        assert_eq!(engine.last_sequence(), last_sequence + 1);
+        assert_eq!(engine.get("id_19".to_string()).unwrap(), Some(photo(19)));
+    }
+
+    #[test]
+    fn repair_orders_tables_by_their_last_write() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let external = TempDir::new().expect("Failed to create temp dir");
+        let config = Config {
+            compaction_threads: 0,
+            ..test_config(&temp_dir)
+        };
+        let version = |url: &str| Photo {
+            id: "id_1".to_string(),
+            url: url.to_string(),
+        };
+        let files = [external_table(&external, "a", &[(2, Some(photo(2)))])];
+        let tables: Vec<_> = {
+            let engine = open_with(config.clone());
+            for url in ["first", "second"] {
+                engine.insert(version(url)).expect("Insert failed");
+                engine.flush().expect("Flush failed");
             }
-            tables.remove(*other_idx);
+            engine.ingest_files(&files).expect("Ingestion failed");
+            engine
+                .version()
+                .level(0)
+                .iter()
+                .map(|table| (table.storage_path.clone(), table.index_path.clone()))
+                .collect()
+        };
+
+        // The flushed tables swap their file numbers. The ingested table holds the same last
+        // write as the second one, and stays after it
+        let db_path = temp_dir.path();
+        fs::remove_dir_all(db_path.join("metadata")).unwrap();
+        for (path, other) in [(&tables[0].0, &tables[1].0), (&tables[0].1, &tables[1].1)] {
+            let swap = format!("{}.swap", path);
+            fs::rename(path, &swap).unwrap();
+            fs::rename(other, path).unwrap();
+            fs::rename(&swap, other).unwrap();
         }
 
-        // Write the metadata
-        self.create_metadata(tables.iter()).unwrap();
+        let report =
+            PhotoEngine::repair(&BinarySerializationEngine, &config).expect("Repair failed");
+        assert_eq!(
+            report.recovered,
+            vec![
+                tables[1].0.clone(),
+                tables[0].0.clone(),
+                tables[2].0.clone()
+            ]
+        );
+        let engine = open_with(config);
+        assert_eq!(
+            engine.get("id_1".to_string()).unwrap(),
+            Some(version("second"))
+        );
+        assert_eq!(engine.get("id_2".to_string()).unwrap(), Some(photo(2)));
     }
 
-    pub fn flush_if_ready(&self) {
-        let Config {
-            index_offset_size,
-            index_key_string_size,
-            initial_index_file_threshold: memtable_threshold,
-            ..
-        } = self.config;
+    #[test]
+    fn repaired_tables_keep_their_write_times() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let config = Config {
+            compaction_threads: 0,
+            compaction_style: CompactionStyle::TimeWindow,
+            time_window_retention_ms: Some(86400000),
+            ..test_config(&temp_dir)
+        };
+        let tables: Vec<_> = {
+            let engine = open_with(config.clone());
+            for i in 0..2 {
+                engine.insert(photo(i)).expect("Insert failed");
+                engine.flush().expect("Flush failed");
+            }
+            engine
+                .version()
+                .level(0)
+                .iter()
+                .map(|table| {
+                    let times = (table.min_timestamp, table.max_timestamp);
+                    (table.storage_path.clone(), times)
+                })
+                .collect()
+        };
+
+        // The second table loses its footer, like the tables written before them
+        let db_path = temp_dir.path();
+        fs::remove_dir_all(db_path.join("metadata")).unwrap();
+        let size = fs::metadata(&tables[1].0).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&tables[1].0)
+            .unwrap()
+            .set_len(size - FOOTER_SIZE as u64)
+            .unwrap();
+        PhotoEngine::repair(&BinarySerializationEngine, &config).expect("Repair failed");
 
-        let pair_size = index_key_string_size + index_offset_size;
+        // Neither table is past the retention
+        let engine = open_with(config);
+        engine.compact().expect("Compaction failed");
+        let version = engine.version();
+        assert_eq!(version.len(), 2);
+        let recovered = version.level(0);
+        assert_eq!(
+            (recovered[0].min_timestamp, recovered[0].max_timestamp),
+            tables[0].1
+        );
+        assert!(recovered[1].min_timestamp >= tables[1].1.1);
+        assert_eq!(engine.get("id_0".to_string()).unwrap(), Some(photo(0)));
+        assert_eq!(engine.get("id_1".to_string()).unwrap(), Some(photo(1)));
+    }
 
-        let _guard = self.flush_mutex.lock();
+    fn segments(dir: &Path) -> Vec<u64> {
+        wal::segment_numbers(dir, Photo::TYPE_NAME).unwrap()
+    }
 
-        if pair_size * self.memtable.len() < *memtable_threshold {
-            return;
+    #[test]
+    fn unflushed_immutable_memtable_is_recovered() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let logs_dir = temp_dir.path().join("logs");
+        {
+            let engine = open(&temp_dir);
+            for i in 0..10 {
+                engine.insert(photo(i)).expect("Insert failed");
+            }
         }
 
-        println!("Flushing Memtable begins");
-        let (index_path, storage_path) = self.get_next_index_storage_logs_name();
+        // Simulate a shutdown right after the memtable was swapped out for a newer segment
+        let unflushed = segments(&logs_dir);
+        assert_eq!(unflushed.len(), 1);
+        fs::write(
+            logs_dir.join(format!("Photo-{}.log", unflushed[0] + 1000)),
+            "",
+        )
+        .unwrap();
+
+        let engine = open(&temp_dir);
+        assert!(engine.inner.flush_state.lock().unwrap().immutable.is_some());
+        engine.flush().expect("Flush failed");
+
+        assert_eq!(segments(&logs_dir), vec![unflushed[0] + 1000]);
+        assert_eq!(engine.inner.version().len(), 1);
+        for i in 0..10 {
+            assert_eq!(engine.get(format!("id_{}", i)).unwrap(), Some(photo(i)));
+        }
+    }
 
-        let table = SSTable::create::<T, S, SS>(
-            &storage_path,
-            &index_path,
-            self.memtable.tree.read().unwrap(),
-            self.serializer,
-            self.config,
+    #[test]
+    fn single_log_is_migrated_to_a_segment() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let logs_dir = temp_dir.path().join("logs");
+        {
+            let engine = open(&temp_dir);
+            for i in 0..10 {
+                engine.insert(photo(i)).expect("Insert failed");
+            }
+        }
+        let segment = segments(&logs_dir)[0];
+        fs::rename(
+            logs_dir.join(format!("Photo-{}.log", segment)),
+            logs_dir.join("Photo.log"),
         )
         .unwrap();
 
-        self.add_sstable_to_metadata(&table);
-        self.memtable.clear().unwrap();
+        let engine = open(&temp_dir);
+        assert!(!logs_dir.join("Photo.log").exists());
+        assert_eq!(segments(&logs_dir).len(), 1);
+        assert!(segments(&logs_dir)[0] > segment);
+        assert_eq!(engine.get("id_9".to_string()).unwrap(), Some(photo(9)));
+    }
+
+    #[test]
+    fn flushed_segments_are_archived_within_limits() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let logs_dir = temp_dir.path().join("logs");
+        let archive_dir = logs_dir.join("archive");
+        let mut config = Config {
+            wal_archive: true,
+            ..test_config(&temp_dir)
+        };
+
+        let flush_batch = |engine: &PhotoEngine, batch: usize| {
+            for i in batch * 10 + 10..batch * 10 + 20 {
+                engine.insert(photo(i)).expect("Insert failed");
+            }
+            engine.flush().expect("Flush failed");
+        };
+
+        let engine = open_with(config.clone());
+        flush_batch(&engine, 0);
+        let archived = segments(&archive_dir);
+        assert_eq!(archived.len(), 1);
+        assert!(segments(&logs_dir)[0] > archived[0]);
+        let segment_size = fs::metadata(archive_dir.join(format!("Photo-{}.log", archived[0])))
+            .unwrap()
+            .len();
+        drop(engine);
+
+        // Every batch has a segment of the same size, and only the last two fit
+        config.wal_archive_max_bytes = Some(segment_size * 2);
+        let engine = open_with(config);
+        for batch in 1..4 {
+            flush_batch(&engine, batch);
+        }
+        let archived = segments(&archive_dir);
+        assert_eq!(archived.len(), 2);
+        assert_eq!(segments(&logs_dir).len(), 1);
+        assert!(segments(&logs_dir)[0] > archived[1]);
+        assert_eq!(engine.get("id_10".to_string()).unwrap(), Some(photo(10)));
+    }
+
+    #[test]
+    fn segments_that_fail_to_be_retired_are_retried() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let logs_dir = temp_dir.path().join("logs");
+        let archive_dir = logs_dir.join("archive");
+        let config = Config {
+            wal_archive: true,
+            ..test_config(&temp_dir)
+        };
+        let engine = open_with(config);
 
-        let mut tables = self.sstables.write().unwrap();
-        tables.push(table);
+        // A file where the archive should be makes archiving fail, but not the flush
+        fs::write(&archive_dir, "").unwrap();
+        engine.insert(photo(1)).expect("Insert failed");
+        engine.flush().expect("Flush failed");
+        assert_eq!(segments(&logs_dir).len(), 2);
+        assert!(engine.inner.flush_state.lock().unwrap().immutable.is_none());
 
-        println!("Flushing Memtable ends");
+        fs::remove_file(&archive_dir).unwrap();
+        engine.insert(photo(2)).expect("Insert failed");
+        engine.flush().expect("Flush failed");
+        assert_eq!(segments(&logs_dir).len(), 1);
+        assert_eq!(segments(&archive_dir).len(), 2);
+        assert_eq!(engine.get("id_1".to_string()).unwrap(), Some(photo(1)));
     }
 
-    fn read_sstables(metadata_file: &File) -> Vec<SSTable> {
-        let reader = BufReader::new(metadata_file);
-        reader
-            .lines()
-            .map(|line| {
-                let line = line.unwrap();
+    #[test]
+    fn compaction_runs_in_the_background_after_flushes() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let engine = open(&temp_dir);
 
-                let values: Vec<&str> = line.split(" ").collect();
+        for batch in 0..5 {
+            for i in 0..10 {
+                engine.insert(photo(batch * 10 + i)).expect("Insert failed");
+            }
+            engine.flush().expect("Flush failed");
+        }
 
-                if values.len() != 6 {
-                    panic!("Invalid metadata");
-                }
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while engine.inner.version().len() > 3 {
+            assert!(Instant::now() < deadline, "Compaction never ran");
+            thread::sleep(Duration::from_millis(10));
+        }
 
-                SSTable {
-                    storage_path: values[0].to_string(),
-                    index_path: values[1].to_string(),
-                    min: values[2].to_string(),
-                    max: values[3].to_string(),
-                    count: values[4].parse().unwrap(),
-                    size: values[5].parse().unwrap(),
+        for i in 0..50 {
+            assert_eq!(engine.get(format!("id_{}", i)).unwrap(), Some(photo(i)));
+        }
+    }
+
+    #[test]
+    fn leveled_compaction_keeps_levels_sorted_and_sized() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let config = Config {
+            compaction_style: CompactionStyle::Leveled,
+            compaction_threads: 0,
+            compaction_threshold: 1,
+            compaction_tier_size: 1000,
+            compaction_size_multiplier: 2,
+            target_file_size: 400,
+            max_levels: 4,
+            compression: Compression::None,
+            ..test_config(&temp_dir)
+        };
+
+        {
+            let engine = open_with(config.clone());
+            for batch in 0..8 {
+                for i in batch * 10..batch * 10 + 25 {
+                    engine.insert(photo(i)).expect("Insert failed");
                 }
+                engine
+                    .delete(format!("id_{}", batch * 3))
+                    .expect("Deletion failed");
+                engine.flush().expect("Flush failed");
+            }
+            for _ in 0..50 {
+                engine.compact().expect("Compaction failed");
+            }
+        }
+
+        let engine = open_with(config);
+        let version = engine.version();
+        assert!(version.level(0).len() <= 1);
+        assert!(version.levels().len() > 2, "Expected tables below level 1");
+        for (level, tables) in version.levels().iter().enumerate().skip(1) {
+            for pair in tables.windows(2) {
+                assert!(pair[0].max < pair[1].min, "Level {} overlaps", level);
+            }
+        }
+        assert!(
+            version
+                .level(1)
+                .iter()
+                .map(|table| table.size)
+                .sum::<usize>()
+                <= 1000
+        );
+        assert!(
+            version
+                .level(2)
+                .iter()
+                .map(|table| table.size)
+                .sum::<usize>()
+                <= 2000
+        );
+
+        for i in 0..95 {
+            let expected = if i % 3 == 0 && i / 3 < 8 {
+                None
+            } else {
+                Some(photo(i))
+            };
+            assert_eq!(
+                engine.get(format!("id_{}", i)).unwrap(),
+                expected,
+                "id_{}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn custom_compaction_strategy_from_config() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let engine = open_with(Config {
+            compaction_threads: 0,
+            custom_compaction_strategy: Some(Arc::new(MergeEverything)),
+            ..test_config(&temp_dir)
+        });
+
+        for batch in 0..3 {
+            for i in 0..10 {
+                engine.insert(photo(batch * 5 + i)).expect("Insert failed");
+            }
+            engine
+                .delete(format!("id_{}", batch))
+                .expect("Deletion failed");
+            engine.flush().expect("Flush failed");
+        }
+        assert_eq!(engine.version().len(), 3);
+
+        engine.compact().expect("Compaction failed");
+
+        let version = engine.version();
+        assert_eq!(version.len(), 1);
+        assert_eq!(version.level(0)[0].count, 20 - 3);
+        for i in 0..20 {
+            let expected = if i < 3 { None } else { Some(photo(i)) };
+            assert_eq!(engine.get(format!("id_{}", i)).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn lost_or_corrupted_tables_fail_reads() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let engine = open(&temp_dir);
+        for i in 0..2 {
+            engine.insert(photo(i)).expect("Insert failed");
+            engine.flush().expect("Flush failed");
+        }
+        let version = engine.version();
+        let tables = version.level(0);
+
+        fs::write(&tables[0].storage_path, "garbage").unwrap();
+        assert!(matches!(
+            engine.get("id_0".to_string()),
+            Err(EngineError::DBCorrupted { file }) if file == tables[0].storage_path
+        ));
+        fs::remove_file(&tables[1].index_path).unwrap();
+        assert!(matches!(
+            engine.get("id_1".to_string()),
+            Err(EngineError::DBFileDeleted { file }) if file == tables[1].index_path
+        ));
+    }
+
+    #[test]
+    fn failed_compactions_are_reported() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let engine = open_with(Config {
+            compaction_threads: 0,
+            custom_compaction_strategy: Some(Arc::new(MergeEverything)),
+            ..test_config(&temp_dir)
+        });
+        for i in 0..2 {
+            engine.insert(photo(i)).expect("Insert failed");
+            engine.flush().expect("Flush failed");
+        }
+
+        // An input that can't be read fails the compaction, which leaves the tables as they were
+        let storage_path = engine.version().level(0)[1].storage_path.clone();
+        let moved = format!("{}.moved", storage_path);
+        fs::rename(&storage_path, &moved).unwrap();
+        assert!(matches!(
+            engine.compact(),
+            Err(EngineError::Compaction { .. })
+        ));
+        assert!(matches!(
+            engine.compaction_error(),
+            Some(EngineError::BackgroundCompaction { .. })
+        ));
+        assert_eq!(engine.version().len(), 2);
+
+        fs::rename(&moved, &storage_path).unwrap();
+        engine.compact().expect("Compaction failed");
+        assert!(engine.compaction_error().is_none());
+        assert_eq!(engine.version().len(), 1);
+        assert_eq!(engine.get("id_1".to_string()).unwrap(), Some(photo(1)));
+    }
+
+    #[test]
+    fn changes_are_streamed_across_restarts() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let config = Config {
+            wal_archive: true,
+            ..test_config(&temp_dir)
+        };
+        {
+            let engine = open_with(config.clone());
+            for i in 0..5 {
+                engine.insert(photo(i)).expect("Insert failed");
+            }
+            engine.flush().expect("Flush failed");
+            engine.delete("id_2".to_string()).expect("Deletion failed");
+        }
+
+        let engine = open_with(config);
+        engine.insert(photo(5)).expect("Insert failed");
+        let changes: Vec<_> = engine
+            .changes_since(3)
+            .expect("History is there")
+            .map(|change| change.unwrap())
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                Change {
+                    sequence: 4,
+                    operation: LogOperation::Insert { record: photo(3) }
+                },
+                Change {
+                    sequence: 5,
+                    operation: LogOperation::Insert { record: photo(4) }
+                },
+                Change {
+                    sequence: 6,
+                    operation: LogOperation::Delete {
+                        key: "id_2".to_string()
+                    }
+                },
+                Change {
+                    sequence: 7,
+                    operation: LogOperation::Insert { record: photo(5) }
+                },
+            ]
+        );
+        assert_eq!(engine.changes_since(7).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn unlogged_writes_leave_no_gap_in_the_changes() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let engine = open(&temp_dir);
+        let unlogged = WriteOptions { disable_wal: true };
+        engine.insert(photo(0)).expect("Insert failed");
+        engine
+            .insert_with(photo(1), &unlogged)
+            .expect("Insert failed");
+        engine.insert(photo(2)).expect("Insert failed");
+        engine
+            .delete_with("id_0".to_string(), &unlogged)
+            .expect("Deletion failed");
+        engine.insert(photo(3)).expect("Insert failed");
+
+        let sequences: Vec<_> = engine
+            .changes_since(0)
+            .expect("History is there")
+            .map(|change| change.unwrap().sequence)
+            .collect();
+        assert_eq!(sequences, vec![1, 2, 3]);
+        assert_eq!(engine.last_sequence(), 3);
+        let resumed: Vec<_> = engine
+            .changes_since(2)
+            .expect("History is there")
+            .map(|change| change.unwrap().operation)
+            .collect();
+        assert_eq!(resumed, vec![LogOperation::Insert { record: photo(3) }]);
+
+        // The unlogged writes still replace the logged ones before them
+        assert_eq!(engine.get("id_0".to_string()).unwrap(), None);
+        assert_eq!(engine.get("id_1".to_string()).unwrap(), Some(photo(1)));
+    }
+
+    #[test]
+    fn discarded_changes_are_reported() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let engine = open(&temp_dir);
+        for i in 0..5 {
+            engine.insert(photo(i)).expect("Insert failed");
+        }
+        engine.flush().expect("Flush failed");
+        assert!(matches!(
+            engine.changes_since(0),
+            Err(EngineError::ChangesDiscarded {
+                requested: 0,
+                oldest: 6
             })
-            .collect()
-    }
-
-    fn add_sstable_to_metadata(&self, table: &SSTable) {
-        let mut metadata = self.metadata.lock().unwrap();
-        metadata.seek(SeekFrom::End(0)).unwrap();
-        metadata
-            .write_all(
-                format!(
-                    "{} {} {} {} {} {}\n",
-                    table.storage_path.clone(),
-                    table.index_path.clone(),
-                    table.min.clone(),
-                    table.max.clone(),
-                    table.count,
-                    table.size,
-                )
-                .as_bytes(),
-            )
-            .unwrap();
+        ));
+        assert_eq!(engine.changes_since(5).unwrap().count(), 0);
+
+        engine.insert(photo(5)).expect("Insert failed");
+        assert!(matches!(
+            engine.changes_since(4),
+            Err(EngineError::ChangesDiscarded { oldest: 6, .. })
+        ));
+        let changes: Vec<_> = engine.changes_since(5).unwrap().collect();
+        assert_eq!(changes.len(), 1);
     }
 
-    fn get_next_index_storage_logs_name(&self) -> (String, String) {
-        let count = fs::read_dir(Path::new(&self.config.db_path).join("storage"))
-            .unwrap()
-            .count();
-        let [storage_path, index_path] = ["storage", "indices"].map(|dir| {
-            Path::new(&self.config.db_path)
-                .join(format!("{}/{}-{}.log", dir, T::TYPE_NAME, count))
+    #[test]
+    fn unlogged_writes_are_durable_after_flush_and_sync() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let logs_dir = temp_dir.path().join("logs");
+        let unlogged = WriteOptions { disable_wal: true };
+        {
+            let engine = open(&temp_dir);
+            engine.insert(photo(0)).expect("Insert failed");
+            for i in 1..10 {
+                engine
+                    .insert_with(photo(i), &unlogged)
+                    .expect("Insert failed");
+            }
+            engine
+                .delete_with("id_0".to_string(), &unlogged)
+                .expect("Deletion failed");
+            assert!(engine.has_unlogged_data());
+            assert_eq!(engine.last_sequence(), 1);
+            assert_eq!(engine.changes_since(0).unwrap().count(), 1);
+
+            engine.flush_and_sync().expect("Flush failed");
+            assert!(!engine.has_unlogged_data());
+
+            // Without a flush the unlogged writes don't survive a restart
+            engine
+                .insert_with(photo(10), &unlogged)
+                .expect("Insert failed");
+        }
+
+        let engine = open(&temp_dir);
+        assert_eq!(engine.get("id_0".to_string()).unwrap(), None);
+        assert_eq!(engine.get("id_9".to_string()).unwrap(), Some(photo(9)));
+        assert_eq!(engine.get("id_10".to_string()).unwrap(), None);
+        drop(engine);
+
+        let engine = open_with(Config {
+            disable_wal: true,
+            ..test_config(&temp_dir)
+        });
+        engine.insert(photo(11)).expect("Insert failed");
+        assert!(engine.has_unlogged_data());
+        let segment = wal::segment_numbers(&logs_dir, Photo::TYPE_NAME).unwrap()[0];
+        let segment_path = logs_dir.join(format!("Photo-{}.log", segment));
+        assert_eq!(fs::metadata(segment_path).unwrap().len(), 0);
+    }
+
+    /// Builds a table out of the database with the given photos, or tombstones for None
+    fn external_table(
+        dir: &TempDir,
+        name: &str,
+        records: &[(usize, Option<Photo>)],
+    ) -> (String, String) {
+        let config = test_config(dir);
+        let mut builder = SSTableBuilder::new(&BinarySerializationEngine, &config).unwrap();
+        for (i, record) in records {
+            builder.add(format!("id_{}", i), record.clone()).unwrap();
+        }
+        let [storage, index] = ["storage", "index"].map(|kind| {
+            dir.path()
+                .join(format!("{}-{}.log", name, kind))
                 .display()
                 .to_string()
         });
-        (index_path, storage_path)
-    }
-
-    fn create_metadata<'b>(&self, tables: impl Iterator<Item = &'b SSTable>) -> IOResult<()> {
-        let mut temp_file = NamedTempFile::new_in(&self.config.db_path)?;
-        for table in tables {
-            temp_file.write_all(
-                format!(
-                    "{} {} {} {} {} {}\n",
-                    table.storage_path.clone(),
-                    table.index_path.clone(),
-                    table.min.clone(),
-                    table.max.clone(),
-                    table.count,
-                    table.size,
-                )
-                .as_bytes(),
-            )?;
-        }
-
-        let _guard = self.metadata.lock().unwrap(); // lock the metadata first, before changing the file
-        temp_file.persist(Self::get_metadata_path(&self.config.db_path))?;
-        Ok(())
+        builder.finish(&storage, &index).unwrap();
+        (storage, index)
+    }
+
+    #[test]
+    fn ingested_tables_are_newer_than_earlier_writes() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let external = TempDir::new().expect("Failed to create temp dir");
+        let newer = Photo {
+            id: "id_1".to_string(),
+            url: "newer".to_string(),
+        };
+        let files = [
+            external_table(&external, "a", &[(1, Some(newer.clone())), (2, None)]),
+            external_table(&external, "b", &[(5, Some(photo(5)))]),
+        ];
+        {
+            let engine = open(&temp_dir);
+            engine.insert(photo(1)).expect("Insert failed");
+            engine.insert(photo(2)).expect("Insert failed");
+            engine.ingest_files(&files).expect("Ingestion failed");
+
+            assert_eq!(engine.get("id_1".to_string()).unwrap(), Some(newer.clone()));
+            assert_eq!(engine.get("id_2".to_string()).unwrap(), None);
+            assert_eq!(engine.get("id_5".to_string()).unwrap(), Some(photo(5)));
+
+            engine.insert(photo(5)).expect("Insert failed");
+        }
+
+        // The originals are kept, and the ingested tables survive a restart
+        for (storage, index) in &files {
+            assert!(Path::new(storage).exists() && Path::new(index).exists());
+        }
+        let engine = open(&temp_dir);
+        assert_eq!(engine.get("id_1".to_string()).unwrap(), Some(newer));
+        assert_eq!(engine.get("id_2".to_string()).unwrap(), None);
+        assert_eq!(engine.get("id_5".to_string()).unwrap(), Some(photo(5)));
+    }
+
+    #[test]
+    fn overlapping_or_invalid_files_are_rejected() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let external = TempDir::new().expect("Failed to create temp dir");
+        let engine = open(&temp_dir);
+        let storage_dir = temp_dir.path().join("storage");
+        let tables_before = fs::read_dir(&storage_dir).unwrap().count();
+
+        let overlapping = [
+            external_table(&external, "a", &[(1, Some(photo(1))), (3, Some(photo(3)))]),
+            external_table(&external, "b", &[(2, Some(photo(2)))]),
+        ];
+        assert!(matches!(
+            engine.ingest_files(&overlapping),
+            Err(EngineError::Ingestion { .. })
+        ));
+
+        let (storage, index) = external_table(&external, "c", &[(4, Some(photo(4)))]);
+        fs::write(&storage, "not a table").unwrap();
+        assert!(matches!(
+            engine.ingest_files(&[(storage, index)]),
+            Err(EngineError::Ingestion { .. })
+        ));
+
+        // An entry past the end of the storage file, which is rejected without cutting the index
+        let (storage, index) = external_table(&external, "d", &[(6, Some(photo(6)))]);
+        let mut bytes = fs::read(&index).unwrap();
+        let intact = bytes.len();
+        bytes.extend_from_within(..intact);
+        bytes[intact + 24..].copy_from_slice(&u64::MAX.to_le_bytes());
+        fs::write(&index, &bytes).unwrap();
+        assert!(matches!(
+            engine.ingest_files(&[(storage, index.clone())]),
+            Err(EngineError::Ingestion { .. })
+        ));
+        assert_eq!(fs::read(&index).unwrap(), bytes);
+
+        assert_eq!(fs::read_dir(&storage_dir).unwrap().count(), tables_before);
+        assert_eq!(engine.get("id_2".to_string()).unwrap(), None);
+        assert_eq!(engine.get("id_4".to_string()).unwrap(), None);
+        assert_eq!(engine.get("id_6".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn large_values_are_separated_and_their_garbage_collected() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let config = Config {
+            compaction_threads: 0,
+            custom_compaction_strategy: Some(Arc::new(MergeEverything)),
+            blob_threshold: Some(100),
+            ..test_config(&temp_dir)
+        };
+        let large = |i: usize, version: &str| Photo {
+            id: format!("id_{}", i),
+            url: format!("{}_{}", version, "x".repeat(200)),
+        };
+        let blob_files = || {
+            let mut names: Vec<_> = fs::read_dir(temp_dir.path().join("blobs"))
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            names.sort();
+            names
+        };
+
+        let engine = open_with(config.clone());
+        for i in 0..10 {
+            engine.insert(large(i, "old")).expect("Insert failed");
+        }
+        engine.insert(photo(10)).expect("Insert failed");
+        engine.flush().expect("Flush failed");
+        let first = blob_files();
+        assert_eq!(first.len(), 1);
+        let table = Arc::clone(&engine.version().level(0)[0]);
+        assert!(table.size < 2000);
+        assert_eq!(table.count, 11);
+
+        // Compactions only move the pointers
+        for i in 0..8 {
+            engine.insert(photo(i)).expect("Insert failed");
+        }
+        engine.flush().expect("Flush failed");
+        engine.compact().expect("Compaction failed");
+        assert_eq!(engine.version().len(), 1);
+        assert_eq!(blob_files(), first);
+        assert_eq!(
+            engine.get("id_9".to_string()).unwrap(),
+            Some(large(9, "old"))
+        );
+
+        // Then the blob file is mostly garbage, and only its live values are kept
+        engine.compact().expect("Compaction failed");
+        let second = blob_files();
+        assert_eq!(second.len(), 1);
+        assert_ne!(second, first);
+        let version = engine.version();
+        let blob_file = version.blob_files().next().unwrap();
+        assert_eq!(version.live_blob_bytes(blob_file.number), blob_file.size);
+        drop(version);
+        drop(engine);
+
+        let engine = open_with(config);
+        assert_eq!(blob_files(), second);
+        assert_eq!(engine.get("id_1".to_string()).unwrap(), Some(photo(1)));
+        assert_eq!(
+            engine.get("id_8".to_string()).unwrap(),
+            Some(large(8, "old"))
+        );
+        assert_eq!(
+            engine.get("id_9".to_string()).unwrap(),
+            Some(large(9, "old"))
+        );
+        assert_eq!(engine.get("id_10".to_string()).unwrap(), Some(photo(10)));
+    }
+
+    #[test]
+    fn repair_discards_tables_whose_blob_files_are_lost() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let config = Config {
+            compaction_threads: 0,
+            blob_threshold: Some(100),
+            ..test_config(&temp_dir)
+        };
+        let large = Photo {
+            id: "id_0".to_string(),
+            url: "x".repeat(200),
+        };
+        let (separated, inline) = {
+            let engine = open_with(config.clone());
+            engine.insert(large).expect("Insert failed");
+            engine.flush().expect("Flush failed");
+            engine.insert(photo(1)).expect("Insert failed");
+            engine.flush().expect("Flush failed");
+            let version = engine.version();
+            let tables = version.level(0);
+            (
+                tables[0].storage_path.clone(),
+                tables[1].storage_path.clone(),
+            )
+        };
+
+        let db_path = temp_dir.path();
+        fs::remove_dir_all(db_path.join("metadata")).unwrap();
+        fs::remove_dir_all(db_path.join("blobs")).unwrap();
+
+        let report =
+            PhotoEngine::repair(&BinarySerializationEngine, &config).expect("Repair failed");
+        assert_eq!(report.recovered, vec![inline]);
+        assert_eq!(report.discarded.len(), 1);
+        assert_eq!(report.discarded[0].0, separated);
+        assert!(report.discarded[0].1.starts_with("Missing blob file"));
+
+        let engine = open_with(config);
+        assert_eq!(engine.version().len(), 1);
+        assert_eq!(engine.get("id_0".to_string()).unwrap(), None);
+        assert_eq!(engine.get("id_1".to_string()).unwrap(), Some(photo(1)));
     }
 
-    fn get_metadata_path(db_path: &str) -> PathBuf {
-        Path::new(db_path).join(format!("metadata/{}.meta", T::TYPE_NAME))
+    #[test]
+    fn bottommost_tables_use_their_own_compression() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let engine = open_with(Config {
+            compaction_threads: 0,
+            custom_compaction_strategy: Some(Arc::new(MergeEverything)),
+            compression: Compression::Lz4,
+            bottommost_compression: Some(Compression::Deflate),
+            ..test_config(&temp_dir)
+        });
+        // The codec of the first block of the table
+        let codec = |table: &Arc<SSTable>| fs::read(&table.storage_path).unwrap()[0];
+
+        for batch in 0..2 {
+            for i in 0..50 {
+                engine.insert(photo(batch * 50 + i)).expect("Insert failed");
+            }
+            engine.flush().expect("Flush failed");
+        }
+        assert!(
+            engine
+                .version()
+                .level(0)
+                .iter()
+                .all(|table| codec(table) == 1)
+        );
+
+        engine.compact().expect("Compaction failed");
+        let version = engine.version();
+        assert_eq!(version.len(), 1);
+        assert_eq!(codec(&version.level(0)[0]), 2);
+        for i in [0, 49, 99] {
+            assert_eq!(engine.get(format!("id_{}", i)).unwrap(), Some(photo(i)));
+        }
+    }
+
+    #[test]
+    fn compacted_tables_are_deleted_once_unused() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let engine = open_with(Config {
+            compaction_threads: 0,
+            custom_compaction_strategy: Some(Arc::new(MergeEverything)),
+            ..test_config(&temp_dir)
+        });
+
+        for batch in 0..3 {
+            for i in 0..10 {
+                engine.insert(photo(batch * 10 + i)).expect("Insert failed");
+            }
+            engine.flush().expect("Flush failed");
+        }
+
+        // A reader still using the tables from before the compaction
+        let old = engine.version();
+        engine.compact().expect("Compaction failed");
+        let files = |version: &Version| -> Vec<String> {
+            version
+                .tables()
+                .flat_map(|table| [table.storage_path.clone(), table.index_path.clone()])
+                .collect()
+        };
+        let old_files = files(&old);
+        assert!(old_files.iter().all(|file| Path::new(file).exists()));
+        assert_eq!(engine.get("id_5".to_string()).unwrap(), Some(photo(5)));
+
+        drop(old);
+        assert!(old_files.iter().all(|file| !Path::new(file).exists()));
+        assert!(
+            files(&engine.version())
+                .iter()
+                .all(|file| Path::new(file).exists())
+        );
+        for i in 0..30 {
+            assert_eq!(engine.get(format!("id_{}", i)).unwrap(), Some(photo(i)));
+        }
+    }
+
+    #[test]
+    fn memtables_are_flushed_by_size_wal_or_age() {
+        let large = |i: usize| Photo {
+            id: format!("id_{}", i),
+            url: "x".repeat(500),
+        };
+        for (memtable_max_bytes, wal_max_bytes) in [(Some(2000), None), (None, Some(2000))] {
+            let temp_dir = TempDir::new().expect("Failed to create temp dir");
+            let engine = open_with(Config {
+                compaction_threads: 0,
+                initial_index_file_threshold: usize::MAX,
+                memtable_max_bytes,
+                wal_max_bytes,
+                ..test_config(&temp_dir)
+            });
+            // Every write takes a bit more than 500 bytes, so every 4th one fills the memtable
+            for i in 0..10 {
+                engine.insert(large(i)).expect("Insert failed");
+            }
+            engine.inner.wait_for_flush().unwrap();
+            assert_eq!(engine.version().len(), 2);
+            let memtable = Arc::clone(&engine.inner.memtable.read().unwrap());
+            assert_eq!(memtable.len(), 2);
+            assert!(memtable.size() > 1000 && memtable.size() < 2000);
+        }
+
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let engine = open_with(Config {
+            compaction_threads: 0,
+            memtable_max_age_ms: Some(50),
+            ..test_config(&temp_dir)
+        });
+        engine.insert(photo(1)).expect("Insert failed");
+        engine.inner.wait_for_flush().unwrap();
+        assert_eq!(engine.version().len(), 0);
+        // Without any more writes, the flush thread notices the age
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while engine.version().is_empty() {
+            assert!(Instant::now() < deadline, "The memtable was never flushed");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(engine.inner.memtable.read().unwrap().is_empty());
+        assert_eq!(engine.get("id_1".to_string()).unwrap(), Some(photo(1)));
+    }
+
+    #[test]
+    fn wal_is_synced_in_the_background_with_interval_mode() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let engine = open_with(Config {
+            wal_sync: WalSyncMode::Interval,
+            wal_sync_interval_ms: 10,
+            ..test_config(&temp_dir)
+        });
+
+        engine.insert(photo(0)).expect("Insert failed");
+        let memtable = Arc::clone(&engine.inner.memtable.read().unwrap());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while memtable.log.has_unsynced_writes() {
+            assert!(Instant::now() < deadline, "The WAL was never synced");
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(engine.wal_sync_error().is_none());
+        drop(memtable);
+        drop(engine);
+
+        let engine = open(&temp_dir);
+        assert_eq!(engine.get("id_0".to_string()).unwrap(), Some(photo(0)));
+    }
+
+    #[test]
+    fn engines_share_the_write_buffer_budget() {
+        let large = |i: usize| Photo {
+            id: format!("id_{}", i),
+            url: "x".repeat(500),
+        };
+        let config = |temp_dir: &TempDir, manager: &Arc<WriteBufferManager>| Config {
+            compaction_threads: 0,
+            initial_index_file_threshold: usize::MAX,
+            memtable_max_bytes: None,
+            write_buffer_manager: Some(Arc::clone(manager)),
+            ..test_config(temp_dir)
+        };
+        let (first_dir, second_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+
+        // Every write takes a bit more than 500 bytes
+        let manager = Arc::new(WriteBufferManager::new(3000));
+        let first = open_with(config(&first_dir, &manager));
+        let second = open_with(config(&second_dir, &manager));
+        for i in 0..4 {
+            first.insert(large(i)).expect("Insert failed");
+        }
+        second.insert(large(0)).expect("Insert failed");
+        assert_eq!(first.version().len(), 0);
+        // Over the budget, the largest memtable is flushed
+        second.insert(large(1)).expect("Insert failed");
+        first.inner.wait_for_flush().unwrap();
+        assert_eq!(first.version().len(), 1);
+        assert_eq!(second.version().len(), 0);
+        let second_size = second.inner.memtable.read().unwrap().size();
+        assert_eq!(manager.usage(), second_size);
+        drop(second);
+        assert_eq!(manager.usage(), 0);
+        drop(first);
+
+        // Writers wait for the flushes once far over the budget
+        let temp_dir = TempDir::new().unwrap();
+        let manager = Arc::new(WriteBufferManager::new(1000).with_stall_limit(1200));
+        let engine = open_with(config(&temp_dir, &manager));
+        for i in 0..10 {
+            engine.insert(large(i)).expect("Insert failed");
+            assert!(manager.usage() < 1800);
+        }
+        for i in 0..10 {
+            assert_eq!(engine.get(format!("id_{}", i)).unwrap(), Some(large(i)));
+        }
     }
 }
@@ -0,0 +1,133 @@
+use std::{collections::BTreeSet, fs, path::Path, sync::Arc};
+
+use crate::{
+    config::Config,
+    memtable::{LogOperation, MemTableRecord},
+    serialization::SerializationEngine,
+    sstable::{
+        SSTable,
+        blob::{BlobFile, blob_path},
+    },
+};
+
+use super::{
+    Engine, EngineError, Version,
+    manifest::{Manifest, table_file_number},
+    wal,
+};
+
+/// @definition: The outcome of rebuilding the manifest from the table files
+/// @field recovered: The storage paths of the tables in the new manifest, from oldest to newest
+/// @field truncated: The recovered tables that were only partly readable. Their entries after the
+/// first unreadable one are lost
+/// @field discarded: The tables left out, with the reason: none of their entries could be read, or
+/// a blob file holding their values is missing. Their files are cleaned up as orphans when the
+/// engine is opened
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    pub recovered: Vec<String>,
+    pub truncated: Vec<String>,
+    pub discarded: Vec<(String, String)>,
+}
+
+impl<T, S, SS> Engine<T, S, SS>
+where
+    T: MemTableRecord,
+    S: SerializationEngine<LogOperation<T>>,
+    SS: SerializationEngine<Option<T>>,
+{
+    /// Rebuilds the manifest from the table files of the record type, for when it is lost or
+    /// corrupted. Every table is validated, and the readable ones are put in level 0 along with
+    /// the blob files they point to, from the oldest to the newest by the sequence number of the
+    /// last write they hold, as recorded in their footer. Must not run while an engine is open on
+    /// the database.
+    pub fn repair(storage_serializer: &SS, config: &Config) -> Result<RepairReport, EngineError> {
+        let db_path = Path::new(&config.db_path);
+        if !db_path.exists() {
+            return Err(EngineError::DBDoesntExist);
+        }
+
+        let storage_dir = db_path.join("storage");
+        let mut numbers: Vec<u64> = match fs::read_dir(&storage_dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| table_file_number(&entry.ok()?.file_name(), T::TYPE_NAME))
+                .collect(),
+            Err(_) => vec![],
+        };
+        numbers.sort();
+
+        let mut report = RepairReport::default();
+        let mut tables = vec![];
+        for number in numbers {
+            let [storage_path, index_path] = ["storage", "indices"].map(|dir| {
+                db_path
+                    .join(format!("{}/{}-{}.log", dir, T::TYPE_NAME, number))
+                    .display()
+                    .to_string()
+            });
+
+            match SSTable::recover(&storage_path, &index_path, storage_serializer, config) {
+                Ok((table, truncated)) => {
+                    // Its values in a lost blob file couldn't be read
+                    let missing = table
+                        .blobs
+                        .iter()
+                        .map(|(number, _)| blob_path(&config.db_path, T::TYPE_NAME, *number))
+                        .find(|path| !path.exists());
+                    if let Some(missing) = missing {
+                        let reason = format!("Missing blob file {}", missing.display());
+                        report.discarded.push((storage_path, reason));
+                        continue;
+                    }
+
+                    if truncated {
+                        report.truncated.push(storage_path);
+                    }
+                    tables.push(Arc::new(table));
+                }
+                Err(err) => report.discarded.push((storage_path, format!("{:?}", err))),
+            }
+        }
+
+        // A table without a footer, written before them or cut, stays after the table with the
+        // file number before its own. The sort is stable, so the tables holding the same last
+        // write keep the order of their file numbers too: a flushed table and the ingested tables
+        // after it
+        let mut known = 0;
+        let mut tables: Vec<_> = tables
+            .into_iter()
+            .map(|table: Arc<SSTable>| {
+                known = match table.max_sequence {
+                    0 => known,
+                    sequence => sequence,
+                };
+                (known, table)
+            })
+            .collect();
+        tables.sort_by_key(|(sequence, _)| *sequence);
+        let tables: Vec<_> = tables.into_iter().map(|(_, table)| table).collect();
+        report.recovered = tables
+            .iter()
+            .map(|table| table.storage_path.clone())
+            .collect();
+
+        // The blob files are kept for the tables pointing to them
+        let referenced: BTreeSet<u64> = tables
+            .iter()
+            .flat_map(|table| table.blobs.iter().map(|(file, _)| *file))
+            .collect();
+        let blob_files = referenced.into_iter().filter_map(|number| {
+            let path = blob_path(&config.db_path, T::TYPE_NAME, number);
+            let size = fs::metadata(&path).ok()?.len();
+            Some(Arc::new(BlobFile::new(number, path, size)))
+        });
+        let version = Version::new(tables).with_blob_files(blob_files, &[]);
+
+        // The next writes are numbered after every write still in the WAL, archived or not
+        let last_sequence = wal::last_logged_sequence(&config.db_path, T::TYPE_NAME)
+            .map_err(|err| EngineError::ManifestRecovery { err })?;
+        Manifest::create(&config.db_path, T::TYPE_NAME, &version, last_sequence)
+            .map_err(|err| EngineError::ManifestRecovery { err })?;
+        Ok(report)
+    }
+}
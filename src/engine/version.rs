@@ -0,0 +1,188 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    mem,
+    sync::Arc,
+};
+
+use crate::sstable::{SSTable, blob::BlobFile};
+
+/// @definition: The sstables of the database at some point in time, grouped by level. A version is
+/// never modified, changes create a new version instead, so reads can keep using the one they
+/// started with
+/// @field levels: Level 0 holds flushed tables from oldest to newest, and may overlap. Every other
+/// level is sorted by key, and its tables never overlap
+/// @field blob_files: The blob files holding the values separated from the tables, by number
+#[derive(Debug, Default, Clone)]
+pub struct Version {
+    levels: Vec<Vec<Arc<SSTable>>>,
+    blob_files: BTreeMap<u64, Arc<BlobFile>>,
+}
+
+impl Version {
+    /// Tables of level 0 are expected in the order they were flushed
+    pub fn new(tables: impl IntoIterator<Item = Arc<SSTable>>) -> Version {
+        let mut version = Version::default();
+        for table in tables {
+            version.level_mut(table.level).push(table);
+        }
+        for level in version.levels.iter_mut().skip(1) {
+            level.sort_by(|a, b| a.min.cmp(&b.min));
+        }
+        version
+    }
+
+    pub fn levels(&self) -> &[Vec<Arc<SSTable>>] {
+        &self.levels
+    }
+
+    pub fn level(&self, level: usize) -> &[Arc<SSTable>] {
+        self.levels
+            .get(level)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// All the tables, level by level
+    pub fn tables(&self) -> impl Iterator<Item = &Arc<SSTable>> {
+        self.levels.iter().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.levels.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The tables that may have the key, from newest to oldest: every table of level 0, then at
+    /// most one table for each of the other levels
+    pub fn candidates<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a Arc<SSTable>> {
+        let level_0 = self.level(0).iter().rev();
+        let others = self.levels.iter().skip(1).filter_map(move |level| {
+            let idx = level.partition_point(|table| table.max.as_str() < key);
+            level.get(idx).filter(|table| table.min.as_str() <= key)
+        });
+        level_0.chain(others)
+    }
+
+    /// The tables of the level with keys in the range `[min, max]`
+    pub fn overlapping(&self, level: usize, min: &str, max: &str) -> Vec<Arc<SSTable>> {
+        self.level(level)
+            .iter()
+            .filter(|table| table.min.as_str() <= max && table.max.as_str() >= min)
+            .cloned()
+            .collect()
+    }
+
+    /// A new version with the flushed table as the newest table of level 0
+    pub fn with_flushed(&self, table: Arc<SSTable>) -> Version {
+        let mut version = self.clone();
+        version.level_mut(0).push(table);
+        version
+    }
+
+    /// A new version with the inputs replaced by the outputs. In level 0 the outputs take the place
+    /// of the newest input, since the tables flushed in the meantime are newer than all of the
+    /// inputs. In the other levels they are placed by key.
+    pub fn with_compaction(
+        &self,
+        inputs: &[Arc<SSTable>],
+        outputs: Vec<Arc<SSTable>>,
+        output_level: usize,
+    ) -> Version {
+        let inputs: HashSet<&str> = inputs
+            .iter()
+            .map(|table| table.storage_path.as_str())
+            .collect();
+        let mut version = self.clone();
+
+        if output_level == 0 {
+            let level = version.level_mut(0);
+            let last_idx = level
+                .iter()
+                .rposition(|table| inputs.contains(table.storage_path.as_str()))
+                .unwrap_or(level.len());
+            let mut outputs = Some(outputs);
+            *level = mem::take(level)
+                .into_iter()
+                .enumerate()
+                .flat_map(|(i, table)| {
+                    if i == last_idx {
+                        outputs.take().unwrap_or_default()
+                    } else if inputs.contains(table.storage_path.as_str()) {
+                        vec![]
+                    } else {
+                        vec![table]
+                    }
+                })
+                .collect();
+        } else {
+            let level = version.level_mut(output_level);
+            level.extend(outputs);
+            level.sort_by(|a, b| a.min.cmp(&b.min));
+        }
+
+        for level in version.levels.iter_mut() {
+            level.retain(|table| !inputs.contains(table.storage_path.as_str()));
+        }
+
+        version.levels.truncate(
+            version
+                .levels
+                .iter()
+                .rposition(|level| !level.is_empty())
+                .map_or(0, |idx| idx + 1),
+        );
+        version
+    }
+
+    pub fn blob_files(&self) -> impl Iterator<Item = &Arc<BlobFile>> {
+        self.blob_files.values()
+    }
+
+    /// The bytes of the blob file that some table references. The rest of the file is garbage
+    pub fn live_blob_bytes(&self, number: u64) -> u64 {
+        self.tables()
+            .flat_map(|table| &table.blobs)
+            .filter(|(file, _)| *file == number)
+            .map(|(_, bytes)| bytes)
+            .sum()
+    }
+
+    /// The blob files that no table references anymore
+    pub fn unreferenced_blob_files(&self) -> Vec<Arc<BlobFile>> {
+        let referenced: HashSet<u64> = self
+            .tables()
+            .flat_map(|table| table.blobs.iter().map(|(file, _)| *file))
+            .collect();
+        self.blob_files
+            .values()
+            .filter(|blob_file| !referenced.contains(&blob_file.number))
+            .cloned()
+            .collect()
+    }
+
+    /// A new version with the blob files added and removed
+    pub fn with_blob_files(
+        &self,
+        added: impl IntoIterator<Item = Arc<BlobFile>>,
+        removed: &[u64],
+    ) -> Version {
+        let mut version = self.clone();
+        for blob_file in added {
+            version.blob_files.insert(blob_file.number, blob_file);
+        }
+        for number in removed {
+            version.blob_files.remove(number);
+        }
+        version
+    }
+
+    fn level_mut(&mut self, level: usize) -> &mut Vec<Arc<SSTable>> {
+        if self.levels.len() <= level {
+            self.levels.resize_with(level + 1, Vec::new);
+        }
+        &mut self.levels[level]
+    }
+}
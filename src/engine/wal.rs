@@ -0,0 +1,246 @@
+use std::{
+    fmt::Debug,
+    fs::{self, File, create_dir_all},
+    io::{self, ErrorKind, Result as IOResult},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::AtomicU64,
+        mpsc::{self, RecvTimeoutError, Sender},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    config::{Config, WalSyncMode},
+    memtable::{LogOperation, MemTable, MemTableLogReader, MemTableRecord, WalRecoveryReport},
+    serialization::SerializationEngine,
+    sstable::sync_dir,
+};
+
+use super::{
+    EngineInner,
+    manifest::{Manifest, table_file_number},
+};
+
+/// @definition: The thread syncing the WAL every `wal_sync_interval_ms`. Only runs with
+/// `WalSyncMode::Interval`, the other modes don't sync in the background. A failed sync is kept in
+/// `wal_sync_error`, and the writes it missed are synced on the next tick
+pub(super) struct WalSyncer {
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl WalSyncer {
+    pub fn spawn<T, S, SS>(inner: Arc<EngineInner<T, S, SS>>) -> WalSyncer
+    where
+        T: MemTableRecord + Debug + Send + Sync + 'static,
+        S: SerializationEngine<LogOperation<T>> + Send + Sync + 'static,
+        SS: SerializationEngine<Option<T>> + Send + Sync + 'static,
+    {
+        if inner.config.wal_sync != WalSyncMode::Interval {
+            return WalSyncer {
+                sender: None,
+                handle: None,
+            };
+        }
+
+        let interval = Duration::from_millis(inner.config.wal_sync_interval_ms);
+        let (sender, receiver) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+                let memtable = Arc::clone(&inner.memtable.read().unwrap());
+                let synced = memtable.log.sync();
+                *inner.wal_sync_error.lock().unwrap() = synced.err().map(|err| format!("{err:?}"));
+            }
+        });
+
+        WalSyncer {
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for WalSyncer {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// @definition: The memtables replayed from the WAL segments when the engine is opened
+/// @field immutable: The memtable of the older segment, that was swapped out but not flushed before
+/// the last shutdown, with the number of its segment
+/// @field active: The memtable accepting writes, with the number of its segment
+/// @field reports: What was replayed and dropped from each segment
+pub(super) struct RecoveredSegments<T, S>
+where
+    T: MemTableRecord,
+    S: SerializationEngine<LogOperation<T>>,
+{
+    pub immutable: Option<(MemTable<T, S>, u64)>,
+    pub active: (MemTable<T, S>, u64),
+    pub reports: Vec<(PathBuf, WalRecoveryReport)>,
+}
+
+/// Replays the segments that aren't flushed yet. The older of them holds the immutable memtable
+/// and the newest the active one, since a memtable is only swapped out once the one before it is
+/// flushed. The single log used before the WAL was split into segments becomes the newest
+/// segment, and segments left behind by a crash right after their memtable was flushed are
+/// retired.
+pub(super) fn recover_segments<T, S>(
+    manifest: &mut Manifest,
+    log_number: u64,
+    config: &Config,
+    serializer: &Arc<S>,
+    sequence: &Arc<AtomicU64>,
+) -> IOResult<RecoveredSegments<T, S>>
+where
+    T: MemTableRecord,
+    S: SerializationEngine<LogOperation<T>>,
+{
+    let logs_dir = Path::new(&config.db_path).join("logs");
+    let legacy = logs_dir.join(format!("{}.log", T::TYPE_NAME));
+    if legacy.exists() {
+        let number = manifest.new_file_number()?;
+        fs::rename(&legacy, segment_path(&config.db_path, T::TYPE_NAME, number))?;
+        sync_dir(&logs_dir)?;
+    }
+
+    let mut reports = vec![];
+    let mut unflushed = vec![];
+    for number in segment_numbers(&logs_dir, T::TYPE_NAME)? {
+        let path = segment_path(&config.db_path, T::TYPE_NAME, number);
+        if number < log_number {
+            retire_segment(&path, T::TYPE_NAME, config)?;
+            continue;
+        }
+
+        let memtable = MemTable::open_or_build_with(
+            &path.display().to_string(),
+            Arc::clone(serializer),
+            config,
+            Arc::clone(sequence),
+        )?;
+        reports.push((path.clone(), memtable.recovery_report().clone()));
+        unflushed.push((memtable, number));
+    }
+
+    let active = match unflushed.pop() {
+        Some(active) => active,
+        None => {
+            let number = manifest.new_file_number()?;
+            let path = segment_path(&config.db_path, T::TYPE_NAME, number);
+            let memtable = MemTable::open_or_build_with(
+                &path.display().to_string(),
+                Arc::clone(serializer),
+                config,
+                Arc::clone(sequence),
+            )?;
+            sync_dir(&logs_dir)?;
+            (memtable, number)
+        }
+    };
+
+    // An empty segment has nothing to flush
+    let mut immutable = None;
+    for (memtable, number) in unflushed {
+        if memtable.is_empty() {
+            drop(memtable);
+            fs::remove_file(segment_path(&config.db_path, T::TYPE_NAME, number))?;
+            sync_dir(&logs_dir)?;
+        } else if immutable.replace((memtable, number)).is_some() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "More than one WAL segment is waiting to be flushed",
+            ));
+        }
+    }
+
+    Ok(RecoveredSegments {
+        immutable,
+        active,
+        reports,
+    })
+}
+
+/// The path of a WAL segment. Segments are numbered from the same counter as the table files, so
+/// every memtable gets a segment newer than the ones before it
+pub(super) fn segment_path(db_path: &str, type_name: &str, number: u64) -> PathBuf {
+    Path::new(db_path).join(format!("logs/{}-{}.log", type_name, number))
+}
+
+/// The numbers of the WAL segments of the record type in `dir`, from oldest to newest
+pub(super) fn segment_numbers(dir: &Path, type_name: &str) -> IOResult<Vec<u64>> {
+    let mut numbers = vec![];
+    if dir.exists() {
+        for entry in fs::read_dir(dir)? {
+            if let Some(number) = table_file_number(&entry?.file_name(), type_name) {
+                numbers.push(number);
+            }
+        }
+    }
+    numbers.sort();
+    Ok(numbers)
+}
+
+/// The highest sequence number logged in the segments of the record type, the archived ones
+/// included
+pub(super) fn last_logged_sequence(db_path: &str, type_name: &str) -> IOResult<u64> {
+    let logs_dir = Path::new(db_path).join("logs");
+    let mut last_sequence = 0;
+    for dir in [logs_dir.clone(), logs_dir.join("archive")] {
+        for number in segment_numbers(&dir, type_name)? {
+            let file = File::open(dir.join(format!("{}-{}.log", type_name, number)))?;
+            last_sequence = last_sequence.max(MemTableLogReader::last_sequence_of(file)?);
+        }
+    }
+    Ok(last_sequence)
+}
+
+/// Gets rid of a segment whose memtable is flushed: it is deleted, or moved to `logs/archive` when
+/// `config.wal_archive` is set, after which the archive is trimmed to its limits
+pub(super) fn retire_segment(path: &Path, type_name: &str, config: &Config) -> IOResult<()> {
+    let logs_dir = path.parent().unwrap();
+    if !config.wal_archive {
+        fs::remove_file(path)?;
+        return sync_dir(logs_dir);
+    }
+
+    let archive_dir = logs_dir.join("archive");
+    create_dir_all(&archive_dir)?;
+    fs::rename(path, archive_dir.join(path.file_name().unwrap()))?;
+    sync_dir(&archive_dir)?;
+    sync_dir(logs_dir)?;
+    trim_archive(&archive_dir, type_name, config)
+}
+
+/// Deletes the oldest archived segments until the archive is within `wal_archive_max_bytes`, and
+/// those last written more than `wal_archive_max_age_ms` ago
+fn trim_archive(archive_dir: &Path, type_name: &str, config: &Config) -> IOResult<()> {
+    let mut segments = vec![];
+    for number in segment_numbers(archive_dir, type_name)? {
+        let path = archive_dir.join(format!("{}-{}.log", type_name, number));
+        let metadata = fs::metadata(&path)?;
+        segments.push((path, metadata.len(), metadata.modified()?));
+    }
+
+    let now = SystemTime::now();
+    let mut size: u64 = segments.iter().map(|(_, len, _)| len).sum();
+    for (path, len, modified) in segments {
+        let expired = config.wal_archive_max_age_ms.is_some_and(|max_age| {
+            now.duration_since(modified).unwrap_or_default() > Duration::from_millis(max_age)
+        });
+        let oversized = config.wal_archive_max_bytes.is_some_and(|max| size > max);
+        if !expired && !oversized {
+            break;
+        }
+        fs::remove_file(&path)?;
+        size -= len;
+    }
+    Ok(())
+}
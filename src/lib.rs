@@ -1,6 +1,9 @@
+#![allow(non_snake_case)]
+
 pub mod compaction;
 pub mod config;
 pub mod engine;
 pub mod memtable;
 pub mod serialization;
 pub mod sstable;
+pub mod write_buffer;
@@ -1,3 +1,5 @@
+#![allow(non_snake_case)]
+
 use SSTables::{
     config::Config, engine::Engine, memtable::MemTableRecord,
     serialization::BinarySerializationEngine,
@@ -18,19 +20,26 @@ impl MemTableRecord for User {
     }
 }
 
+type UserEngine = Engine<User, BinarySerializationEngine, BinarySerializationEngine>;
+
 fn main() {
-    let serializer = BinarySerializationEngine;
     let config = Config::from_file("config.yaml").unwrap();
-    let engine = Engine::<User, BinarySerializationEngine, BinarySerializationEngine>::new(
-        &serializer,
-        &serializer,
-        &config,
-    )
-    .unwrap();
+
+    // `repair` rebuilds the manifest from the table files
+    if std::env::args().nth(1).as_deref() == Some("repair") {
+        let report = UserEngine::repair(&BinarySerializationEngine, &config).unwrap();
+        println!("Recovered tables: {:?}", report.recovered);
+        println!("Truncated tables: {:?}", report.truncated);
+        println!("Discarded tables: {:?}", report.discarded);
+        return;
+    }
 
     let count = config.initial_index_file_threshold
         / (config.index_key_string_size + config.index_offset_size);
 
+    let engine =
+        UserEngine::new(BinarySerializationEngine, BinarySerializationEngine, config).unwrap();
+
     for i in 0..count * 10 {
         engine
             .insert(User {
@@ -47,7 +56,7 @@ fn main() {
         assert!(engine.get(key).unwrap().is_some());
     }
 
-    engine.compact();
+    engine.compact().unwrap();
 
     for i in 0..count {
         let key = format!("user_{}", i);
@@ -1,39 +1,188 @@
+use crate::config::WalSyncMode;
 use crate::memtable::MemTableRecord;
 use crate::serialization::SerializationEngine;
 
 use super::operation::LogOperation;
 use std::fs::File;
 use std::io::{Error, ErrorKind, Result as IOResult, Seek, SeekFrom, Write};
-use std::sync::{Arc, Mutex};
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 
+/// @definition: The records waiting to be written by the next group commit
+/// @field batch: The id of the batch the new records join
+/// @field written: The id of the last batch that was written
+/// @field writing: Whether a leader is writing a batch right now
+/// @field failed: The first batch that couldn't be written, and why. Every later write fails too,
+/// since the log may have a hole
+#[derive(Default)]
+struct GroupCommit {
+    pending: Vec<u8>,
+    batch: u64,
+    written: u64,
+    writing: bool,
+    failed: Option<(u64, String)>,
+}
+
+/// @definition: The write-ahead log of a memtable. Concurrent appends are committed in groups: the
+/// first writer to find no write in progress becomes the leader, and writes the records of every
+/// writer that joined the batch with a single write and sync, while the others wait for it.
+/// @field sync_mode: Whether the leader syncs the batch before the writers are acknowledged
+/// @field dirty: Whether there are writes that weren't synced yet
+/// @field size: The length of the log, with the records written so far
+/// @field writes: The number of batches written, each with a single write
 pub struct MemTableLog {
     pub file: Arc<Mutex<File>>,
+    sync_mode: WalSyncMode,
+    dirty: AtomicBool,
+    size: AtomicU64,
+    writes: AtomicU64,
+    group: Mutex<GroupCommit>,
+    committed: Condvar,
 }
 
 impl MemTableLog {
-    pub fn new(file: File) -> Self {
+    pub fn new(file: File, sync_mode: WalSyncMode) -> Self {
+        let size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
         MemTableLog {
             file: Arc::new(Mutex::new(file)),
+            sync_mode,
+            dirty: AtomicBool::new(false),
+            size: AtomicU64::new(size),
+            writes: AtomicU64::new(0),
+            group: Mutex::new(GroupCommit {
+                batch: 1,
+                ..Default::default()
+            }),
+            committed: Condvar::new(),
         }
     }
 
-    pub fn append<T, S>(&self, opt: LogOperation<T>, serializer: &S) -> IOResult<()>
+    /// Appends the operation with the next number of `sequence`, which is taken in the order the
+    /// operations are written, and returns that number once the operation is committed
+    pub fn append<T, S>(
+        &self,
+        opt: LogOperation<T>,
+        serializer: &S,
+        sequence: &AtomicU64,
+    ) -> IOResult<u64>
     where
         T: MemTableRecord,
         S: SerializationEngine<LogOperation<T>>,
     {
-        let Ok(decoded) = serializer.serialize(opt) else {
+        let Ok(encoded) = serializer.serialize(opt) else {
             return Err(Error::new(ErrorKind::InvalidInput, "Failed to encode data"));
         };
+        self.append_encoded(&encoded, sequence)
+    }
+
+    /// Like `append`, for an operation that is already encoded
+    pub fn append_encoded(&self, encoded: &[u8], sequence: &AtomicU64) -> IOResult<u64> {
+        let mut group = self.group.lock().unwrap();
+        let batch = group.batch;
+        let number = sequence.fetch_add(1, Ordering::SeqCst) + 1;
+        group
+            .pending
+            .extend_from_slice(&Self::frame(number, encoded));
+
+        loop {
+            if let Some((failed, message)) = &group.failed
+                && *failed <= batch
+            {
+                return Err(Error::other(message.clone()));
+            }
+            if group.written >= batch {
+                return Ok(number);
+            }
+            if !group.writing {
+                break;
+            }
+            group = self.committed.wait(group).unwrap();
+        }
+
+        // Lead the batch, and let the records arriving in the meantime form the next one
+        group.writing = true;
+        group.batch += 1;
+        let records = mem::take(&mut group.pending);
+        drop(group);
+
+        let result = self.write(&records);
+
+        let mut group = self.group.lock().unwrap();
+        group.writing = false;
+        match &result {
+            Ok(()) => group.written = batch,
+            Err(err) => {
+                group.failed.get_or_insert((batch, format!("{:?}", err)));
+            }
+        }
+        self.committed.notify_all();
+        result.map(|_| number)
+    }
+
+    /// Frames an encoded operation as `[length: u32][crc32: u32][sequence: u64][operation]`, so
+    /// that a damaged record is detected when the log is replayed. The checksum covers the
+    /// sequence number and the operation
+    pub fn frame(sequence: u64, encoded: &[u8]) -> Vec<u8> {
+        let mut body = Vec::with_capacity(encoded.len() + 8);
+        body.extend_from_slice(&sequence.to_le_bytes());
+        body.extend_from_slice(encoded);
+
+        let mut record = Vec::with_capacity(body.len() + 8);
+        record.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+        record.extend_from_slice(&body);
+        record
+    }
+
+    fn write(&self, records: &[u8]) -> IOResult<()> {
         let mut file = self.file.lock().unwrap();
-        file.write_all(&decoded)?;
+        file.write_all(records)?;
         file.flush()?;
+        self.size.fetch_add(records.len() as u64, Ordering::SeqCst);
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        match self.sync_mode {
+            WalSyncMode::Always => file.sync_data(),
+            WalSyncMode::None | WalSyncMode::Interval => {
+                self.dirty.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+        }
+    }
+
+    /// Syncs the writes that weren't synced by their group commit. They are still unsynced when
+    /// it fails
+    pub fn sync(&self) -> IOResult<()> {
+        if self.dirty.swap(false, Ordering::SeqCst) {
+            let synced = self.file.lock().unwrap().sync_data();
+            if synced.is_err() {
+                self.dirty.store(true, Ordering::SeqCst);
+            }
+            return synced;
+        }
         Ok(())
     }
 
+    /// Whether some writes weren't synced yet
+    pub fn has_unsynced_writes(&self) -> bool {
+        self.dirty.load(Ordering::SeqCst)
+    }
+
+    /// The number of writes of the file, since the log was opened. Fewer than the appends when
+    /// they were committed in groups
+    pub fn writes(&self) -> u64 {
+        self.writes.load(Ordering::SeqCst)
+    }
+
+    /// The length of the log
+    pub fn size(&self) -> u64 {
+        self.size.load(Ordering::SeqCst)
+    }
+
     pub fn clear(&self) -> IOResult<()> {
         let mut file = self.file.lock().unwrap();
         file.set_len(0)?;
+        self.size.store(0, Ordering::SeqCst);
         file.seek(SeekFrom::Start(0))?;
         file.flush()?;
         Ok(())
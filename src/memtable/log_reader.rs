@@ -1,33 +1,213 @@
+use crate::config::WalRecoveryMode;
 use crate::memtable::MemTableRecord;
 use crate::serialization::{SerializationEngine, SerializationError};
 
 use super::operation::LogOperation;
 use std::fs::File;
-use std::io::{BufReader, Error, Read, Result as IOResult};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result as IOResult};
 
+/// @definition: What was dropped while replaying a log
+/// @field replayed: The number of records that were replayed
+/// @field skipped: The offset and length of the corrupted records that were skipped
+/// @field truncated_at: Where the log was cut, when its end was damaged
+/// @field dropped_bytes: The bytes of the skipped records and the cut tail
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct WalRecoveryReport {
+    pub replayed: usize,
+    pub skipped: Vec<(u64, u64)>,
+    pub truncated_at: Option<u64>,
+    pub dropped_bytes: u64,
+}
+
+impl WalRecoveryReport {
+    pub fn dropped_anything(&self) -> bool {
+        self.dropped_bytes > 0
+    }
+}
+
+/// @definition: Reads the records of a log, framed as
+/// `[length: u32][crc32: u32][sequence: u64][operation]`. Damaged records are handled according to
+/// the recovery mode, and what was dropped is reported.
+/// @field offset: Where the next record starts
+/// @field len: The length of the log when it was opened
+/// @field last_sequence: The sequence number of the last record read, or the one before the log
 pub struct MemTableLogReader<R: Read> {
     pub reader: BufReader<R>,
+    mode: WalRecoveryMode,
+    offset: u64,
+    len: u64,
+    last_sequence: u64,
+    report: WalRecoveryReport,
+}
+
+/// @definition: A record of the log, before its operation is decoded
+/// @variant Torn: A record cut short, from `start` to the end of the log
+/// @variant Record: A whole record of `len` bytes, `intact` when its checksum matches
+enum Frame {
+    End,
+    Torn {
+        start: u64,
+    },
+    Record {
+        start: u64,
+        len: u64,
+        sequence: u64,
+        payload: Vec<u8>,
+        intact: bool,
+    },
 }
 
 impl MemTableLogReader<File> {
-    pub fn open(file: File) -> IOResult<Self> {
+    /// Opens a log whose records come after the write numbered `last_sequence`
+    pub fn open(file: File, mode: WalRecoveryMode, last_sequence: u64) -> IOResult<Self> {
+        let len = file.metadata()?.len();
         Ok(Self {
             reader: BufReader::new(file),
+            mode,
+            offset: 0,
+            len,
+            last_sequence,
+            report: WalRecoveryReport::default(),
         })
     }
+
+    /// The highest sequence number of the intact records of a log, found without decoding their
+    /// operations. Nothing after a record cut short is read
+    pub fn last_sequence_of(file: File) -> IOResult<u64> {
+        let mut reader = Self::open(file, WalRecoveryMode::TruncateTail, 0)?;
+        while let Frame::Record {
+            sequence, intact, ..
+        } = reader.next_frame()?
+        {
+            if intact {
+                reader.last_sequence = reader.last_sequence.max(sequence);
+            }
+        }
+        Ok(reader.last_sequence)
+    }
 }
 
 impl<R: Read> MemTableLogReader<R> {
-    pub fn next_op<T, S>(&mut self, serializer: &S) -> IOResult<Option<LogOperation<T>>>
+    /// The next operation and its sequence number
+    pub fn next_op<T, S>(&mut self, serializer: &S) -> IOResult<Option<(u64, LogOperation<T>)>>
     where
         T: MemTableRecord,
         S: SerializationEngine<LogOperation<T>>,
     {
-        let result = serializer.deserialize(&mut self.reader);
-        match result {
-            Ok(op) => Ok(Some(op)),
-            Err(SerializationError::UnexpectedEOF) => Ok(None),
-            Err(_) => Err(Error::other("Failed to read next record")),
+        loop {
+            let (start, len, sequence, payload, intact) = match self.next_frame()? {
+                Frame::End => return Ok(None),
+                Frame::Torn { start } => return self.damaged_tail(start),
+                Frame::Record {
+                    start,
+                    len,
+                    sequence,
+                    payload,
+                    intact,
+                } => (start, len, sequence, payload, intact),
+            };
+            let op = if intact {
+                serializer.deserialize(&mut BufReader::new(payload.as_slice()))
+            } else {
+                Err(SerializationError::Unknown {
+                    message: "Checksum mismatch".to_string(),
+                })
+            };
+            match op {
+                Ok(op) => {
+                    self.report.replayed += 1;
+                    self.last_sequence = self.last_sequence.max(sequence);
+                    return Ok(Some((sequence, op)));
+                }
+                Err(_) => match self.mode {
+                    WalRecoveryMode::Fail => return Err(Self::corrupted(start)),
+                    WalRecoveryMode::TruncateTail => return self.damaged_tail(start),
+                    WalRecoveryMode::SkipCorrupted => {
+                        self.report.skipped.push((start, len));
+                        self.report.dropped_bytes += len;
+                    }
+                },
+            }
         }
     }
+
+    /// Reads the next record without decoding its operation
+    fn next_frame(&mut self) -> IOResult<Frame> {
+        let start = self.offset;
+        let remaining = self.len - start;
+        if remaining == 0 {
+            return Ok(Frame::End);
+        }
+
+        // A record cut short by a crash while it was being appended
+        let mut header = [0u8; 8];
+        if remaining < 8 || self.reader.read_exact(&mut header).is_err() {
+            return Ok(Frame::Torn { start });
+        }
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+        // The body starts with the sequence number
+        let len = len as u64 + 8;
+        if len > remaining - 8 {
+            return Ok(Frame::Torn { start });
+        }
+
+        let mut body = vec![0u8; len as usize];
+        self.reader.read_exact(&mut body)?;
+        self.offset += 8 + len;
+
+        let intact = crc32fast::hash(&body) == checksum;
+        let payload = body.split_off(8);
+        let sequence = u64::from_le_bytes(body.try_into().unwrap());
+        Ok(Frame::Record {
+            start,
+            len: 8 + len,
+            sequence,
+            payload,
+            intact,
+        })
+    }
+
+    pub fn report(&self) -> &WalRecoveryReport {
+        &self.report
+    }
+
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    /// Reads a log written before the records were framed. Returns None unless the whole log is
+    /// made of operations.
+    pub fn read_unframed<T, S>(mut reader: R, serializer: &S) -> Option<Vec<LogOperation<T>>>
+    where
+        T: MemTableRecord,
+        S: SerializationEngine<LogOperation<T>>,
+    {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).ok()?;
+        let mut reader = BufReader::new(bytes.as_slice());
+        let mut ops = vec![];
+        while !reader.fill_buf().ok()?.is_empty() {
+            ops.push(serializer.deserialize(&mut reader).ok()?);
+        }
+        (!ops.is_empty()).then_some(ops)
+    }
+
+    /// Everything from `offset` on is dropped, unless the mode doesn't allow losing anything
+    fn damaged_tail<T>(&mut self, offset: u64) -> IOResult<Option<T>> {
+        if self.mode == WalRecoveryMode::Fail {
+            return Err(Self::corrupted(offset));
+        }
+        self.report.truncated_at = Some(offset);
+        self.report.dropped_bytes += self.len - offset;
+        self.offset = self.len;
+        Ok(None)
+    }
+
+    fn corrupted(offset: u64) -> Error {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Corrupted log record at offset {}", offset),
+        )
+    }
 }
@@ -1,11 +1,17 @@
 mod log;
 mod log_reader;
 mod operation;
+mod rep;
+mod reservation;
+mod skiplist;
 mod table;
 mod value;
 
 pub use log::MemTableLog;
-pub use log_reader::MemTableLogReader;
+pub use log_reader::{MemTableLogReader, WalRecoveryReport};
 pub use operation::LogOperation;
+pub use rep::{HashRep, MemTableRep, RepIter, TreeRep, VectorRep};
+pub use reservation::MemoryReservation;
+pub use skiplist::SkipList;
 pub use table::MemTable;
 pub use value::MemTableRecord;
@@ -1,7 +1,7 @@
 use bincode::{Decode, Encode};
 
 use crate::memtable::MemTableRecord;
-#[derive(Encode, Decode, PartialEq, Debug)]
+#[derive(Encode, Decode, Clone, PartialEq, Debug)]
 pub enum LogOperation<T: MemTableRecord> {
     Insert { record: T },
     Delete { key: String },
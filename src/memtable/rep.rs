@@ -0,0 +1,299 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use rbtree::RBTree;
+
+use super::SkipList;
+
+/// The entries of a memtable in key order, with their value or None for a deleted key
+pub type RepIter<'a, T> = Box<dyn Iterator<Item = (String, Option<T>)> + 'a>;
+
+/// A value and the sequence number of the operation that wrote it
+type Versioned<T> = (u64, Option<T>);
+
+/// @definition: How the entries of a memtable are kept in memory. Every key has the value of its
+/// newest operation, so an operation applied after a newer one on the same key is ignored. Picked
+/// per engine by `Config::memtable_rep`
+pub trait MemTableRep<T>: Send + Sync {
+    /// Sets the value of the key, unless it already has the value of a newer operation. Returns
+    /// whether the key is new
+    fn insert(&self, key: String, sequence: u64, value: Option<T>) -> bool;
+
+    /// The newest value of the key, or None when the key isn't in the memtable
+    fn get(&self, key: &str) -> Option<Option<T>>;
+
+    /// The number of keys, the deleted ones included
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The entries in key order
+    fn iter(&self) -> RepIter<'_, T>;
+
+    /// Like `iter`, from the first key at or after `key`
+    fn seek<'a>(&'a self, key: &'a str) -> RepIter<'a, T>
+    where
+        T: 'a,
+    {
+        Box::new(
+            self.iter()
+                .skip_while(move |(found, _)| found.as_str() < key),
+        )
+    }
+
+    fn clear(&mut self);
+}
+
+/// Replaces the value unless it was written after `sequence`
+fn update<T>(existing: &mut Versioned<T>, sequence: u64, value: Option<T>) {
+    if existing.0 <= sequence {
+        *existing = (sequence, value);
+    }
+}
+
+impl<T: Clone + Send + Sync> MemTableRep<T> for SkipList<T> {
+    fn insert(&self, key: String, sequence: u64, value: Option<T>) -> bool {
+        SkipList::insert(self, key, sequence, value)
+    }
+
+    fn get(&self, key: &str) -> Option<Option<T>> {
+        SkipList::get(self, key).cloned()
+    }
+
+    fn len(&self) -> usize {
+        SkipList::len(self)
+    }
+
+    /// Walks the list as it is written, without copying it
+    fn iter(&self) -> RepIter<'_, T> {
+        Box::new(SkipList::iter(self).map(|(key, value)| (key.clone(), value.clone())))
+    }
+
+    fn seek<'a>(&'a self, key: &'a str) -> RepIter<'a, T>
+    where
+        T: 'a,
+    {
+        Box::new(SkipList::seek(self, key).map(|(key, value)| (key.clone(), value.clone())))
+    }
+
+    fn clear(&mut self) {
+        *self = SkipList::new();
+    }
+}
+
+/// @definition: A red-black tree behind a lock. Writers block the readers, and iterating copies the
+/// tree
+pub struct TreeRep<T> {
+    tree: RwLock<RBTree<String, Versioned<T>>>,
+}
+
+impl<T> Default for TreeRep<T> {
+    fn default() -> Self {
+        TreeRep {
+            tree: RwLock::new(RBTree::new()),
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync> MemTableRep<T> for TreeRep<T> {
+    fn insert(&self, key: String, sequence: u64, value: Option<T>) -> bool {
+        let mut tree = self.tree.write().unwrap();
+        if let Some(existing) = tree.get_mut(&key) {
+            update(existing, sequence, value);
+            return false;
+        }
+        tree.insert(key, (sequence, value));
+        true
+    }
+
+    fn get(&self, key: &str) -> Option<Option<T>> {
+        let tree = self.tree.read().unwrap();
+        tree.get(&key.to_string()).map(|(_, value)| value.clone())
+    }
+
+    fn len(&self) -> usize {
+        self.tree.read().unwrap().len()
+    }
+
+    fn iter(&self) -> RepIter<'_, T> {
+        let tree = self.tree.read().unwrap();
+        // Snapshot into Vec to avoid holding the lock during iteration
+        let entries: Vec<_> = tree
+            .iter()
+            .map(|(key, (_, value))| (key.clone(), value.clone()))
+            .collect();
+        Box::new(entries.into_iter())
+    }
+
+    fn clear(&mut self) {
+        self.tree.get_mut().unwrap().clear();
+    }
+}
+
+/// @definition: The entries in a vector sorted by key. Appending keys in order is cheap and lookups
+/// are binary searches, but a key inserted before others moves them. For bulk loads
+pub struct VectorRep<T> {
+    entries: RwLock<Vec<(String, Versioned<T>)>>,
+}
+
+impl<T> Default for VectorRep<T> {
+    fn default() -> Self {
+        VectorRep {
+            entries: RwLock::new(Vec::new()),
+        }
+    }
+}
+
+impl<T> VectorRep<T> {
+    fn position(entries: &[(String, Versioned<T>)], key: &str) -> Result<usize, usize> {
+        // Most bulk loads are in key order, so the key usually goes last
+        match entries.last() {
+            Some((last, _)) if last.as_str() < key => Err(entries.len()),
+            _ => entries.binary_search_by(|(found, _)| found.as_str().cmp(key)),
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync> MemTableRep<T> for VectorRep<T> {
+    fn insert(&self, key: String, sequence: u64, value: Option<T>) -> bool {
+        let mut entries = self.entries.write().unwrap();
+        match Self::position(&entries, &key) {
+            Ok(position) => {
+                update(&mut entries[position].1, sequence, value);
+                false
+            }
+            Err(position) => {
+                entries.insert(position, (key, (sequence, value)));
+                true
+            }
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<Option<T>> {
+        let entries = self.entries.read().unwrap();
+        let position = Self::position(&entries, key).ok()?;
+        Some(entries[position].1.1.clone())
+    }
+
+    fn len(&self) -> usize {
+        self.entries.read().unwrap().len()
+    }
+
+    fn iter(&self) -> RepIter<'_, T> {
+        self.seek("")
+    }
+
+    fn seek<'a>(&'a self, key: &'a str) -> RepIter<'a, T>
+    where
+        T: 'a,
+    {
+        let entries = self.entries.read().unwrap();
+        let start = Self::position(&entries, key).unwrap_or_else(|position| position);
+        let entries: Vec<_> = entries[start..]
+            .iter()
+            .map(|(key, (_, value))| (key.clone(), value.clone()))
+            .collect();
+        Box::new(entries.into_iter())
+    }
+
+    fn clear(&mut self) {
+        self.entries.get_mut().unwrap().clear();
+    }
+}
+
+/// @definition: The entries in a hash map. Lookups don't depend on the number of keys, but
+/// iterating sorts the keys. For data mostly read by key
+pub struct HashRep<T> {
+    entries: RwLock<HashMap<String, Versioned<T>>>,
+}
+
+impl<T> Default for HashRep<T> {
+    fn default() -> Self {
+        HashRep {
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync> MemTableRep<T> for HashRep<T> {
+    fn insert(&self, key: String, sequence: u64, value: Option<T>) -> bool {
+        let mut entries = self.entries.write().unwrap();
+        if let Some(existing) = entries.get_mut(&key) {
+            update(existing, sequence, value);
+            return false;
+        }
+        entries.insert(key, (sequence, value));
+        true
+    }
+
+    fn get(&self, key: &str) -> Option<Option<T>> {
+        let entries = self.entries.read().unwrap();
+        entries.get(key).map(|(_, value)| value.clone())
+    }
+
+    fn len(&self) -> usize {
+        self.entries.read().unwrap().len()
+    }
+
+    fn iter(&self) -> RepIter<'_, T> {
+        let entries = self.entries.read().unwrap();
+        let mut sorted: Vec<_> = entries
+            .iter()
+            .map(|(key, (_, value))| (key.clone(), value.clone()))
+            .collect();
+        sorted.sort_by(|a, b| a.0.cmp(&b.0));
+        Box::new(sorted.into_iter())
+    }
+
+    fn clear(&mut self) {
+        self.entries.get_mut().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, MemTableRepKind};
+
+    #[test]
+    fn every_rep_keeps_the_newest_value_in_order() {
+        for kind in [
+            MemTableRepKind::SkipList,
+            MemTableRepKind::Tree,
+            MemTableRepKind::Vector,
+            MemTableRepKind::Hash,
+        ] {
+            let mut rep = Config {
+                memtable_rep: kind,
+                ..Config::default()
+            }
+            .memtable_rep::<u32>();
+            assert!(rep.insert("c".into(), 1, Some(1)));
+            assert!(rep.insert("a".into(), 2, Some(2)));
+            assert!(rep.insert("b".into(), 3, Some(3)));
+            assert!(!rep.insert("c".into(), 5, None));
+            // Replayed after the newer deletion
+            assert!(!rep.insert("c".into(), 4, Some(4)));
+
+            assert_eq!(rep.len(), 3, "{kind:?}");
+            assert_eq!(rep.get("c"), Some(None), "{kind:?}");
+            assert_eq!(rep.get("b"), Some(Some(3)), "{kind:?}");
+            assert_eq!(rep.get("d"), None, "{kind:?}");
+            let entries: Vec<_> = rep.iter().collect();
+            assert_eq!(
+                entries,
+                vec![
+                    ("a".to_string(), Some(2)),
+                    ("b".to_string(), Some(3)),
+                    ("c".to_string(), None)
+                ],
+                "{kind:?}"
+            );
+            let keys: Vec<_> = rep.seek("a0").map(|(key, _)| key).collect();
+            assert_eq!(keys, vec!["b", "c"], "{kind:?}");
+
+            rep.clear();
+            assert!(rep.is_empty(), "{kind:?}");
+        }
+    }
+}
@@ -0,0 +1,8 @@
+/// @definition: Accounts for the memory taken by memtables, like a `WriteBufferManager` sharing a
+/// budget across engines. A memtable reserves the bytes it grows by, and releases them all once it
+/// is cleared or dropped
+pub trait MemoryReservation: Send + Sync {
+    fn reserve(&self, bytes: u64);
+
+    fn release(&self, bytes: u64);
+}
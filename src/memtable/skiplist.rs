@@ -0,0 +1,378 @@
+use std::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    mem::MaybeUninit,
+    ptr,
+    sync::{
+        Mutex,
+        atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+    },
+};
+
+/// The most levels a node can be linked in. With a level kept for a quarter of the nodes, it
+/// covers memtables of millions of keys
+const MAX_HEIGHT: usize = 12;
+
+/// The number of slots in an arena chunk
+const CHUNK_SIZE: usize = 1024;
+
+struct Chunk<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    used: AtomicUsize,
+}
+
+impl<T> Chunk<T> {
+    fn new() -> Box<Chunk<T>> {
+        Box::new(Chunk {
+            slots: (0..CHUNK_SIZE)
+                .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+                .collect(),
+            used: AtomicUsize::new(0),
+        })
+    }
+}
+
+/// @definition: Allocates values in chunks, and frees them all at once when it is dropped. The
+/// values never move, so a reference to one lives as long as the arena.
+/// @field current: The chunk the values are allocated in. Taking a slot is an atomic increment,
+/// the lock is only taken to add a chunk once it is full
+/// @field chunks: Every chunk, the full ones included. They are boxed so that `current` stays valid
+/// when the vector grows
+struct Arena<T> {
+    current: AtomicPtr<Chunk<T>>,
+    #[allow(clippy::vec_box)]
+    chunks: Mutex<Vec<Box<Chunk<T>>>>,
+}
+
+impl<T> Arena<T> {
+    fn new() -> Arena<T> {
+        let chunk = Chunk::new();
+        Arena {
+            current: AtomicPtr::new(&*chunk as *const Chunk<T> as *mut Chunk<T>),
+            chunks: Mutex::new(vec![chunk]),
+        }
+    }
+
+    fn alloc(&self, value: T) -> &T {
+        loop {
+            let current = self.current.load(Ordering::Acquire);
+            // SAFETY: the chunks are only freed with the arena
+            let chunk = unsafe { &*current };
+            let slot = chunk.used.fetch_add(1, Ordering::Relaxed);
+            if let Some(cell) = chunk.slots.get(slot) {
+                // SAFETY: the increment handed the slot to this call only
+                return unsafe { (*cell.get()).write(value) };
+            }
+
+            let mut chunks = self.chunks.lock().unwrap();
+            if self.current.load(Ordering::Acquire) == current {
+                let chunk = Chunk::new();
+                self.current.store(
+                    &*chunk as *const Chunk<T> as *mut Chunk<T>,
+                    Ordering::Release,
+                );
+                chunks.push(chunk);
+            }
+        }
+    }
+}
+
+impl<T> Drop for Arena<T> {
+    fn drop(&mut self) {
+        for chunk in self.chunks.get_mut().unwrap().iter_mut() {
+            // Allocations that found the chunk full moved `used` past its end
+            let used = (*chunk.used.get_mut()).min(CHUNK_SIZE);
+            for cell in chunk.slots[..used].iter_mut() {
+                // SAFETY: the first `used` slots were written, and nothing refers to them anymore
+                unsafe { cell.get_mut().assume_init_drop() };
+            }
+        }
+    }
+}
+
+/// @definition: A value and the sequence number of the operation that wrote it
+type Versioned<V> = (u64, Option<V>);
+
+/// @field value: The newest value of the key. Updates swap it for another one of the arena, so
+/// readers holding the previous one keep a valid reference
+/// @field next: The next node of every level the node is linked in, the upper ones staying null
+struct Node<V> {
+    key: String,
+    value: AtomicPtr<Versioned<V>>,
+    next: [AtomicPtr<Node<V>>; MAX_HEIGHT],
+}
+
+impl<V> Node<V> {
+    fn next(&self, level: usize) -> Option<&Node<V>> {
+        // SAFETY: the nodes are only freed with the arena of the list
+        unsafe { self.next[level].load(Ordering::Acquire).as_ref() }
+    }
+
+    fn value(&self) -> &Option<V> {
+        // SAFETY: linked nodes always have a value, freed with the arena of the list
+        unsafe { &(*self.value.load(Ordering::Acquire)).1 }
+    }
+}
+
+/// @definition: A sorted map of the keys to their newest value that is written and read
+/// concurrently without locks. Inserting links a node with compare-and-swaps, from the bottom
+/// level up, and readers follow the links without ever waiting for a writer. Nodes and values are
+/// allocated in arenas and only freed with the list, so the references handed out stay valid.
+/// @field head: The node before the first key, linked in every level
+/// @field len: The number of keys
+/// @field seed: The state of the generator of node heights
+pub struct SkipList<V> {
+    head: Box<Node<V>>,
+    len: AtomicUsize,
+    seed: AtomicU64,
+    nodes: Arena<Node<V>>,
+    values: Arena<Versioned<V>>,
+}
+
+// SAFETY: the raw pointers only refer to the arenas of the list, which hand out shared references
+// to their values
+unsafe impl<V: Send + Sync> Send for SkipList<V> {}
+unsafe impl<V: Send + Sync> Sync for SkipList<V> {}
+
+impl<V> Default for SkipList<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> SkipList<V> {
+    pub fn new() -> SkipList<V> {
+        SkipList {
+            head: Box::new(Node {
+                key: String::new(),
+                value: AtomicPtr::new(ptr::null_mut()),
+                next: Default::default(),
+            }),
+            len: AtomicUsize::new(0),
+            seed: AtomicU64::new(0),
+            nodes: Arena::new(),
+            values: Arena::new(),
+        }
+    }
+
+    /// Sets the value of the key, unless it already has the value of a newer operation. Returns
+    /// whether the key is new
+    pub fn insert(&self, key: String, sequence: u64, value: Option<V>) -> bool {
+        let value = self.values.alloc((sequence, value)) as *const Versioned<V> as *mut _;
+        let mut preds = [&*self.head; MAX_HEIGHT];
+        let mut succs = [ptr::null_mut(); MAX_HEIGHT];
+        if let Some(existing) = self.find(&key, &mut preds, &mut succs) {
+            Self::replace(existing, value);
+            return false;
+        }
+
+        let height = self.random_height();
+        let node = self.nodes.alloc(Node {
+            key,
+            value: AtomicPtr::new(value),
+            next: Default::default(),
+        });
+        let node_ptr = node as *const Node<V> as *mut Node<V>;
+
+        // Once linked in the bottom level, the key is in the list
+        loop {
+            node.next[0].store(succs[0], Ordering::Relaxed);
+            if preds[0].next[0]
+                .compare_exchange(succs[0], node_ptr, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                break;
+            }
+            // The node is left unlinked in the arena when another writer added the key first
+            if let Some(existing) = self.find(&node.key, &mut preds, &mut succs) {
+                Self::replace(existing, value);
+                return false;
+            }
+        }
+        self.len.fetch_add(1, Ordering::SeqCst);
+
+        // The upper levels only make the searches shorter
+        for level in 1..height {
+            loop {
+                node.next[level].store(succs[level], Ordering::Release);
+                if preds[level].next[level]
+                    .compare_exchange(succs[level], node_ptr, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+                {
+                    break;
+                }
+                self.find(&node.key, &mut preds, &mut succs);
+            }
+        }
+        true
+    }
+
+    /// The newest value of the key, or None when the key isn't in the list
+    pub fn get(&self, key: &str) -> Option<&Option<V>> {
+        self.seek(key)
+            .next()
+            .filter(|(found, _)| found.as_str() == key)
+            .map(|(_, value)| value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The keys and their value in order. Keys inserted while iterating may or may not be seen
+    pub fn iter(&self) -> Iter<'_, V> {
+        Iter {
+            node: self.head.next(0),
+            _list: PhantomData,
+        }
+    }
+
+    /// Like `iter`, from the first key at or after `key`
+    pub fn seek(&self, key: &str) -> Iter<'_, V> {
+        let mut pred = &*self.head;
+        let mut node = None;
+        for level in (0..MAX_HEIGHT).rev() {
+            node = pred.next(level);
+            while let Some(next) = node.filter(|next| next.key.as_str() < key) {
+                pred = next;
+                node = next.next(level);
+            }
+        }
+        Iter {
+            node,
+            _list: PhantomData,
+        }
+    }
+
+    /// Fills the last node before `key` and the one after it in every level, and returns the node
+    /// of the key when it is in the list
+    fn find<'a>(
+        &'a self,
+        key: &str,
+        preds: &mut [&'a Node<V>; MAX_HEIGHT],
+        succs: &mut [*mut Node<V>; MAX_HEIGHT],
+    ) -> Option<&'a Node<V>> {
+        let mut pred = &*self.head;
+        for level in (0..MAX_HEIGHT).rev() {
+            // The successor is the link that was compared. Loading it again could return a node
+            // with a smaller key, linked in the meantime
+            let succ = loop {
+                let next = pred.next[level].load(Ordering::Acquire);
+                // SAFETY: the nodes are only freed with the arena of the list
+                match unsafe { next.as_ref() } {
+                    Some(node) if node.key.as_str() < key => pred = node,
+                    _ => break next,
+                }
+            };
+            preds[level] = pred;
+            succs[level] = succ;
+        }
+        // SAFETY: as above
+        unsafe { succs[0].as_ref() }.filter(|node| node.key == key)
+    }
+
+    /// Swaps the value of the node, unless it holds one written after it
+    fn replace(node: &Node<V>, value: *mut Versioned<V>) {
+        // SAFETY: the values are only freed with the arena of the list
+        let sequence = unsafe { (*value).0 };
+        let mut current = node.value.load(Ordering::Acquire);
+        while unsafe { (*current).0 } <= sequence {
+            match node
+                .value
+                .compare_exchange(current, value, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// A height where every level is kept with a probability of 1/4, from a splitmix64 sequence
+    fn random_height(&self) -> usize {
+        let mut z = self
+            .seed
+            .fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed)
+            .wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (1 + z.trailing_zeros() as usize / 2).min(MAX_HEIGHT)
+    }
+}
+
+/// @definition: Walks the bottom level of a `SkipList`, without copying or locking it
+pub struct Iter<'a, V> {
+    node: Option<&'a Node<V>>,
+    _list: PhantomData<&'a SkipList<V>>,
+}
+
+impl<'a, V> Iterator for Iter<'a, V> {
+    type Item = (&'a String, &'a Option<V>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.node?;
+        self.node = node.next(0);
+        Some((&node.key, node.value()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::SkipList;
+
+    #[test]
+    fn newest_sequence_wins() {
+        let list = SkipList::new();
+        assert!(list.insert("b".into(), 1, Some(1)));
+        assert!(list.insert("a".into(), 2, Some(2)));
+        assert!(!list.insert("b".into(), 4, None));
+        assert!(!list.insert("b".into(), 3, Some(3)));
+
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.get("b"), Some(&None));
+        assert_eq!(list.get("c"), None);
+        let keys: Vec<_> = list.seek("a0").map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, vec!["b"]);
+    }
+
+    #[test]
+    fn concurrent_inserts_and_reads() {
+        let list = Arc::new(SkipList::new());
+        let writers: Vec<_> = (0..8u64)
+            .map(|t| {
+                let list = Arc::clone(&list);
+                thread::spawn(move || {
+                    for i in 0..2000u64 {
+                        // The first keys of every thread are written twice
+                        let key = format!("{:05}", (i * 8 + t) % 12000);
+                        list.insert(key, t * 2000 + i + 1, Some(i));
+                    }
+                })
+            })
+            .collect();
+        let reader = {
+            let list = Arc::clone(&list);
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    let keys: Vec<_> = list.iter().map(|(key, _)| key.clone()).collect();
+                    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+                }
+            })
+        };
+        writers
+            .into_iter()
+            .for_each(|writer| writer.join().unwrap());
+        reader.join().unwrap();
+
+        assert_eq!(list.len(), 12000);
+        assert_eq!(list.iter().count(), 12000);
+        assert_eq!(list.get("00004"), Some(&Some(1500)));
+        assert_eq!(list.get("04004"), Some(&Some(500)));
+    }
+}
@@ -1,118 +1,378 @@
-use rbtree::RBTree;
-use std::io::Result as IOResult;
-use std::sync::RwLock;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Result as IOResult, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{fs::OpenOptions, sync::Arc};
 
-use crate::{memtable::MemTableRecord, serialization::SerializationEngine};
-
-use super::{LogOperation, MemTableLog, MemTableLogReader};
-
-pub struct MemTable<'a, T, S>
+use crate::{config::Config, memtable::MemTableRecord, serialization::SerializationEngine};
+
+use super::{
+    LogOperation, MemTableLog, MemTableLogReader, MemTableRep, MemoryReservation, RepIter,
+    WalRecoveryReport,
+};
+
+/// @field entries: The newest value of every key, or None for a deleted key, kept as
+/// `Config::memtable_rep` says
+/// @field size: The bytes of the keys and of the encoded operations written to the memtable, see
+/// `size`
+/// @field write_buffer: Where the size is reserved from, until the memtable is dropped
+/// @field min_timestamp: The write time of the oldest operation, in milliseconds since the epoch.
+/// Operations replayed from the log take the time the log was last modified
+/// @field max_timestamp: The write time of the newest operation
+/// @field operations: The number of operations in the log, including the replayed ones
+/// @field unlogged: The number of operations that were applied without being logged
+/// @field recovery: What was dropped from the log when it was replayed
+/// @field sequence: The last sequence number handed out, shared by the memtables of an engine so
+/// that the numbers keep increasing across logs
+/// @field last_sequence: The sequence number of the newest operation in the log
+pub struct MemTable<T, S>
 where
     T: MemTableRecord,
     S: SerializationEngine<LogOperation<T>>,
 {
-    pub tree: Arc<RwLock<RBTree<String, Option<T>>>>,
+    entries: Box<dyn MemTableRep<T>>,
+    size: AtomicU64,
+    write_buffer: Option<Arc<dyn MemoryReservation>>,
     pub log: MemTableLog,
-    pub serializer: &'a S,
+    pub serializer: Arc<S>,
+    min_timestamp: AtomicU64,
+    max_timestamp: AtomicU64,
+    operations: AtomicU64,
+    unlogged: AtomicU64,
+    recovery: WalRecoveryReport,
+    sequence: Arc<AtomicU64>,
+    last_sequence: AtomicU64,
 }
 
-impl<'a, T, S> MemTable<'a, T, S>
+impl<T, S> MemTable<T, S>
 where
     T: MemTableRecord,
     S: SerializationEngine<LogOperation<T>>,
 {
-    pub fn open_or_build(path: &str, serializer: &'a S) -> IOResult<Self> {
+    pub fn open_or_build(path: &str, serializer: Arc<S>) -> IOResult<Self> {
+        Self::open_or_build_with(
+            path,
+            serializer,
+            &Config::default(),
+            Arc::new(AtomicU64::new(0)),
+        )
+    }
+
+    /// Opens the log at `path` and replays it, creating it when it doesn't exist. Damaged records
+    /// are handled according to `config.wal_recovery`, and a damaged tail is cut off the log so
+    /// that the next appends follow the last good record. A log written before the records were
+    /// framed is replayed as a whole and rewritten framed. The operations logged without their
+    /// sequence number are numbered after `sequence`, which is moved past the replayed operations.
+    pub fn open_or_build_with(
+        path: &str,
+        serializer: Arc<S>,
+        config: &Config,
+        sequence: Arc<AtomicU64>,
+    ) -> IOResult<Self> {
         let mut options = OpenOptions::new();
         options.create(true).append(true).read(true);
 
-        let mut reader = MemTableLogReader::open(options.open(path)?)?;
-        let mut tree = RBTree::<String, Option<T>>::new();
-
-        while let Some(op) = reader.next_op(serializer)? {
-            match op {
-                LogOperation::Insert { record } => {
-                    let key = record.get_key();
-                    tree.remove(&key);
-                    tree.insert(key, Some(record));
-                }
-                LogOperation::Delete { key } => {
-                    tree.remove(&key);
-                    tree.insert(key, None);
+        let file = options.open(path)?;
+        let modified = file.metadata()?.modified()?;
+        let first_sequence = sequence.load(Ordering::SeqCst);
+        let mut reader = MemTableLogReader::open(file, config.wal_recovery, first_sequence)?;
+        let entries = config.memtable_rep();
+        let mut size = 0;
+
+        let replayed = loop {
+            match reader.next_op(serializer.as_ref()) {
+                Ok(Some((sequence, op))) => {
+                    size += Self::replay(entries.as_ref(), sequence, op, serializer.as_ref())
                 }
+                Ok(None) => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
+        let mut recovery = reader.report().clone();
+        let mut last_sequence = reader.last_sequence();
+
+        if recovery.replayed == 0
+            && (replayed.is_err() || recovery.dropped_anything())
+            && let Some(ops) =
+                MemTableLogReader::read_unframed(options.open(path)?, serializer.as_ref())
+        {
+            recovery = WalRecoveryReport {
+                replayed: ops.len(),
+                ..Default::default()
+            };
+            Self::rewrite_framed(path, first_sequence, &ops, serializer.as_ref())?;
+            last_sequence = first_sequence + ops.len() as u64;
+            for (sequence, op) in (first_sequence + 1..).zip(ops) {
+                size += Self::replay(entries.as_ref(), sequence, op, serializer.as_ref());
+            }
+        } else {
+            replayed?;
+            if let Some(offset) = recovery.truncated_at {
+                let file = OpenOptions::new().write(true).open(path)?;
+                file.set_len(offset)?;
+                file.sync_data()?;
             }
         }
 
-        let tree = Arc::new(RwLock::new(tree));
-        Ok(MemTable {
-            tree,
-            log: MemTableLog::new(options.open(path)?),
+        let memtable = MemTable {
+            entries,
+            size: AtomicU64::new(size),
+            write_buffer: config
+                .write_buffer_manager
+                .clone()
+                .map(|manager| manager as Arc<dyn MemoryReservation>),
+            log: MemTableLog::new(options.open(path)?, config.wal_sync),
             serializer,
-        })
+            min_timestamp: AtomicU64::new(u64::MAX),
+            max_timestamp: AtomicU64::new(0),
+            operations: AtomicU64::new(recovery.replayed as u64),
+            unlogged: AtomicU64::new(0),
+            recovery,
+            sequence,
+            last_sequence: AtomicU64::new(last_sequence),
+        };
+        memtable.sequence.fetch_max(last_sequence, Ordering::SeqCst);
+        if let Some(manager) = &memtable.write_buffer {
+            manager.reserve(size);
+        }
+        if !memtable.is_empty() {
+            memtable.touch(Self::millis(modified));
+        }
+        Ok(memtable)
     }
 
-    pub fn insert(&self, record: T) -> IOResult<()> {
-        let key = record.get_key();
-        self.log.append(
-            LogOperation::Insert {
-                record: record.clone(),
-            },
-            self.serializer,
-        )?;
-        let mut tree = self.tree.write().unwrap();
-        tree.remove(&key); // remove any previous values
-        tree.insert(key, Some(record));
-        Ok(())
+    /// Applies the operation, and returns the bytes it adds to the memtable: the encoded
+    /// operation, and the key when it is new
+    fn apply(
+        entries: &dyn MemTableRep<T>,
+        sequence: u64,
+        op: LogOperation<T>,
+        encoded: usize,
+    ) -> u64 {
+        let (key, value) = match op {
+            LogOperation::Insert { record } => (record.get_key(), Some(record)),
+            LogOperation::Delete { key } => (key, None),
+        };
+        let key_size = key.len();
+        let added = if entries.insert(key, sequence, value) {
+            encoded + key_size
+        } else {
+            encoded
+        };
+        added as u64
     }
 
-    pub fn delete(&self, key: String) -> IOResult<()> {
-        let mut tree = self.tree.write().unwrap();
-        tree.remove(&key); // remove any previous values
-        tree.insert(key.clone(), None);
-        self.log
-            .append(LogOperation::<T>::Delete { key }, self.serializer)?;
-        Ok(())
+    /// Applies an operation read from the log. Its size is the one it had when it was written
+    fn replay(
+        entries: &dyn MemTableRep<T>,
+        sequence: u64,
+        op: LogOperation<T>,
+        serializer: &S,
+    ) -> u64 {
+        let encoded = serializer
+            .serialize(op.clone())
+            .map_or(0, |encoded| encoded.len());
+        Self::apply(entries, sequence, op, encoded)
+    }
+
+    /// Replaces the log with its operations framed and numbered after `last_sequence`, through a
+    /// temporary file so that a crash leaves either the old log or the new one
+    fn rewrite_framed(
+        path: &str,
+        last_sequence: u64,
+        ops: &[LogOperation<T>],
+        serializer: &S,
+    ) -> IOResult<()> {
+        let temp_path = format!("{}.tmp", path);
+        let mut temp = File::create(&temp_path)?;
+        for (sequence, op) in (last_sequence + 1..).zip(ops) {
+            let Ok(encoded) = serializer.serialize(op.clone()) else {
+                return Err(Error::new(ErrorKind::InvalidInput, "Failed to encode data"));
+            };
+            temp.write_all(&MemTableLog::frame(sequence, &encoded))?;
+        }
+        temp.sync_all()?;
+        fs::rename(&temp_path, path)
+    }
+
+    /// Logs and applies the insertion, and returns its sequence number
+    pub fn insert(&self, record: T) -> IOResult<u64> {
+        self.insert_with(record, true)
+    }
+
+    /// Applies the insertion, logging it unless `logged` is false, and returns its sequence
+    /// number. An unlogged insertion is lost if the process stops before the memtable is flushed,
+    /// and takes the number of the last write before it, see `write`
+    pub fn insert_with(&self, record: T, logged: bool) -> IOResult<u64> {
+        self.write(LogOperation::Insert { record }, logged)
+    }
+
+    /// Logs and applies the deletion, and returns its sequence number
+    pub fn delete(&self, key: String) -> IOResult<u64> {
+        self.delete_with(key, true)
+    }
+
+    /// Applies the deletion, logging it unless `logged` is false, and returns its sequence number
+    pub fn delete_with(&self, key: String, logged: bool) -> IOResult<u64> {
+        self.write(LogOperation::Delete { key }, logged)
+    }
+
+    /// Encodes the operation, logs it unless `logged` is false, and applies it. Unlogged
+    /// operations are encoded too, to be sized. They don't get a number of their own, so that the
+    /// numbers in the log follow each other and readers of the log see no gap: they share the one
+    /// of the last write, which they still replace in the memtable
+    fn write(&self, op: LogOperation<T>, logged: bool) -> IOResult<u64> {
+        let Ok(encoded) = self.serializer.serialize(op.clone()) else {
+            return Err(Error::new(ErrorKind::InvalidInput, "Failed to encode data"));
+        };
+        let sequence = if logged {
+            self.log.append_encoded(&encoded, &self.sequence)?
+        } else {
+            self.sequence.load(Ordering::SeqCst)
+        };
+        let added = Self::apply(self.entries.as_ref(), sequence, op, encoded.len());
+        self.size.fetch_add(added, Ordering::SeqCst);
+        if let Some(manager) = &self.write_buffer {
+            manager.reserve(added);
+        }
+        self.applied(sequence, logged);
+        Ok(sequence)
+    }
+
+    fn applied(&self, sequence: u64, logged: bool) {
+        let counter = if logged {
+            &self.operations
+        } else {
+            &self.unlogged
+        };
+        counter.fetch_add(1, Ordering::SeqCst);
+        self.last_sequence.fetch_max(sequence, Ordering::SeqCst);
+        self.touch(Self::millis(SystemTime::now()));
+    }
+
+    pub fn get(&self, key: &str) -> Option<Option<T>> {
+        self.entries.get(key)
     }
 
-    pub fn get(&self, key: &String) -> Option<Option<T>> {
-        let tree = self.tree.read().unwrap();
-        tree.get(key).cloned()
+    /// Whether some key is in the range `[min, max]`
+    pub fn overlaps(&self, min: &str, max: &str) -> bool {
+        self.entries
+            .seek(min)
+            .next()
+            .is_some_and(|(key, _)| key.as_str() <= max)
     }
 
     pub fn len(&self) -> usize {
-        let tree = self.tree.read().unwrap();
-        tree.len()
+        self.entries.len()
     }
 
     pub fn is_empty(&self) -> bool {
-        let tree = self.tree.read().unwrap();
-        tree.is_empty()
+        self.entries.is_empty()
+    }
+
+    /// The bytes written to the memtable: every key once, and every operation as it is encoded in
+    /// the log. Overwritten values are counted too, since the skiplist keeps them until the
+    /// memtable is dropped
+    pub fn size(&self) -> u64 {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    /// The length of the log, without the unlogged operations
+    pub fn log_size(&self) -> u64 {
+        self.log.size()
     }
 
-    pub fn clear(&self) -> IOResult<()> {
+    /// How long ago the oldest operation was written, in milliseconds
+    pub fn age(&self) -> Option<u64> {
+        let (oldest, _) = self.timestamps()?;
+        Some(Self::millis(SystemTime::now()).saturating_sub(oldest))
+    }
+
+    pub fn clear(&mut self) -> IOResult<()> {
         self.log.clear()?;
-        self.tree.write().unwrap().clear();
+        self.entries.clear();
+        let size = self.size.swap(0, Ordering::SeqCst);
+        if let Some(manager) = &self.write_buffer {
+            manager.release(size);
+        }
+        self.min_timestamp.store(u64::MAX, Ordering::SeqCst);
+        self.max_timestamp.store(0, Ordering::SeqCst);
+        self.operations.store(0, Ordering::SeqCst);
+        self.unlogged.store(0, Ordering::SeqCst);
         Ok(())
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (String, Option<T>)> {
-        let tree = self.tree.read().unwrap();
-        // Snapshot into Vec to avoid holding the lock during iteration
-        tree.iter()
-            .map(|(k, v)| (k.clone(), v.clone()))
-            .collect::<Vec<_>>()
-            .into_iter()
+    /// The write times of the oldest and newest operations, or None when nothing was written
+    pub fn timestamps(&self) -> Option<(u64, u64)> {
+        let min = self.min_timestamp.load(Ordering::SeqCst);
+        let max = self.max_timestamp.load(Ordering::SeqCst);
+        (min <= max).then_some((min, max))
+    }
+
+    pub fn operations(&self) -> u64 {
+        self.operations.load(Ordering::SeqCst)
+    }
+
+    /// Whether some operations were applied without being logged
+    pub fn has_unlogged_data(&self) -> bool {
+        self.unlogged.load(Ordering::SeqCst) > 0
+    }
+
+    /// The sequence number of the newest operation in the log, or the last one before the log when
+    /// it is empty
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence.load(Ordering::SeqCst)
+    }
+
+    /// What was dropped from the log when it was replayed
+    pub fn recovery_report(&self) -> &WalRecoveryReport {
+        &self.recovery
+    }
+
+    fn touch(&self, timestamp: u64) {
+        self.min_timestamp.fetch_min(timestamp, Ordering::SeqCst);
+        self.max_timestamp.fetch_max(timestamp, Ordering::SeqCst);
+    }
+
+    fn millis(time: SystemTime) -> u64 {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+
+    /// The keys in order, with their value or None when they were deleted. The writes made while
+    /// iterating may or may not be seen
+    pub fn iter(&self) -> RepIter<'_, T> {
+        self.entries.iter()
+    }
+}
+
+impl<T, S> Drop for MemTable<T, S>
+where
+    T: MemTableRecord,
+    S: SerializationEngine<LogOperation<T>>,
+{
+    fn drop(&mut self) {
+        if let Some(manager) = &self.write_buffer {
+            manager.release(self.size());
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{
+        sync::{Arc, atomic::AtomicU64},
+        thread,
+    };
+
     use bincode::{Decode, Encode};
     use tempfile::NamedTempFile;
 
     use crate::{
-        memtable::{MemTable, MemTableRecord},
-        serialization::BinarySerializationEngine,
+        config::{Config, WalRecoveryMode, WalSyncMode},
+        memtable::{LogOperation, MemTable, MemTableRecord},
+        serialization::{BinarySerializationEngine, SerializationEngine},
     };
 
     #[derive(Encode, Decode, Clone, Debug, PartialEq)]
@@ -130,27 +390,27 @@ mod tests {
         file.path().to_str().unwrap().to_string()
     }
 
-    fn create_memtable<'a>(
+    fn create_memtable(
         path: &str,
-        serializer: &'a BinarySerializationEngine,
-    ) -> MemTable<'a, Dummy, BinarySerializationEngine> {
-        MemTable::<Dummy, BinarySerializationEngine>::open_or_build(path, serializer)
+        serializer: &Arc<BinarySerializationEngine>,
+    ) -> MemTable<Dummy, BinarySerializationEngine> {
+        MemTable::<Dummy, BinarySerializationEngine>::open_or_build(path, Arc::clone(serializer))
             .expect("Failed to create MemTable")
     }
 
     #[test]
     fn non_existing_folder_should_fail() {
-        let serializer = BinarySerializationEngine {};
+        let serializer = Arc::new(BinarySerializationEngine {});
         let result = MemTable::<Dummy, BinarySerializationEngine>::open_or_build(
             "/invalid/path/to/file.log",
-            &serializer,
+            serializer,
         );
         assert!(result.is_err());
     }
 
     #[test]
     fn no_repetitive_items() {
-        let ser = BinarySerializationEngine;
+        let ser = Arc::new(BinarySerializationEngine);
         let path = new_temp_path();
         let table = create_memtable(&path, &ser);
 
@@ -158,28 +418,25 @@ mod tests {
         table.insert(Dummy("hello".to_string(), 20)).unwrap();
 
         assert_eq!(table.len(), 1);
-        assert_eq!(
-            table.get(&"hello".to_string()).unwrap().as_ref().unwrap().1,
-            20
-        );
+        assert_eq!(table.get("hello").unwrap().as_ref().unwrap().1, 20);
     }
 
     #[test]
     fn roundtrip_get() {
-        let ser = BinarySerializationEngine;
+        let ser = Arc::new(BinarySerializationEngine);
         let path = new_temp_path();
         let table = create_memtable(&path, &ser);
 
         table.insert(Dummy("hello".to_string(), 10)).unwrap();
 
-        let value = table.get(&"hello".to_string());
+        let value = table.get("hello");
         assert!(value.is_some());
         assert_eq!(value.unwrap().as_ref().unwrap().1, 10);
     }
 
     #[test]
     fn deletion_marks_none() {
-        let ser = BinarySerializationEngine;
+        let ser = Arc::new(BinarySerializationEngine);
         let path = new_temp_path();
         let table = create_memtable(&path, &ser);
 
@@ -190,13 +447,39 @@ mod tests {
 
         // Still present in tree, but value is None
         assert_eq!(table.len(), 1);
-        assert!(table.get(&"hello".to_string()).is_some());
-        assert!(table.get(&"hello".to_string()).unwrap().is_none());
+        assert!(table.get("hello").is_some());
+        assert!(table.get("hello").unwrap().is_none());
+    }
+
+    #[test]
+    fn size_counts_keys_and_encoded_operations() {
+        let ser = Arc::new(BinarySerializationEngine);
+        let path = new_temp_path();
+        let size = {
+            let table = create_memtable(&path, &ser);
+            table.insert(Dummy("a".into(), 1)).unwrap();
+            let small = table.size();
+            table.insert(Dummy("b".repeat(100), 1)).unwrap();
+            assert!(table.size() > small + 200);
+            // The key is only counted once
+            let before = table.size();
+            table.delete("a".into()).unwrap();
+            assert!(table.size() - before < small);
+
+            assert_eq!(table.log_size(), std::fs::metadata(&path).unwrap().len());
+            table.insert_with(Dummy("c".into(), 1), false).unwrap();
+            assert_eq!(table.log_size(), std::fs::metadata(&path).unwrap().len());
+            table.size()
+        };
+
+        let table = create_memtable(&path, &ser);
+        assert!(table.size() < size);
+        assert!(table.size() > 200);
     }
 
     #[test]
     fn iterates_in_order() {
-        let ser = BinarySerializationEngine;
+        let ser = Arc::new(BinarySerializationEngine);
         let path = new_temp_path();
         let table = create_memtable(&path, &ser);
 
@@ -210,7 +493,7 @@ mod tests {
 
     #[test]
     fn rebuild_from_log_preserves_state() {
-        let ser = BinarySerializationEngine;
+        let ser = Arc::new(BinarySerializationEngine);
         let path = new_temp_path();
 
         {
@@ -226,7 +509,189 @@ mod tests {
         let table = create_memtable(&path, &ser);
 
         assert_eq!(table.len(), 2);
-        assert!(table.get(&"k2".into()).unwrap().is_some());
-        assert!(table.get(&"k1".into()).unwrap().is_none());
+        assert!(table.get("k2").unwrap().is_some());
+        assert!(table.get("k1").unwrap().is_none());
+    }
+
+    #[test]
+    fn concurrent_writes_are_group_committed() {
+        let ser = Arc::new(BinarySerializationEngine);
+        let path = new_temp_path();
+
+        {
+            let table = Arc::new(
+                MemTable::<Dummy, BinarySerializationEngine>::open_or_build_with(
+                    &path,
+                    Arc::clone(&ser),
+                    &Config {
+                        wal_sync: WalSyncMode::Always,
+                        ..Config::default()
+                    },
+                    Arc::new(AtomicU64::new(0)),
+                )
+                .unwrap(),
+            );
+            let handles: Vec<_> = (0..8)
+                .map(|t| {
+                    let table = Arc::clone(&table);
+                    thread::spawn(move || {
+                        for i in 0..50 {
+                            table.insert(Dummy(format!("{}_{}", t, i), i)).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for handle in handles {
+                handle.join().unwrap();
+            }
+            // The writers waiting on a sync joined the next batch
+            assert!(table.log.writes() < 400, "{} writes", table.log.writes());
+            assert!(!table.log.has_unsynced_writes());
+        }
+
+        let table = create_memtable(&path, &ser);
+        assert_eq!(table.len(), 400);
+        assert_eq!(table.operations(), 400);
+        assert_eq!(table.last_sequence(), 400);
+        assert_eq!(table.get("7_49").unwrap(), Some(Dummy("7_49".into(), 49)));
+    }
+
+    #[test]
+    fn interval_sync_leaves_writes_to_sync() {
+        let ser = Arc::new(BinarySerializationEngine);
+        let path = new_temp_path();
+        let table = MemTable::<Dummy, BinarySerializationEngine>::open_or_build_with(
+            &path,
+            Arc::clone(&ser),
+            &Config {
+                wal_sync: WalSyncMode::Interval,
+                ..Config::default()
+            },
+            Arc::new(AtomicU64::new(0)),
+        )
+        .unwrap();
+        assert!(!table.log.has_unsynced_writes());
+
+        table.insert(Dummy("k1".into(), 1)).unwrap();
+        table.insert(Dummy("k2".into(), 2)).unwrap();
+        assert!(table.log.has_unsynced_writes());
+        assert_eq!(table.log.writes(), 2);
+
+        table.log.sync().unwrap();
+        assert!(!table.log.has_unsynced_writes());
+        drop(table);
+        assert_eq!(create_memtable(&path, &ser).len(), 2);
+    }
+
+    fn open_with_recovery(
+        path: &str,
+        ser: &Arc<BinarySerializationEngine>,
+        mode: WalRecoveryMode,
+    ) -> std::io::Result<MemTable<Dummy, BinarySerializationEngine>> {
+        MemTable::open_or_build_with(
+            path,
+            Arc::clone(ser),
+            &Config {
+                wal_recovery: mode,
+                ..Config::default()
+            },
+            Arc::new(AtomicU64::new(0)),
+        )
+    }
+
+    #[test]
+    fn torn_tail_is_truncated() {
+        let ser = Arc::new(BinarySerializationEngine);
+        let path = new_temp_path();
+        {
+            let table = create_memtable(&path, &ser);
+            table.insert(Dummy("k1".into(), 1)).unwrap();
+            table.insert(Dummy("k2".into(), 2)).unwrap();
+        }
+        let intact = std::fs::metadata(&path).unwrap().len();
+        // A crash in the middle of the next append
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.extend_from_slice(&[42, 0, 0, 0, 1, 2]);
+        std::fs::write(&path, bytes).unwrap();
+
+        assert!(open_with_recovery(&path, &ser, WalRecoveryMode::Fail).is_err());
+
+        {
+            let table = open_with_recovery(&path, &ser, WalRecoveryMode::TruncateTail).unwrap();
+            let report = table.recovery_report();
+            assert_eq!(report.replayed, 2);
+            assert_eq!(report.truncated_at, Some(intact));
+            assert_eq!(report.dropped_bytes, 6);
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), intact);
+            table.insert(Dummy("k3".into(), 3)).unwrap();
+        }
+
+        let table = create_memtable(&path, &ser);
+        assert!(!table.recovery_report().dropped_anything());
+        assert_eq!(table.len(), 3);
+    }
+
+    #[test]
+    fn corrupted_records_are_skipped_or_cut() {
+        let ser = Arc::new(BinarySerializationEngine);
+        let path = new_temp_path();
+        {
+            let table = create_memtable(&path, &ser);
+            for i in 0..3 {
+                table.insert(Dummy(format!("k{}", i), i)).unwrap();
+            }
+        }
+        let mut bytes = std::fs::read(&path).unwrap();
+        let record = bytes.len() / 3;
+        bytes[record + 10] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        assert!(open_with_recovery(&path, &ser, WalRecoveryMode::Fail).is_err());
+
+        let table = open_with_recovery(&path, &ser, WalRecoveryMode::SkipCorrupted).unwrap();
+        assert_eq!(
+            table.recovery_report().skipped,
+            vec![(record as u64, record as u64)]
+        );
+        assert_eq!(table.len(), 2);
+        assert!(table.get("k1").is_none());
+        assert!(table.get("k2").is_some());
+        drop(table);
+
+        let table = open_with_recovery(&path, &ser, WalRecoveryMode::TruncateTail).unwrap();
+        assert_eq!(table.recovery_report().truncated_at, Some(record as u64));
+        assert_eq!(table.len(), 1);
+        assert!(table.get("k0").is_some());
+    }
+
+    #[test]
+    fn unframed_logs_are_replayed_and_rewritten() {
+        let ser = Arc::new(BinarySerializationEngine);
+        let path = new_temp_path();
+        let mut bytes = vec![];
+        for op in [
+            LogOperation::Insert {
+                record: Dummy("k1".into(), 1),
+            },
+            LogOperation::Delete { key: "k1".into() },
+            LogOperation::Insert {
+                record: Dummy("k2".into(), 2),
+            },
+        ] {
+            bytes.extend(ser.serialize(op).unwrap());
+        }
+        std::fs::write(&path, bytes).unwrap();
+
+        {
+            let table = open_with_recovery(&path, &ser, WalRecoveryMode::Fail).unwrap();
+            assert_eq!(table.recovery_report().replayed, 3);
+            assert_eq!(table.len(), 2);
+            table.insert(Dummy("k3".into(), 3)).unwrap();
+        }
+
+        let table = open_with_recovery(&path, &ser, WalRecoveryMode::Fail).unwrap();
+        assert_eq!(table.operations(), 4);
+        assert!(table.get("k1").unwrap().is_none());
+        assert_eq!(table.get("k3").unwrap(), Some(Dummy("k3".into(), 3)));
     }
 }
@@ -4,7 +4,8 @@ use bincode::{Decode, Encode};
 
 // TODO: This means it will use the comparator of strings only. This won't work for numbers. change
 // this interface to change the key type as long as comparable, and serializable
-pub trait MemTableRecord: Encode + Decode<()> + Clone + Debug {
+/// Records are shared with the background threads of their engine
+pub trait MemTableRecord: Encode + Decode<()> + Clone + Debug + Send + Sync + 'static {
     const TYPE_NAME: &'static str;
     fn get_key(&self) -> String;
 }
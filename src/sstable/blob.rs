@@ -0,0 +1,214 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Error, ErrorKind, Read, Result as IOResult, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use tempfile::NamedTempFile;
+
+use crate::{
+    compaction::temp_file,
+    memtable::MemTableRecord,
+    serialization::SerializationEngine,
+    sstable::{error::SSTableError, sync_dir},
+};
+
+/// Set in the index offsets of the entries whose value is in a blob file. The storage file has the
+/// `BlobPointer` of the value at that offset instead of the value
+pub const BLOB_POINTER: u64 = 1 << 63;
+
+/// @definition: Where a value separated from its table is
+/// @field file: The number of the blob file
+/// @field offset: Where the record of the value starts in the blob file
+/// @field len: The length of the encoded value, without the header of its record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobPointer {
+    pub file: u64,
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl BlobPointer {
+    pub const SIZE: usize = 24;
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[..8].copy_from_slice(&self.file.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[16..].copy_from_slice(&self.len.to_le_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8; Self::SIZE]) -> BlobPointer {
+        let number =
+            |range: std::ops::Range<usize>| u64::from_le_bytes(bytes[range].try_into().unwrap());
+        BlobPointer {
+            file: number(0..8),
+            offset: number(8..16),
+            len: number(16..24),
+        }
+    }
+
+    /// The bytes of the blob file taken by the value, with the header of its record
+    pub fn record_size(&self) -> u64 {
+        self.len + 8
+    }
+}
+
+/// @definition: The value of an entry, as it is in the storage file
+/// @variant Inline: The record, or None for a tombstone
+/// @variant Blob: The record is in a blob file
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoredValue<T> {
+    Inline(Option<T>),
+    Blob(BlobPointer),
+}
+
+/// The path of a blob file of the record type
+pub fn blob_path(db_path: &str, type_name: &str, number: u64) -> PathBuf {
+    Path::new(db_path)
+        .join("blobs")
+        .join(format!("{}-{}.log", type_name, number))
+}
+
+/// @definition: An append-only file holding the values that were separated from their tables, as
+/// records of `[length: u32][crc32: u32][value]`. The tables only have pointers to them, so
+/// compactions move the pointers around without copying the values.
+/// @field size: The length of the file. The part of it not referenced by any table is garbage
+/// @field obsolete: Set once no table of the current version references the file. It is deleted
+/// when the last version holding it drops it
+#[derive(Debug)]
+pub struct BlobFile {
+    pub number: u64,
+    pub path: PathBuf,
+    pub size: u64,
+    pub obsolete: AtomicBool,
+}
+
+impl BlobFile {
+    pub fn new(number: u64, path: PathBuf, size: u64) -> BlobFile {
+        BlobFile {
+            number,
+            path,
+            size,
+            obsolete: AtomicBool::new(false),
+        }
+    }
+
+    /// Makes the file and its entry in the directory durable
+    pub fn sync(&self) -> IOResult<()> {
+        File::open(&self.path)?.sync_all()?;
+        sync_dir(self.path.parent().unwrap())
+    }
+
+    pub fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Drop for BlobFile {
+    fn drop(&mut self) {
+        if !self.obsolete.load(Ordering::SeqCst) {
+            return;
+        }
+        // A file that can't be deleted is an orphan, cleaned up when the engine is opened
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// @definition: Writes a new blob file. Like the files of a table being built, it is a temporary
+/// file until it is finished
+/// @field number: The number of the blob file, which the pointers refer to
+/// @field size: The bytes written so far
+pub struct BlobWriter {
+    writer: BufWriter<NamedTempFile>,
+    number: u64,
+    size: u64,
+}
+
+impl BlobWriter {
+    pub fn new(db_path: &str, type_name: &str, number: u64) -> IOResult<BlobWriter> {
+        Ok(BlobWriter {
+            writer: BufWriter::new(temp_file(db_path, type_name)?),
+            number,
+            size: 0,
+        })
+    }
+
+    /// Appends an encoded value, and returns where it is
+    pub fn append(&mut self, encoded: &[u8]) -> IOResult<BlobPointer> {
+        self.writer
+            .write_all(&(encoded.len() as u32).to_le_bytes())?;
+        self.writer
+            .write_all(&crc32fast::hash(encoded).to_le_bytes())?;
+        self.writer.write_all(encoded)?;
+
+        let pointer = BlobPointer {
+            file: self.number,
+            offset: self.size,
+            len: encoded.len() as u64,
+        };
+        self.size += pointer.record_size();
+        Ok(pointer)
+    }
+
+    /// Moves the file to its path in the `blobs` directory, which must not exist yet
+    pub fn finish(self, db_path: &str, type_name: &str) -> IOResult<BlobFile> {
+        let path = blob_path(db_path, type_name, self.number);
+        fs::create_dir_all(path.parent().unwrap())?;
+        self.writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .persist_noclobber(&path)
+            .map_err(|err| err.error)?;
+        Ok(BlobFile::new(self.number, path, self.size))
+    }
+}
+
+/// Reads the encoded value the pointer refers to, and checks that it wasn't damaged
+pub fn read_blob(db_path: &str, type_name: &str, pointer: &BlobPointer) -> IOResult<Vec<u8>> {
+    let path = blob_path(db_path, type_name, pointer.file);
+    let mut reader = BufReader::new(File::open(&path)?);
+    reader.seek(SeekFrom::Start(pointer.offset))?;
+
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header)?;
+    let mut encoded = vec![0u8; pointer.len as usize];
+    reader.read_exact(&mut encoded)?;
+
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+    let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+    if len != pointer.len || crc32fast::hash(&encoded) != checksum {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Corrupted blob at offset {} of {}",
+                pointer.offset,
+                path.display()
+            ),
+        ));
+    }
+    Ok(encoded)
+}
+
+/// Reads and decodes the record the pointer refers to
+pub fn load_blob<T, SS>(
+    db_path: &str,
+    pointer: &BlobPointer,
+    serializer: &SS,
+) -> Result<Option<T>, SSTableError>
+where
+    T: MemTableRecord,
+    SS: SerializationEngine<Option<T>>,
+{
+    let encoded =
+        read_blob(db_path, T::TYPE_NAME, pointer).map_err(|_| SSTableError::DBFileCorrupted {
+            file: blob_path(db_path, T::TYPE_NAME, pointer.file)
+                .display()
+                .to_string(),
+        })?;
+    serializer
+        .deserialize(&mut BufReader::new(encoded.as_slice()))
+        .map_err(|_| SSTableError::EncodingError)
+}
@@ -0,0 +1,182 @@
+use std::{
+    fs::File,
+    io::{BufReader, Error, ErrorKind, Read, Result as IOResult, Seek, SeekFrom},
+};
+
+use crate::{
+    config::Compression,
+    memtable::MemTableRecord,
+    serialization::SerializationEngine,
+    sstable::blob::{BLOB_POINTER, BlobPointer, StoredValue},
+};
+
+/// Set in the index offsets of the entries that are in a block. The rest of the offset is the
+/// position of the block in the storage file, followed by `IN_BLOCK_BITS` bits for the position of
+/// the entry in the uncompressed block. Tables written before the blocks have their records one
+/// after the other, at the offset in the index
+pub const BLOCK_ENTRY: u64 = 1 << 62;
+const IN_BLOCK_BITS: u32 = 20;
+const IN_BLOCK_MASK: u64 = (1 << IN_BLOCK_BITS) - 1;
+
+/// The largest block size, so that an entry always starts at a position that fits in its offset
+pub const MAX_BLOCK_SIZE: usize = 1 << IN_BLOCK_BITS;
+
+/// `[codec: u8][uncompressed length: u32][length: u32][crc32: u32]`
+const HEADER_SIZE: usize = 13;
+
+/// The offset of an entry in the index, when it starts at `position` of the block at `start`
+pub fn entry_offset(start: u64, position: usize) -> u64 {
+    BLOCK_ENTRY | (start << IN_BLOCK_BITS) | position as u64
+}
+
+/// Where the entry of the offset starts in the storage file, or where its block starts
+pub fn file_offset(offset: u64) -> u64 {
+    let offset = offset & !BLOB_POINTER;
+    if offset & BLOCK_ENTRY == 0 {
+        return offset;
+    }
+    (offset & !BLOCK_ENTRY) >> IN_BLOCK_BITS
+}
+
+fn codec_byte(compression: Compression) -> u8 {
+    match compression {
+        Compression::None => 0,
+        Compression::Lz4 => 1,
+        Compression::Deflate => 2,
+    }
+}
+
+/// Compresses the entries of a block, and frames them as
+/// `[codec: u8][uncompressed length: u32][length: u32][crc32: u32][entries]`. Blocks that don't
+/// get smaller are stored uncompressed.
+pub fn encode_block(entries: &[u8], compression: Compression) -> Vec<u8> {
+    let compressed = match compression {
+        Compression::None => None,
+        Compression::Lz4 => Some(lz4_flex::block::compress(entries)),
+        Compression::Deflate => Some(miniz_oxide::deflate::compress_to_vec(entries, 6)),
+    };
+    let (compression, payload) = match compressed {
+        Some(compressed) if compressed.len() < entries.len() => (compression, compressed),
+        _ => (Compression::None, entries.to_vec()),
+    };
+
+    let mut block = Vec::with_capacity(HEADER_SIZE + payload.len());
+    block.push(codec_byte(compression));
+    block.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    block.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    block.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    block.extend_from_slice(&payload);
+    block
+}
+
+/// Reads the block starting at `start`, checks it and decompresses it
+pub fn read_block<R: Read + Seek>(reader: &mut R, start: u64) -> IOResult<Vec<u8>> {
+    reader.seek(SeekFrom::Start(start))?;
+    let mut header = [0u8; HEADER_SIZE];
+    reader.read_exact(&mut header)?;
+    let size = u32::from_le_bytes(header[1..5].try_into().unwrap()) as usize;
+    let len = u32::from_le_bytes(header[5..9].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(header[9..].try_into().unwrap());
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    let corrupted = || {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Corrupted block at offset {}", start),
+        )
+    };
+    if crc32fast::hash(&payload) != checksum {
+        return Err(corrupted());
+    }
+
+    let entries = match header[0] {
+        0 => Some(payload),
+        1 => lz4_flex::block::decompress(&payload, size).ok(),
+        2 => miniz_oxide::inflate::decompress_to_vec_with_limit(&payload, size).ok(),
+        _ => None,
+    };
+    entries
+        .filter(|entries| entries.len() == size)
+        .ok_or_else(corrupted)
+}
+
+/// @definition: Reads the entries of a storage file by their offset in the index, from tables with
+/// or without blocks
+/// @field block: The last block read, and where it starts. Entries are mostly read in order, so
+/// the block is decompressed once for all of its entries
+pub struct StorageReader {
+    reader: BufReader<File>,
+    block: Option<(u64, Vec<u8>)>,
+}
+
+impl StorageReader {
+    pub fn open(path: &str) -> IOResult<StorageReader> {
+        Ok(StorageReader {
+            reader: BufReader::new(File::open(path)?),
+            block: None,
+        })
+    }
+
+    /// The value of the entry at the offset taken from the index
+    pub fn read<T, SS>(&mut self, offset: u64, serializer: &SS) -> IOResult<StoredValue<T>>
+    where
+        T: MemTableRecord,
+        SS: SerializationEngine<Option<T>>,
+    {
+        let blob = offset & BLOB_POINTER != 0;
+        if offset & BLOCK_ENTRY == 0 {
+            self.reader.seek(SeekFrom::Start(file_offset(offset)))?;
+            return if blob {
+                let mut pointer = [0u8; BlobPointer::SIZE];
+                self.reader.read_exact(&mut pointer)?;
+                Ok(StoredValue::Blob(BlobPointer::decode(&pointer)))
+            } else {
+                Self::decode(&mut self.reader, serializer).map(StoredValue::Inline)
+            };
+        }
+
+        let start = file_offset(offset);
+        if self
+            .block
+            .as_ref()
+            .is_none_or(|(cached, _)| *cached != start)
+        {
+            self.block = Some((start, read_block(&mut self.reader, start)?));
+        }
+        let block = &self.block.as_ref().unwrap().1;
+
+        // Entries are `[length: u32][value]` in a block
+        let position = (offset & IN_BLOCK_MASK) as usize;
+        let entry = block
+            .get(position..position + 4)
+            .and_then(|len| {
+                let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+                block.get(position + 4..position + 4 + len)
+            })
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("No entry at {} of the block at {}", position, start),
+                )
+            })?;
+        if blob {
+            let pointer = entry
+                .try_into()
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid blob pointer"))?;
+            return Ok(StoredValue::Blob(BlobPointer::decode(pointer)));
+        }
+        Self::decode(&mut BufReader::new(entry), serializer).map(StoredValue::Inline)
+    }
+
+    fn decode<T, SS, R>(reader: &mut BufReader<R>, serializer: &SS) -> IOResult<Option<T>>
+    where
+        T: MemTableRecord,
+        SS: SerializationEngine<Option<T>>,
+        R: Read,
+    {
+        serializer
+            .deserialize(reader)
+            .map_err(|err| Error::new(ErrorKind::InvalidData, format!("{:?}", err)))
+    }
+}
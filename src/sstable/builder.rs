@@ -0,0 +1,470 @@
+use std::{
+    collections::BTreeMap,
+    io::{BufWriter, Write},
+    marker::PhantomData,
+    path::Path,
+    sync::atomic::AtomicBool,
+};
+
+use tempfile::NamedTempFile;
+
+use crate::{
+    compaction::temp_file,
+    config::{Compression, Config},
+    memtable::MemTableRecord,
+    serialization::SerializationEngine,
+    sstable::{
+        SSTable,
+        blob::{BLOB_POINTER, BlobFile, BlobWriter, StoredValue},
+        block::{MAX_BLOCK_SIZE, encode_block, entry_offset},
+        error::SSTableError,
+        footer::TableFooter,
+    },
+};
+
+/// @definition: Writes a table from records given in ascending key order. The records are grouped
+/// in blocks that are compressed and written to the storage file once full, and their keys go to
+/// the index, so only a block is held in memory. Both files are temporary files in the database
+/// directory until the table is finished, so an unfinished table never leaves table files behind.
+/// @field min: The first key, once a record was added
+/// @field max: The last key added, which the next one must be greater than
+/// @field size: The bytes written to the storage file, where the block being filled will start
+/// @field block: The entries of the block being filled, as `[length: u32][value]`
+/// @field count: The number of records added, without the tombstones
+/// @field blob_file: The number of the blob file the values of at least `Config::blob_threshold`
+/// bytes go to. None keeps every value in the table
+/// @field blobs: The blob file being written, once a value went to it
+/// @field blob_refs: The bytes of each blob file the table references
+/// @field footer: The newest sequence number and the write times of the records, written after
+/// the blocks
+pub struct SSTableBuilder<'a, T, SS>
+where
+    T: MemTableRecord,
+    SS: SerializationEngine<Option<T>>,
+{
+    index_writer: BufWriter<NamedTempFile>,
+    storage_writer: BufWriter<NamedTempFile>,
+    serializer: &'a SS,
+    config: &'a Config,
+    min: Option<String>,
+    max: String,
+    size: usize,
+    block: Vec<u8>,
+    compression: Compression,
+    count: usize,
+    blob_file: Option<u64>,
+    blobs: Option<BlobWriter>,
+    blob_refs: BTreeMap<u64, u64>,
+    footer: TableFooter,
+    record: PhantomData<T>,
+}
+
+impl<'a, T, SS> SSTableBuilder<'a, T, SS>
+where
+    T: MemTableRecord,
+    SS: SerializationEngine<Option<T>>,
+{
+    pub fn new(serializer: &'a SS, config: &'a Config) -> Result<Self, SSTableError> {
+        let temp = || {
+            temp_file(&config.db_path, T::TYPE_NAME).map_err(|_| SSTableError::FileCreationError)
+        };
+        Ok(SSTableBuilder {
+            index_writer: BufWriter::new(temp()?),
+            storage_writer: BufWriter::new(temp()?),
+            serializer,
+            config,
+            min: None,
+            max: String::new(),
+            size: 0,
+            block: vec![],
+            compression: config.compression,
+            count: 0,
+            blob_file: None,
+            blobs: None,
+            blob_refs: BTreeMap::new(),
+            footer: TableFooter::default(),
+            record: PhantomData,
+        })
+    }
+
+    /// Compresses the blocks with the codec instead of `Config::compression`
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Separates the large values into the blob file numbered `number`, when
+    /// `Config::blob_threshold` is set. The blob file is only written if a value goes to it
+    pub fn with_blob_file(mut self, number: u64) -> Self {
+        self.blob_file = Some(number);
+        self
+    }
+
+    /// Records the sequence number of the newest write in the table, which orders the tables when
+    /// the manifest is rebuilt
+    pub fn with_sequence(mut self, max_sequence: u64) -> Self {
+        self.footer.max_sequence = max_sequence;
+        self
+    }
+
+    /// Records the times the oldest and the newest records were written, in milliseconds since the
+    /// epoch
+    pub fn with_timestamps(mut self, min_timestamp: u64, max_timestamp: u64) -> Self {
+        self.footer.min_timestamp = min_timestamp;
+        self.footer.max_timestamp = max_timestamp;
+        self
+    }
+
+    /// Adds a record, or a tombstone when `value` is None. Fails when the key isn't greater than
+    /// the last one added
+    pub fn add(&mut self, key: String, value: Option<T>) -> Result<(), SSTableError> {
+        self.check_order(&key)?;
+
+        // The count avoids tombstones
+        let tombstone = value.is_none();
+        let encoded = self
+            .serializer
+            .serialize(value)
+            .map_err(|_| SSTableError::EncodingError)?;
+
+        if let Some(number) = self.blob_file
+            && !tombstone
+            && self
+                .config
+                .blob_threshold
+                .is_some_and(|threshold| encoded.len() >= threshold)
+        {
+            let writer = match &mut self.blobs {
+                Some(writer) => writer,
+                None => self.blobs.insert(
+                    BlobWriter::new(&self.config.db_path, T::TYPE_NAME, number)
+                        .map_err(|_| SSTableError::FileCreationError)?,
+                ),
+            };
+            let pointer = writer
+                .append(&encoded)
+                .map_err(|err| SSTableError::LogWriteError { err })?;
+            return self.add_stored(key, StoredValue::Blob(pointer));
+        }
+
+        self.write_entry(key, &encoded, 0)?;
+        if !tombstone {
+            self.count += 1;
+        }
+        Ok(())
+    }
+
+    /// Adds an entry as it was read from another table, so that a value in a blob file stays there
+    pub fn add_stored(&mut self, key: String, value: StoredValue<T>) -> Result<(), SSTableError> {
+        match value {
+            StoredValue::Inline(value) => self.add(key, value),
+            StoredValue::Blob(pointer) => {
+                self.check_order(&key)?;
+                self.write_entry(key, &pointer.encode(), BLOB_POINTER)?;
+                *self.blob_refs.entry(pointer.file).or_default() += pointer.record_size();
+                self.count += 1;
+                Ok(())
+            }
+        }
+    }
+
+    fn check_order(&self, key: &str) -> Result<(), SSTableError> {
+        if self.min.is_some() && key <= self.max.as_str() {
+            return Err(SSTableError::OutOfOrderKey {
+                previous: self.max.clone(),
+                key: key.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Writes the key and the offset to the index, with the flags set, and the bytes to the
+    /// block being filled
+    fn write_entry(&mut self, key: String, bytes: &[u8], flags: u64) -> Result<(), SSTableError> {
+        // The index keys are padded or truncated to a fixed size
+        let mut key_bytes = vec![0u8; self.config.index_key_string_size];
+        let len = key.len().min(self.config.index_key_string_size);
+        key_bytes[..len].copy_from_slice(&key.as_bytes()[..len]);
+
+        let write_error = |err| SSTableError::LogWriteError { err };
+        self.index_writer
+            .write_all(&key_bytes)
+            .map_err(write_error)?;
+        let offset = entry_offset(self.size as u64, self.block.len()) | flags;
+        self.index_writer
+            .write_all(&offset.to_le_bytes())
+            .map_err(write_error)?;
+        self.block
+            .extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        self.block.extend_from_slice(bytes);
+
+        self.min.get_or_insert_with(|| key.clone());
+        self.max = key;
+        if self.block.len() >= self.config.block_size.clamp(1, MAX_BLOCK_SIZE) {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self) -> Result<(), SSTableError> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let block = encode_block(&self.block, self.compression);
+        self.storage_writer
+            .write_all(&block)
+            .map_err(|err| SSTableError::LogWriteError { err })?;
+        self.size += block.len();
+        self.block.clear();
+        Ok(())
+    }
+
+    /// The bytes written to the storage file so far, and the ones of the block being filled
+    pub fn size(&self) -> usize {
+        self.size + self.block.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.is_none()
+    }
+
+    /// Writes the footer and moves both files to their paths, which must not exist yet, and returns
+    /// the table. It is in level 0.
+    pub fn finish(self, storage_path: &str, index_path: &str) -> Result<SSTable, SSTableError> {
+        self.finish_with_blobs(storage_path, index_path)
+            .map(|(table, _)| table)
+    }
+
+    /// Like `finish`, and also returns the blob file written by the builder, if a value went to it.
+    /// The blob file is moved to the `blobs` directory before the table files.
+    pub fn finish_with_blobs(
+        mut self,
+        storage_path: &str,
+        index_path: &str,
+    ) -> Result<(SSTable, Option<BlobFile>), SSTableError> {
+        self.write_block()?;
+        let Some(min) = self.min else {
+            return Err(SSTableError::EmptyMemtableError);
+        };
+        if Path::new(storage_path).exists() {
+            return Err(SSTableError::LogFileAlreadyExistsError);
+        }
+        if Path::new(index_path).exists() {
+            return Err(SSTableError::IndexFileAlreadyExistsError);
+        }
+        self.storage_writer
+            .write_all(&self.footer.encode())
+            .map_err(|err| SSTableError::LogWriteError { err })?;
+
+        let persist = |writer: BufWriter<NamedTempFile>, path: &str| {
+            let file = writer
+                .into_inner()
+                .map_err(|err| SSTableError::LogWriteError {
+                    err: err.into_error(),
+                })?;
+            file.persist_noclobber(path)
+                .map_err(|err| SSTableError::LogWriteError { err: err.error })
+        };
+        let blob_file = self
+            .blobs
+            .map(|writer| writer.finish(&self.config.db_path, T::TYPE_NAME))
+            .transpose()
+            .map_err(|err| SSTableError::LogWriteError { err })?;
+        persist(self.storage_writer, storage_path)?;
+        persist(self.index_writer, index_path)?;
+
+        let table = SSTable {
+            storage_path: storage_path.to_string(),
+            index_path: index_path.to_string(),
+            min,
+            max: self.max,
+            size: self.size,
+            count: self.count,
+            level: 0,
+            min_timestamp: self.footer.min_timestamp,
+            max_timestamp: self.footer.max_timestamp,
+            max_sequence: self.footer.max_sequence,
+            blobs: self.blob_refs.into_iter().collect(),
+            obsolete: AtomicBool::new(false),
+        };
+        Ok((table, blob_file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bincode::{Decode, Encode};
+    use tempfile::TempDir;
+
+    use std::{fs, sync::atomic::AtomicBool};
+
+    use crate::{
+        config::{Compression, Config},
+        memtable::MemTableRecord,
+        serialization::{BinarySerializationEngine, SerializationEngine},
+        sstable::{SSTable, SSTableBuilder, error::SSTableError},
+    };
+
+    #[derive(Encode, Decode, Clone, Debug, PartialEq)]
+    struct Photo {
+        id: String,
+        url: String,
+    }
+
+    impl MemTableRecord for Photo {
+        const TYPE_NAME: &'static str = "Photo";
+        fn get_key(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    fn photo(id: &str) -> Option<Photo> {
+        Some(Photo {
+            id: id.to_string(),
+            url: format!("url_{}", id),
+        })
+    }
+
+    #[test]
+    fn builds_a_readable_table_from_sorted_records() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let config = Config {
+            db_path: temp_dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let storage_path = temp_dir.path().join("storage.log");
+        let index_path = temp_dir.path().join("index.log");
+
+        let mut builder = SSTableBuilder::new(&BinarySerializationEngine, &config).unwrap();
+        builder.add("a".to_string(), photo("a")).unwrap();
+        builder.add("b".to_string(), None).unwrap();
+        builder.add("c".to_string(), photo("c")).unwrap();
+        assert!(matches!(
+            builder.add("b".to_string(), photo("b")),
+            Err(SSTableError::OutOfOrderKey { .. })
+        ));
+        assert!(matches!(
+            builder.add("c".to_string(), photo("c")),
+            Err(SSTableError::OutOfOrderKey { .. })
+        ));
+
+        let table = builder
+            .finish(storage_path.to_str().unwrap(), index_path.to_str().unwrap())
+            .unwrap();
+        assert_eq!((table.min.as_str(), table.max.as_str()), ("a", "c"));
+        assert_eq!(table.count, 2);
+
+        let get = |key: &str| {
+            table
+                .get::<Photo, _>(key, &config, &BinarySerializationEngine)
+                .unwrap()
+        };
+        assert_eq!(get("a"), Some(photo("a")));
+        assert_eq!(get("b"), Some(None));
+        assert_eq!(get("c"), Some(photo("c")));
+        assert_eq!(get("d"), None);
+
+        // No temporary file is left behind
+        let leftovers = std::fs::read_dir(temp_dir.path()).unwrap().count();
+        assert_eq!(leftovers, 2);
+    }
+
+    #[test]
+    fn empty_tables_and_existing_files_are_rejected() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let config = Config {
+            db_path: temp_dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let storage_path = temp_dir.path().join("storage.log");
+        let index_path = temp_dir.path().join("index.log");
+        let [storage_path, index_path] =
+            [&storage_path, &index_path].map(|path| path.to_str().unwrap());
+
+        let builder = SSTableBuilder::<Photo, _>::new(&BinarySerializationEngine, &config).unwrap();
+        assert!(matches!(
+            builder.finish(storage_path, index_path),
+            Err(SSTableError::EmptyMemtableError)
+        ));
+
+        std::fs::write(storage_path, "").unwrap();
+        let mut builder = SSTableBuilder::new(&BinarySerializationEngine, &config).unwrap();
+        builder.add("a".to_string(), photo("a")).unwrap();
+        assert!(matches!(
+            builder.finish(storage_path, index_path),
+            Err(SSTableError::LogFileAlreadyExistsError)
+        ));
+    }
+
+    #[test]
+    fn blocks_are_readable_whatever_their_codec() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let config = Config {
+            db_path: temp_dir.path().to_str().unwrap().to_string(),
+            block_size: 256,
+            ..Default::default()
+        };
+        let path = |name: &str| temp_dir.path().join(name).to_str().unwrap().to_string();
+        let record = |i: usize| {
+            Some(Photo {
+                id: format!("{:03}", i),
+                url: format!("https://photos.example.com/albums/holidays/{}.jpg", i),
+            })
+        };
+
+        let mut sizes = vec![];
+        for compression in [Compression::None, Compression::Lz4, Compression::Deflate] {
+            let name = format!("{:?}", compression);
+            let mut builder = SSTableBuilder::new(&BinarySerializationEngine, &config)
+                .unwrap()
+                .with_compression(compression);
+            for i in 0..100 {
+                builder.add(format!("{:03}", i), record(i)).unwrap();
+            }
+            let table = builder
+                .finish(
+                    &path(&format!("{}.storage", name)),
+                    &path(&format!("{}.index", name)),
+                )
+                .unwrap();
+            for i in [0, 42, 99] {
+                let found = table
+                    .get::<Photo, _>(&format!("{:03}", i), &config, &BinarySerializationEngine)
+                    .unwrap();
+                assert_eq!(found, Some(record(i)));
+            }
+            sizes.push(table.size);
+        }
+        assert!(sizes[1] < sizes[0] && sizes[2] < sizes[1], "{:?}", sizes);
+
+        // Tables written before the blocks have their records one after the other
+        let (mut storage, mut index) = (vec![], vec![]);
+        for i in 0..3 {
+            let mut key = format!("{:03}", i).into_bytes();
+            key.resize(config.index_key_string_size, 0);
+            index.extend_from_slice(&key);
+            index.extend_from_slice(&(storage.len() as u64).to_le_bytes());
+            storage.extend(BinarySerializationEngine.serialize(record(i)).unwrap());
+        }
+        fs::write(path("legacy.storage"), &storage).unwrap();
+        fs::write(path("legacy.index"), &index).unwrap();
+        let legacy = SSTable {
+            storage_path: path("legacy.storage"),
+            index_path: path("legacy.index"),
+            min: "000".to_string(),
+            max: "002".to_string(),
+            size: storage.len(),
+            count: 3,
+            level: 0,
+            min_timestamp: 0,
+            max_timestamp: 0,
+            max_sequence: 0,
+            blobs: vec![],
+            obsolete: AtomicBool::new(false),
+        };
+        let found = legacy
+            .get::<Photo, _>("001", &config, &BinarySerializationEngine)
+            .unwrap();
+        assert_eq!(found, Some(record(1)));
+    }
+}
@@ -1,4 +1,4 @@
-use std::{fmt::Debug, fs::File, io};
+use std::{fmt::Debug, io};
 
 #[derive(Debug)]
 pub enum SSTableError {
@@ -8,6 +8,7 @@ pub enum SSTableError {
     EncodingError,
     LogWriteError { err: io::Error },
     EmptyMemtableError,
+    OutOfOrderKey { previous: String, key: String },
     DBFileDeleted { file: String },
     DBFilePermissionsChanged { file: String },
     DBFileCorrupted { file: String },
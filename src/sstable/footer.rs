@@ -0,0 +1,123 @@
+use std::{
+    fs::File,
+    io::{Read, Result as IOResult, Seek, SeekFrom, Write},
+};
+
+/// Ends the footer, so that tables written before it had one are told apart
+const MAGIC: u32 = 0x5353_5446;
+
+/// `[max sequence: u64][min timestamp: u64][max timestamp: u64][crc32: u32][magic: u32]`
+pub const FOOTER_SIZE: usize = 32;
+
+/// @definition: What a table knows about the writes it holds, appended to its storage file after
+/// the blocks. It is what orders the tables and dates them when the manifest is rebuilt
+/// @field max_sequence: The sequence number of the newest write in the table
+/// @field min_timestamp: The time the oldest record in the table was written, in milliseconds since
+/// the epoch. 0 when unknown
+/// @field max_timestamp: The time the newest record in the table was written
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TableFooter {
+    pub max_sequence: u64,
+    pub min_timestamp: u64,
+    pub max_timestamp: u64,
+}
+
+impl TableFooter {
+    pub fn encode(&self) -> [u8; FOOTER_SIZE] {
+        let mut bytes = [0u8; FOOTER_SIZE];
+        bytes[..8].copy_from_slice(&self.max_sequence.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.min_timestamp.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.max_timestamp.to_le_bytes());
+        let checksum = crc32fast::hash(&bytes[..24]);
+        bytes[24..28].copy_from_slice(&checksum.to_le_bytes());
+        bytes[28..].copy_from_slice(&MAGIC.to_le_bytes());
+        bytes
+    }
+
+    /// Reads the footer, or None when the bytes aren't one
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; FOOTER_SIZE] = bytes.try_into().ok()?;
+        let word =
+            |range: std::ops::Range<usize>| u64::from_le_bytes(bytes[range].try_into().unwrap());
+        if u32::from_le_bytes(bytes[28..].try_into().unwrap()) != MAGIC
+            || u32::from_le_bytes(bytes[24..28].try_into().unwrap())
+                != crc32fast::hash(&bytes[..24])
+        {
+            return None;
+        }
+        Some(TableFooter {
+            max_sequence: word(0..8),
+            min_timestamp: word(8..16),
+            max_timestamp: word(16..24),
+        })
+    }
+
+    /// Reads the footer at the end of a storage file, with the length of the file before it. None
+    /// when the file ends without one, like the tables written before the footers
+    pub fn read(file: &mut File) -> IOResult<Option<(Self, u64)>> {
+        let len = file.metadata()?.len();
+        if len < FOOTER_SIZE as u64 {
+            return Ok(None);
+        }
+        let mut bytes = [0u8; FOOTER_SIZE];
+        file.seek(SeekFrom::Start(len - FOOTER_SIZE as u64))?;
+        file.read_exact(&mut bytes)?;
+        Ok(Self::decode(&bytes).map(|footer| (footer, len - FOOTER_SIZE as u64)))
+    }
+
+    /// Replaces the footer of a storage file, or appends one
+    pub fn write(&self, file: &mut File) -> IOResult<()> {
+        let end = match Self::read(file)? {
+            Some((_, data)) => data,
+            None => file.metadata()?.len(),
+        };
+        file.set_len(end)?;
+        file.seek(SeekFrom::Start(end))?;
+        file.write_all(&self.encode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn footers_are_read_back_and_replaced() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("table");
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"blocks").unwrap();
+        assert_eq!(TableFooter::read(&mut file).unwrap(), None);
+
+        let footer = TableFooter {
+            max_sequence: 7,
+            min_timestamp: 10,
+            max_timestamp: 20,
+        };
+        footer.write(&mut file).unwrap();
+        assert_eq!(TableFooter::read(&mut file).unwrap(), Some((footer, 6)));
+
+        let newer = TableFooter {
+            max_sequence: 9,
+            ..footer
+        };
+        newer.write(&mut file).unwrap();
+        assert_eq!(TableFooter::read(&mut file).unwrap(), Some((newer, 6)));
+        assert_eq!(file.metadata().unwrap().len(), 6 + FOOTER_SIZE as u64);
+
+        // A flipped bit makes it unreadable
+        let mut bytes = footer.encode();
+        bytes[3] ^= 1;
+        assert_eq!(TableFooter::decode(&bytes), None);
+    }
+}
@@ -1,4 +1,9 @@
+pub mod blob;
+pub mod block;
+mod builder;
 pub mod error;
+pub mod footer;
 pub mod table;
 
-pub use table::SSTable;
+pub use builder::SSTableBuilder;
+pub use table::{SSTable, sync_dir};
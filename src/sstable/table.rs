@@ -1,18 +1,23 @@
 use std::{
     fmt::Debug,
-    fs::{File, OpenOptions},
-    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
-    ops::Deref,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, Read, Result as IOResult, Seek, SeekFrom},
     path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::UNIX_EPOCH,
 };
 
-use rbtree::RBTree;
-
 use crate::{
     config::Config,
     memtable::{LogOperation, MemTableRecord},
     serialization::SerializationEngine,
-    sstable::error::SSTableError,
+    sstable::{
+        SSTableBuilder,
+        blob::{StoredValue, load_blob},
+        block::{StorageReader, file_offset},
+        error::SSTableError,
+        footer::TableFooter,
+    },
 };
 
 /// @definition: An implementation of sorted string tables. This struct is a reference to an
@@ -23,6 +28,16 @@ use crate::{
 /// @field max: The maximum key in this file. used for faster lookup
 /// @field size: The actual storage_file size. used for compaction
 /// @field count: the number of records in the sstable. This doens't include the tombstones
+/// @field level: The level of the sstable. Flushed tables are in level 0, and only the leveled
+/// compaction moves them to the other levels
+/// @field min_timestamp: The time the oldest record in the table was written, in milliseconds since
+/// the epoch. 0 when unknown
+/// @field max_timestamp: The time the newest record in the table was written
+/// @field max_sequence: The sequence number of the newest write in the table. 0 when unknown
+/// @field blobs: The numbers of the blob files holding values of the table, with the bytes of each
+/// that the table references
+/// @field obsolete: Set once the table is no longer part of the current version. Its files are
+/// deleted when the last version or reader holding the table drops it
 #[derive(Debug)]
 pub struct SSTable {
     pub storage_path: String,
@@ -31,13 +46,20 @@ pub struct SSTable {
     pub max: String,
     pub size: usize,
     pub count: usize,
+    pub level: usize,
+    pub min_timestamp: u64,
+    pub max_timestamp: u64,
+    pub max_sequence: u64,
+    pub blobs: Vec<(u64, u64)>,
+    pub obsolete: AtomicBool,
 }
 
 impl SSTable {
+    /// Writes the records of a memtable to a new table, see `SSTableBuilder`
     pub fn create<'a, T, S, SS>(
         storage_path: &'a str,
         index_path: &'a str,
-        tree: impl Deref<Target = RBTree<String, Option<T>>>,
+        entries: impl IntoIterator<Item = (String, Option<T>)>,
         serializer: &SS,
         config: &Config,
     ) -> Result<SSTable, SSTableError>
@@ -46,66 +68,11 @@ impl SSTable {
         S: SerializationEngine<LogOperation<T>>,
         SS: SerializationEngine<Option<T>>,
     {
-        if tree.is_empty() {
-            return Err(SSTableError::EmptyMemtableError);
-        }
-        if Path::new(storage_path).exists() {
-            return Err(SSTableError::LogFileAlreadyExistsError);
-        }
-        if Path::new(index_path).exists() {
-            return Err(SSTableError::IndexFileAlreadyExistsError);
-        }
-
-        let file = File::create(storage_path).map_err(|_| SSTableError::FileCreationError)?;
-        let mut indices: Vec<(String, u64)> = vec![];
-
-        let min = tree.get_first().unwrap().0.clone();
-        let max = tree.get_last().unwrap().0.clone();
-
-        let mut writer = BufWriter::new(file);
-        for (key, value) in tree.iter() {
-            indices.push((key.clone(), writer.stream_position().unwrap()));
-            let encoded = serializer
-                .serialize(value.clone())
-                .map_err(|_| SSTableError::EncodingError)?;
-
-            writer
-                .write_all(&encoded)
-                .map_err(|err| SSTableError::LogWriteError { err })?;
+        let mut builder = SSTableBuilder::new(serializer, config)?;
+        for (key, value) in entries {
+            builder.add(key, value)?;
         }
-        writer.flush();
-        let size = writer.stream_position().unwrap() as usize;
-
-        let index_file = File::create(index_path).map_err(|_| SSTableError::FileCreationError)?;
-        let mut index_writer = BufWriter::new(index_file);
-
-        for (key, offset) in indices.iter() {
-            let mut key_bytes = vec![0u8; config.index_key_string_size];
-            let truncated = key.as_bytes();
-            let len = truncated.len().min(config.index_key_string_size);
-            key_bytes[..len].copy_from_slice(&truncated[..len]);
-
-            index_writer
-                .write_all(&key_bytes)
-                .map_err(|err| SSTableError::LogWriteError { err })?;
-            index_writer
-                .write_all(&offset.to_le_bytes())
-                .map_err(|err| SSTableError::LogWriteError { err })?;
-        }
-
-        // The count avoids tombstones
-        let count = tree
-            .iter()
-            .fold(0, |a, (_, value)| if value.is_some() { 1 + a } else { a });
-
-        Ok(SSTable {
-            storage_path: storage_path.to_string(),
-            index_path: index_path.to_string(),
-            min,
-            max,
-            size,
-            count,
-        })
+        builder.finish(storage_path, index_path)
     }
 
     pub fn get<T, SS>(
@@ -129,10 +96,18 @@ impl SSTable {
                 file: self.index_path.clone(),
             })?;
 
-        // binary search
+        // binary search. The index has an entry for every key, including the tombstones that aren't
+        // part of the count
         let unit = config.index_key_string_size + config.index_offset_size;
+        let entries = index_file
+            .metadata()
+            .map_err(|_| SSTableError::DBFileCorrupted {
+                file: self.index_path.clone(),
+            })?
+            .len() as usize
+            / unit;
         let mut lo = 0;
-        let mut hi = self.count;
+        let mut hi = entries;
         let mut reader = BufReader::new(index_file);
 
         while lo < hi {
@@ -165,7 +140,7 @@ impl SSTable {
 
         // After binary search, lo is the position where key should be
         // Check if we found the exact key
-        if lo < self.size {
+        if lo < entries {
             let offset = (lo * unit) as u64;
             reader
                 .seek(SeekFrom::Start(offset))
@@ -194,35 +169,221 @@ impl SSTable {
                     })?;
 
                 let file_offset =
-                    u64::from_le_bytes(offset_buf.try_into().expect("offset size mismatch"));
-
-                return Ok(Some(self.load_record(
-                    &self.storage_path,
-                    file_offset,
-                    serializer,
-                )));
+                    index_offset(&offset_buf).ok_or_else(|| SSTableError::DBFileCorrupted {
+                        file: self.index_path.clone(),
+                    })?;
+                let value = StorageReader::open(&self.storage_path)
+                    .and_then(|mut storage| storage.read(file_offset, serializer))
+                    .map_err(|_| SSTableError::DBFileCorrupted {
+                        file: self.storage_path.clone(),
+                    })?;
+                return match value {
+                    StoredValue::Inline(value) => Ok(Some(value)),
+                    StoredValue::Blob(pointer) => {
+                        load_blob(&config.db_path, &pointer, serializer).map(Some)
+                    }
+                };
             }
         }
         Ok(None)
     }
 
-    fn load_record<T, SS>(&self, storage: &str, offset: u64, serializer: &SS) -> Option<T>
+    /// Rebuilds the table from its files, for when the manifest is lost. Its newest sequence number
+    /// and write times come from the footer of the storage file. Without one, the sequence number
+    /// is unknown and the records date from the last change to the file. The entries are read
+    /// until the first one that is out of order or can't be read, and the index is cut there.
+    /// Returns whether it was cut, or an error when not a single entry could be read.
+    pub fn recover<T, SS>(
+        storage_path: &str,
+        index_path: &str,
+        serializer: &SS,
+        config: &Config,
+    ) -> Result<(SSTable, bool), SSTableError>
+    where
+        T: MemTableRecord,
+        SS: SerializationEngine<Option<T>>,
+    {
+        let (table, readable) = Self::inspect(storage_path, index_path, serializer, config)?;
+        if let Some(len) = readable {
+            OpenOptions::new()
+                .write(true)
+                .open(index_path)
+                .and_then(|file| file.set_len(len))
+                .map_err(|err| SSTableError::LogWriteError { err })?;
+        }
+        Ok((table, readable.is_some()))
+    }
+
+    /// Like `recover`, without writing to the files. Returns the length of the index up to the
+    /// first entry that can't be read, when some can't
+    pub fn inspect<T, SS>(
+        storage_path: &str,
+        index_path: &str,
+        serializer: &SS,
+        config: &Config,
+    ) -> Result<(SSTable, Option<u64>), SSTableError>
     where
         T: MemTableRecord,
         SS: SerializationEngine<Option<T>>,
     {
-        let file = OpenOptions::new().read(true).open(storage).unwrap();
-        let mut reader = BufReader::new(file);
-        reader.seek(SeekFrom::Start(offset));
-        serializer.deserialize(&mut reader).unwrap()
+        let index = fs::read(index_path).map_err(|_| SSTableError::DBFileDeleted {
+            file: index_path.to_string(),
+        })?;
+        let deleted = |_| SSTableError::DBFileDeleted {
+            file: storage_path.to_string(),
+        };
+        let mut file = File::open(storage_path).map_err(deleted)?;
+        // The blocks end where the footer starts
+        let (footer, size) = match TableFooter::read(&mut file).map_err(deleted)? {
+            Some((footer, size)) => (footer, size as usize),
+            None => {
+                // The records were written before the file was last modified
+                let metadata = file.metadata().map_err(deleted)?;
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |time| time.as_millis() as u64);
+                let footer = TableFooter {
+                    min_timestamp: modified,
+                    max_timestamp: modified,
+                    ..Default::default()
+                };
+                (footer, metadata.len() as usize)
+            }
+        };
+        let mut storage =
+            StorageReader::open(storage_path).map_err(|_| SSTableError::DBFileDeleted {
+                file: storage_path.to_string(),
+            })?;
+
+        let key_size = config.index_key_string_size;
+        let unit = key_size + config.index_offset_size;
+        let mut entries = 0;
+        let mut keys: Option<(String, String)> = None;
+        let mut count = 0;
+        let mut blobs: Vec<(u64, u64)> = vec![];
+
+        for entry in index.chunks_exact(unit) {
+            let Some(offset) = index_offset(&entry[key_size..]) else {
+                break;
+            };
+            if file_offset(offset) as usize >= size {
+                break;
+            }
+            let value = match storage.read(offset, serializer) {
+                Ok(StoredValue::Inline(value)) => value,
+                Ok(StoredValue::Blob(pointer)) => {
+                    match blobs.iter_mut().find(|(file, _)| *file == pointer.file) {
+                        Some((_, bytes)) => *bytes += pointer.record_size(),
+                        None => blobs.push((pointer.file, pointer.record_size())),
+                    }
+                    count += 1;
+                    None
+                }
+                Err(_) => break,
+            };
+
+            // Tombstones and values in blob files only have the key in the index, which may be
+            // truncated
+            let key = match &value {
+                Some(record) => {
+                    let key = record.get_key();
+                    let mut key_bytes = vec![0u8; key_size];
+                    let len = key.len().min(key_size);
+                    key_bytes[..len].copy_from_slice(&key.as_bytes()[..len]);
+                    if key_bytes != entry[..key_size] {
+                        break;
+                    }
+                    count += 1;
+                    key
+                }
+                None => String::from_utf8_lossy(&entry[..key_size])
+                    .trim_end_matches('\0')
+                    .to_string(),
+            };
+
+            match keys.as_mut() {
+                Some((_, max)) if key < *max => break,
+                Some((_, max)) => *max = key,
+                None => keys = Some((key.clone(), key)),
+            }
+            entries += 1;
+        }
+
+        let Some((min, max)) = keys else {
+            return Err(SSTableError::DBFileCorrupted {
+                file: index_path.to_string(),
+            });
+        };
+        let readable = (entries * unit < index.len()).then_some((entries * unit) as u64);
+
+        Ok((
+            SSTable {
+                storage_path: storage_path.to_string(),
+                index_path: index_path.to_string(),
+                min,
+                max,
+                size,
+                count,
+                level: 0,
+                min_timestamp: footer.min_timestamp,
+                max_timestamp: footer.max_timestamp,
+                max_sequence: footer.max_sequence,
+                blobs,
+                obsolete: AtomicBool::new(false),
+            },
+            readable,
+        ))
+    }
+
+    /// Makes the table durable: the contents of both files, and their entries in the directories.
+    /// Must be done before the table is recorded in the manifest.
+    pub fn sync(&self) -> IOResult<()> {
+        for path in [&self.storage_path, &self.index_path] {
+            File::open(path)?.sync_all()?;
+            if let Some(dir) = Path::new(path).parent() {
+                sync_dir(dir)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::SeqCst);
+    }
+}
+
+/// The offset of an index entry, from its `Config::index_offset_size` little endian bytes
+pub(crate) fn index_offset(bytes: &[u8]) -> Option<u64> {
+    let mut offset = [0u8; 8];
+    offset.get_mut(..bytes.len())?.copy_from_slice(bytes);
+    Some(u64::from_le_bytes(offset))
+}
+
+/// Makes the creation, renaming and removal of the files in the directory durable
+pub fn sync_dir(dir: impl AsRef<Path>) -> IOResult<()> {
+    File::open(dir)?.sync_all()
+}
+
+impl Drop for SSTable {
+    fn drop(&mut self) {
+        if !self.obsolete.load(Ordering::SeqCst) {
+            return;
+        }
+        // A file that can't be deleted is an orphan, cleaned up when the engine is opened
+        for path in [&self.storage_path, &self.index_path] {
+            let _ = fs::remove_file(path);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use std::{
-        fs::File,
+        fs::{self, File},
         io::{BufRead, BufReader},
+        sync::Arc,
     };
     use tempfile::TempDir;
     use uuid::Uuid;
@@ -230,7 +391,7 @@ mod tests {
     use crate::{
         config::Config,
         memtable::{MemTable, MemTableRecord},
-        serialization::BinarySerializationEngine,
+        serialization::{BinarySerializationEngine, SerializationEngine},
         sstable::SSTable,
     };
 
@@ -258,11 +419,11 @@ mod tests {
         let reader = BufReader::new(file);
 
         let log_path = temp_dir.path().join(format!("{}.log", Uuid::new_v4()));
-        let serializer = BinarySerializationEngine;
+        let serializer = Arc::new(BinarySerializationEngine);
 
         let memtable = MemTable::<Photo, BinarySerializationEngine>::open_or_build(
             log_path.to_str().unwrap(),
-            &serializer,
+            Arc::clone(&serializer),
         )
         .expect("Failed to open or build MemTable");
 
@@ -286,8 +447,8 @@ mod tests {
         SSTable::create::<Photo, BinarySerializationEngine, BinarySerializationEngine>(
             storage_path.to_str().unwrap(),
             index_path.to_str().unwrap(),
-            memtable.tree.read().unwrap(),
-            &serializer,
+            memtable.iter(),
+            serializer.as_ref(),
             &Config {
                 db_path: temp_dir.path().to_str().unwrap().to_string(),
                 index_key_string_size: 24,
@@ -296,6 +457,7 @@ mod tests {
                 compaction_threshold: 3,
                 compaction_tier_size: 2097152,
                 compaction_size_multiplier: 10,
+                ..Default::default()
             },
         )
         .expect("Failed to create SSTable");
@@ -303,4 +465,47 @@ mod tests {
         assert!(storage_path.exists(), "SSTable data file was not created");
         assert!(index_path.exists(), "SSTable index file was not created");
     }
+
+    #[test]
+    fn recovery_reads_offsets_of_the_configured_size() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let config = Config {
+            db_path: temp_dir.path().to_str().unwrap().to_string(),
+            index_key_string_size: 8,
+            index_offset_size: 4,
+            ..Default::default()
+        };
+        let photo = |id: i32| {
+            Some(Photo {
+                id,
+                url: format!("url_{}", id),
+                thumbnail_url: format!("thumbnail_{}", id),
+            })
+        };
+
+        // Records one after the other, like the tables written before the blocks
+        let (mut storage, mut index) = (vec![], vec![]);
+        for id in 1..4 {
+            let mut key = id.to_string().into_bytes();
+            key.resize(config.index_key_string_size, 0);
+            index.extend_from_slice(&key);
+            index.extend_from_slice(&(storage.len() as u32).to_le_bytes());
+            storage.extend(BinarySerializationEngine.serialize(photo(id)).unwrap());
+        }
+        let storage_path = temp_dir.path().join("storage.log");
+        let index_path = temp_dir.path().join("index.log");
+        fs::write(&storage_path, &storage).unwrap();
+        fs::write(&index_path, &index).unwrap();
+
+        let (table, truncated) = SSTable::recover::<Photo, _>(
+            storage_path.to_str().unwrap(),
+            index_path.to_str().unwrap(),
+            &BinarySerializationEngine,
+            &config,
+        )
+        .unwrap();
+        assert!(!truncated);
+        assert_eq!((table.min.as_str(), table.max.as_str()), ("1", "3"));
+        assert_eq!(table.count, 3);
+    }
 }
@@ -0,0 +1,160 @@
+use std::{
+    fmt::{self, Debug},
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::memtable::MemoryReservation;
+
+/// How long a stalled writer waits before checking again that a flush is running
+const STALL_RECHECK: Duration = Duration::from_millis(100);
+
+/// @definition: An engine sharing a `WriteBufferManager`. It doesn't keep the engine open
+pub(crate) trait BufferedEngine: Send + Sync {
+    /// The size of the memtable accepting writes, or None once the engine is closed
+    fn memtable_size(&self) -> Option<u64>;
+
+    /// Swaps the memtable out and schedules its flush, unless the previous one is still being
+    /// flushed. Returns whether it did
+    fn flush_memtable(&self) -> bool;
+
+    /// Whether a memtable is being flushed, which frees its memory once done
+    fn is_flushing(&self) -> bool;
+}
+
+/// @definition: A memory budget shared by the memtables of several engines, given to each of them
+/// in `Config::write_buffer_manager`. Once the memtables take more than the budget, and the ones
+/// accepting writes at least half of it, the largest of those is flushed. Memtables being flushed
+/// still count until their table is written.
+/// @field stall_limit: Writers wait while the memtables take at least this much, as long as a flush
+/// is running to bring them back under it. None never stalls
+/// @field usage: The bytes of every memtable that wasn't dropped yet, see `MemTable::size`
+/// @field engines: The engines sharing the budget. The closed ones are removed as they are found
+/// @field released: Notified when memtables are dropped, for the stalled writers
+pub struct WriteBufferManager {
+    budget: u64,
+    stall_limit: Option<u64>,
+    usage: AtomicU64,
+    engines: Mutex<Vec<Arc<dyn BufferedEngine>>>,
+    stalled: Mutex<()>,
+    released: Condvar,
+}
+
+impl WriteBufferManager {
+    pub fn new(budget: u64) -> WriteBufferManager {
+        WriteBufferManager {
+            budget,
+            stall_limit: None,
+            usage: AtomicU64::new(0),
+            engines: Mutex::new(vec![]),
+            stalled: Mutex::new(()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Makes the writers wait while the memtables take `limit` bytes or more
+    pub fn with_stall_limit(mut self, limit: u64) -> WriteBufferManager {
+        self.stall_limit = Some(limit);
+        self
+    }
+
+    pub fn budget(&self) -> u64 {
+        self.budget
+    }
+
+    /// The bytes taken by the memtables of every engine
+    pub fn usage(&self) -> u64 {
+        self.usage.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn register(&self, engine: Arc<dyn BufferedEngine>) {
+        self.engines.lock().unwrap().push(engine);
+    }
+
+    /// Flushes the largest memtable accepting writes when the budget is exceeded. Returns whether
+    /// a flush was scheduled
+    pub(crate) fn enforce(&self) -> bool {
+        if self.usage() <= self.budget {
+            return false;
+        }
+        let sizes = self.memtable_sizes();
+        // The memory of the memtables being flushed is about to be freed
+        if sizes.iter().map(|(size, _)| size).sum::<u64>() < self.budget / 2 {
+            return false;
+        }
+        Self::flush_largest(sizes)
+    }
+
+    /// Blocks the writer while the memtables take more than the stall limit, as long as a flush
+    /// can bring them back under it
+    pub(crate) fn stall(&self) {
+        let Some(limit) = self.stall_limit else {
+            return;
+        };
+        while self.usage() >= limit {
+            let flushing = Self::flush_largest(self.memtable_sizes())
+                || self
+                    .engines
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .any(|engine| engine.is_flushing());
+            if !flushing {
+                return;
+            }
+
+            let stalled = self.stalled.lock().unwrap();
+            if self.usage() < limit {
+                return;
+            }
+            let _ = self.released.wait_timeout(stalled, STALL_RECHECK).unwrap();
+        }
+    }
+
+    /// The size of the memtable accepting writes of every open engine
+    fn memtable_sizes(&self) -> Vec<(u64, Arc<dyn BufferedEngine>)> {
+        let mut engines = self.engines.lock().unwrap();
+        let mut sizes = vec![];
+        engines.retain(|engine| match engine.memtable_size() {
+            Some(size) => {
+                sizes.push((size, Arc::clone(engine)));
+                true
+            }
+            None => false,
+        });
+        sizes
+    }
+
+    fn flush_largest(mut sizes: Vec<(u64, Arc<dyn BufferedEngine>)>) -> bool {
+        sizes.sort_by_key(|(size, _)| std::cmp::Reverse(*size));
+        sizes
+            .into_iter()
+            .filter(|(size, _)| *size > 0)
+            .any(|(_, engine)| engine.flush_memtable())
+    }
+}
+
+impl MemoryReservation for WriteBufferManager {
+    fn reserve(&self, bytes: u64) {
+        self.usage.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    fn release(&self, bytes: u64) {
+        self.usage.fetch_sub(bytes, Ordering::SeqCst);
+        let _stalled = self.stalled.lock().unwrap();
+        self.released.notify_all();
+    }
+}
+
+impl Debug for WriteBufferManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteBufferManager")
+            .field("budget", &self.budget)
+            .field("stall_limit", &self.stall_limit)
+            .field("usage", &self.usage())
+            .finish()
+    }
+}